@@ -0,0 +1,26 @@
+//
+// Part of Roadkill Project.
+//
+// Copyright 2010-2018, Berkus <berkus+github@metta.systems>
+//
+// Distributed under the Boost Software License, Version 1.0.
+// (See file LICENSE_1_0.txt or a copy at http://www.boost.org/LICENSE_1_0.txt)
+//
+#![no_main]
+
+use {
+    carma::support::car::Car,
+    libfuzzer_sys::fuzz_target,
+    std::{env, fs, path::PathBuf, process},
+};
+
+// Car description goes into DATA/CARS like in the game data, files it refers to
+// are looked up in the sibling directories and are missing, which must fail cleanly.
+fuzz_target!(|data: &[u8]| {
+    let dir = env::temp_dir().join(format!("carma-fuzz-{}", process::id()));
+    let cars = dir.join("DATA").join("CARS");
+    fs::create_dir_all(&cars).unwrap();
+    let fname: PathBuf = cars.join("FUZZ.ENC");
+    fs::write(&fname, data).unwrap();
+    let _ = Car::load_from(fname.into_os_string().into_string().unwrap());
+});
@@ -0,0 +1,21 @@
+//
+// Part of Roadkill Project.
+//
+// Copyright 2010-2018, Berkus <berkus+github@metta.systems>
+//
+// Distributed under the Boost Software License, Version 1.0.
+// (See file LICENSE_1_0.txt or a copy at http://www.boost.org/LICENSE_1_0.txt)
+//
+#![no_main]
+
+use {
+    carma::support::{flic::Flic, palette::Palette},
+    libfuzzer_sys::fuzz_target,
+};
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(flic) = Flic::read_from(&mut &data[..], String::from("FUZZ.FLC"), Palette::default())
+    {
+        for _ in flic.frames() {}
+    }
+});
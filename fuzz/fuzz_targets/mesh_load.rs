@@ -0,0 +1,18 @@
+//
+// Part of Roadkill Project.
+//
+// Copyright 2010-2018, Berkus <berkus+github@metta.systems>
+//
+// Distributed under the Boost Software License, Version 1.0.
+// (See file LICENSE_1_0.txt or a copy at http://www.boost.org/LICENSE_1_0.txt)
+//
+#![no_main]
+
+use {
+    carma::support::{mesh::Mesh, resource::Strictness},
+    libfuzzer_sys::fuzz_target,
+};
+
+fuzz_target!(|data: &[u8]| {
+    let _ = Mesh::load_with(&mut &data[..], Strictness::Keep);
+});
@@ -1,13 +1,38 @@
 #version 140
 
 in vec3 v_normal;
-in vec3 v_position;
+in vec3 v_position; // in eye space
 in vec2 v_tex_coords;
 
 out vec4 color;
 
 uniform sampler2D diffuse_tex;
+uniform mat4 view;
+uniform vec3 u_light;
+uniform bool u_lit;
+uniform float u_ambient;
+uniform float u_diffuse;
+uniform float u_specular;
+uniform float u_power;
+uniform vec3 u_specular_color;
+uniform float u_opacity;
 
 void main() {
-    color = vec4(texture(diffuse_tex, v_tex_coords).rgb, 1.0);
+    vec4 texel = texture(diffuse_tex, v_tex_coords);
+    // Colour key holes come with zero alpha, cut them out instead of blending.
+    if (texel.a < 0.5) {
+        discard;
+    }
+    if (!u_lit) {
+        color = vec4(texel.rgb, u_opacity);
+        return;
+    }
+    vec3 n = normalize(v_normal);
+    vec3 l = normalize(mat3(view) * u_light);
+    vec3 r = reflect(-l, n);
+    vec3 e = normalize(-v_position);
+    float diffuse = max(dot(n, l), 0.0);
+    float specular = diffuse > 0.0 ? pow(max(dot(r, e), 0.0), u_power) : 0.0;
+    vec3 lit = texel.rgb * (u_ambient + u_diffuse * diffuse) + u_specular * specular * u_specular_color;
+    color = vec4(lit, u_opacity);
 }
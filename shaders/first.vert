@@ -11,11 +11,15 @@ out vec2 v_tex_coords;
 uniform mat4 perspective;
 uniform mat4 view;
 uniform mat4 model;
+uniform mat3 u_uv_transform; // material map transform
+uniform bool u_environment; // reflective, UVs come from reflection(), see reflection.glsl
 
 void main() {
     mat4 modelview = view * model;
     v_normal = transpose(inverse(mat3(modelview))) * normal;
-    v_tex_coords = tex_coords;
-    gl_Position = perspective * modelview * vec4(position, 1.0);
-    v_position = gl_Position.xyz / gl_Position.w;
+    vec4 eye = modelview * vec4(position, 1.0);
+    v_position = eye.xyz / eye.w;
+    vec2 uv = u_environment ? reflection(v_position, v_normal) : tex_coords;
+    v_tex_coords = (u_uv_transform * vec3(uv, 1.0)).xy;
+    gl_Position = perspective * eye;
 }
@@ -0,0 +1,17 @@
+#version 140
+
+in vec2 v_tex_coords;
+
+out vec4 color;
+
+uniform sampler2D glyphs;
+
+void main() {
+    vec4 texel = texture(glyphs, v_tex_coords);
+    // Colour key holes in glyphs and head-up art let the scene through,
+    // dimmed areas are blended over it.
+    if (texel.a == 0.0) {
+        discard;
+    }
+    color = texel;
+}
@@ -0,0 +1,15 @@
+#version 140
+
+in vec2 position;
+in vec2 tex_coords;
+
+out vec2 v_tex_coords;
+
+// Positions come in pixels from the top left corner.
+uniform vec2 u_screen;
+
+void main() {
+    v_tex_coords = tex_coords;
+    vec2 ndc = position / u_screen * 2.0 - 1.0;
+    gl_Position = vec4(ndc.x, -ndc.y, 0.0, 1.0);
+}
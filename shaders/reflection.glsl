@@ -0,0 +1,6 @@
+// Sphere map coordinates of the surroundings reflected towards the eye.
+vec2 reflection(vec3 eye, vec3 n) {
+    vec3 r = reflect(normalize(eye), normalize(n));
+    float m = 2.0 * sqrt(r.x * r.x + r.y * r.y + (r.z + 1.0) * (r.z + 1.0));
+    return r.xy / max(m, 1e-4) + 0.5;
+}
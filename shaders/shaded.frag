@@ -0,0 +1,33 @@
+#version 140
+
+in vec2 v_tex_coords;
+in float v_light;
+in float v_depth;
+
+out vec4 color;
+
+uniform sampler2D index_tex; // 8-bit colour indices
+uniform sampler2D shade_tab; // 256 indices per light level, darkest row first
+uniform sampler2D palette;   // 256 x 1 colours
+uniform int u_levels;
+uniform int u_identity;      // row for fully lit surface
+uniform int u_color_key;     // index drawn as a hole, -1 for none
+uniform float u_cue_start;
+uniform float u_cue_end;
+
+int fetch_index(sampler2D tex, ivec2 at) {
+    return int(texelFetch(tex, at, 0).r * 255.0 + 0.5);
+}
+
+void main() {
+    int index = int(texture(index_tex, v_tex_coords).r * 255.0 + 0.5);
+    if (index == u_color_key) {
+        discard;
+    }
+    // Depth cue fades distant surfaces down the shade table towards the darkest row.
+    float cue = clamp((v_depth - u_cue_start) / (u_cue_end - u_cue_start), 0.0, 1.0);
+    int level = clamp(int(v_light * (1.0 - cue) * float(u_identity) + 0.5), 0, u_levels - 1);
+    // Table rows are uploaded bottom-up like all textures.
+    int shaded = fetch_index(shade_tab, ivec2(index, u_levels - 1 - level));
+    color = vec4(texelFetch(palette, ivec2(shaded, 0), 0).rgb, 1.0);
+}
@@ -0,0 +1,30 @@
+#version 150
+
+in vec3 position;
+in vec3 normal;
+in vec2 tex_coords;
+
+out vec2 v_tex_coords;
+out float v_light;
+out float v_depth;
+
+uniform mat4 perspective;
+uniform mat4 view;
+uniform mat4 model;
+uniform vec3 u_light;
+uniform float u_ambient;
+uniform mat3 u_uv_transform; // material map transform
+uniform bool u_environment; // reflective, UVs come from reflection(), see reflection.glsl
+
+void main() {
+    mat4 modelview = view * model;
+    vec3 n = normalize(transpose(inverse(mat3(modelview))) * normal);
+    vec3 l = normalize(mat3(view) * u_light);
+    // Lit once per vertex and interpolated, like the software renderer did.
+    v_light = u_ambient + (1.0 - u_ambient) * max(dot(n, l), 0.0);
+    vec4 eye = modelview * vec4(position, 1.0);
+    vec2 uv = u_environment ? reflection(eye.xyz / eye.w, n) : tex_coords;
+    v_tex_coords = (u_uv_transform * vec3(uv, 1.0)).xy;
+    v_depth = -eye.z;
+    gl_Position = perspective * eye;
+}
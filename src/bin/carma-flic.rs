@@ -0,0 +1,68 @@
+//
+// Part of Roadkill Project.
+//
+// Copyright 2010-2018, Berkus <berkus+github@metta.systems>
+//
+// Distributed under the Boost Software License, Version 1.0.
+// (See file LICENSE_1_0.txt or a copy at http://www.boost.org/LICENSE_1_0.txt)
+//
+//! Export FLI/FLC animations as an animated PNG or a sequence of PNG frames.
+//!
+//!     $ cargo run --bin carma-flic -- [--palette PAL] [--frames] INPUT.FLI OUTPUT
+//!
+//! OUTPUT is an animated .png file, or a directory for NAME_000.png frames with `--frames`.
+//! Animations without palette chunks are drawn with DRACEFLC.PAL unless given another one.
+use {
+    carma::support::{
+        flic::{Flic, DEFAULT_PALETTE},
+        palette::Palette,
+        Error,
+    },
+    std::{
+        env,
+        fs::File,
+        io::{BufWriter, Write},
+        path::Path,
+        process,
+    },
+};
+
+fn usage() -> ! {
+    eprintln!("Usage: carma-flic [--palette PAL] [--frames] INPUT.FLI OUTPUT");
+    process::exit(2);
+}
+
+fn export(input: String, output: String, palette: String, frames: bool) -> Result<(), Error> {
+    let flic = Flic::load_from_with(input, Palette::load_from(palette)?)?;
+    if frames {
+        let files = flic.write_png_sequence(Path::new(&output))?;
+        println!("{} frames written to {}", files.len(), output);
+        return Ok(());
+    }
+    let mut file = BufWriter::new(File::create(output)?);
+    flic.write_apng(&mut file)?;
+    file.flush()?;
+    Ok(())
+}
+
+fn main() {
+    let mut palette = String::from(DEFAULT_PALETTE);
+    let mut frames = false;
+    let mut files = Vec::new();
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--palette" => palette = args.next().unwrap_or_else(|| usage()),
+            "--frames" => frames = true,
+            "-h" | "--help" => usage(),
+            _ if arg.starts_with('-') => usage(),
+            _ => files.push(arg),
+        }
+    }
+    let [input, output] = <[String; 2]>::try_from(files).unwrap_or_else(|_| usage());
+
+    if let Err(e) = export(input, output, palette, frames) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,568 @@
+//
+// Part of Roadkill Project.
+//
+// Copyright 2010-2018, Berkus <berkus+github@metta.systems>
+//
+// Distributed under the Boost Software License, Version 1.0.
+// (See file LICENSE_1_0.txt or a copy at http://www.boost.org/LICENSE_1_0.txt)
+//
+//! Dump any chunked resource file (.DAT, .ACT, .MAT, .PIX, .PAL, .TAB) as an annotated
+//! chunk tree: offset, type, declared size and decoded fields of every chunk.
+//!
+//!     $ cargo run --bin carma-inspect -- [--hex] [--json] FILE...
+//!
+//! Unknown chunk payloads are always hex dumped, `--hex` dumps raw payloads of known
+//! chunks too, to look at the bytes the reader skips or does not understand yet.
+use {
+    carma::support::{
+        self,
+        resource::{Chunk, ChunkHeader},
+        texture::PixelFormat,
+        Error,
+    },
+    std::{
+        env,
+        fmt::Write,
+        fs,
+        io::{self, Write as _},
+        path::Path,
+        process,
+    },
+};
+
+/// Decoded chunk field value, rendered either as text or as JSON.
+#[derive(Debug, PartialEq)]
+enum Value {
+    Num(u64),
+    Hex(u64),
+    Float(f32),
+    Text(String),
+    List(Vec<Value>),
+    Record(Vec<(&'static str, Value)>),
+}
+
+/// Single chunk as laid out in the file.
+struct Entry {
+    offset: usize,
+    chunk_type: u32,
+    size: u32,
+    read: usize, // bytes actually consumed by the reader after the header
+    depth: usize,
+    fields: Vec<(&'static str, Value)>,
+    payload: Option<Vec<u8>>,
+}
+
+struct Dump {
+    entries: Vec<Entry>,
+    error: Option<(usize, Error)>,
+}
+
+fn floats(values: &[f32]) -> Value {
+    Value::List(values.iter().map(|v| Value::Float(*v)).collect())
+}
+
+fn describe(chunk: &Chunk) -> Vec<(&'static str, Value)> {
+    match chunk {
+        Chunk::Null()
+        | Chunk::ActorNodeDown()
+        | Chunk::ActorBounds()
+        | Chunk::ActorNodeUp()
+        | Chunk::Unknown { .. } => vec![],
+        Chunk::FileHeader { file_type, version } => {
+            let mut fields = vec![("file_type", Value::Hex(u64::from(*file_type)))];
+            if let Some(name) = support::file_type_name(*file_type) {
+                fields.push(("file_type_name", Value::Text(name.into())));
+            }
+            fields.push(("version", Value::Num(u64::from(*version))));
+            fields
+        }
+        Chunk::FileName { name, subtype } => vec![
+            ("name", Value::Text(name.clone())),
+            ("subtype", Value::Hex(u64::from(*subtype))),
+        ],
+        Chunk::VertexList(vertices) => vec![
+            ("count", Value::Num(vertices.len() as u64)),
+            (
+                "vertices",
+                Value::List(vertices.iter().map(|v| floats(&v.position)).collect()),
+            ),
+        ],
+        Chunk::UvMapList(uvs) => vec![
+            ("count", Value::Num(uvs.len() as u64)),
+            (
+                "uvs",
+                Value::List(uvs.iter().map(|uv| floats(&[uv.u, uv.v])).collect()),
+            ),
+        ],
+        Chunk::FaceList(faces) => vec![
+            ("count", Value::Num(faces.len() as u64)),
+            (
+                "faces",
+                Value::List(
+                    faces
+                        .iter()
+                        .map(|f| {
+                            Value::Record(vec![
+                                ("v1", Value::Num(u64::from(f.v1))),
+                                ("v2", Value::Num(u64::from(f.v2))),
+                                ("v3", Value::Num(u64::from(f.v3))),
+                                ("flags", Value::Hex(u64::from(f.flags))),
+                                ("what", Value::Hex(u64::from(f.what))),
+                            ])
+                        })
+                        .collect(),
+                ),
+            ),
+        ],
+        Chunk::MaterialList(names, _) => vec![
+            ("count", Value::Num(names.len() as u64)),
+            (
+                "materials",
+                Value::List(names.iter().map(|n| Value::Text(n.clone())).collect()),
+            ),
+        ],
+        Chunk::MaterialDesc {
+            name, properties, ..
+        } => {
+            let [r, g, b] = properties.colour;
+            vec![
+                ("name", Value::Text(name.clone())),
+                (
+                    "colour",
+                    Value::Hex(u64::from(u32::from_be_bytes([0, r, g, b]))),
+                ),
+                ("opacity", Value::Num(u64::from(properties.opacity))),
+                ("ambient", Value::Float(properties.ambient)),
+                ("diffuse", Value::Float(properties.diffuse)),
+                ("specular", Value::Float(properties.specular)),
+                ("power", Value::Float(properties.power)),
+                ("flags", Value::Hex(u64::from(properties.flags))),
+                (
+                    "map_transform",
+                    Value::List(
+                        properties
+                            .map_transform
+                            .iter()
+                            .map(|row| floats(row))
+                            .collect(),
+                    ),
+                ),
+                ("index_base", Value::Num(u64::from(properties.index_base))),
+                ("index_range", Value::Num(u64::from(properties.index_range))),
+            ]
+        }
+        Chunk::FaceMatList {
+            materials: ids,
+            unknown,
+        } => vec![
+            ("count", Value::Num(ids.len() as u64)),
+            ("unknown", Value::Num(u64::from(*unknown))),
+            (
+                "material_ids",
+                Value::List(ids.iter().map(|id| Value::Num(u64::from(*id))).collect()),
+            ),
+        ],
+        Chunk::PixelmapHeader {
+            name,
+            w,
+            h,
+            origin_x,
+            origin_y,
+            pixel_type,
+            row_bytes,
+        } => vec![
+            ("name", Value::Text(name.clone())),
+            ("w", Value::Num(u64::from(*w))),
+            ("h", Value::Num(u64::from(*h))),
+            ("origin_x", Value::Num(u64::from(*origin_x))),
+            ("origin_y", Value::Num(u64::from(*origin_y))),
+            ("pixel_type", Value::Num(u64::from(*pixel_type))),
+            (
+                "format",
+                Value::Text(PixelFormat::from_pixel_type(*pixel_type).to_string()),
+            ),
+            ("row_bytes", Value::Num(u64::from(*row_bytes))),
+        ],
+        Chunk::PixelmapData {
+            units,
+            unit_bytes,
+            data,
+        } => vec![
+            ("units", Value::Num(u64::from(*units))),
+            ("unit_bytes", Value::Num(u64::from(*unit_bytes))),
+            ("data_bytes", Value::Num(data.len() as u64)),
+        ],
+        Chunk::PixelmapRef(name, _)
+        | Chunk::RenderTabRef(name, _)
+        | Chunk::MeshFileRef(name, _)
+        | Chunk::MaterialRef(name, _) => vec![("name", Value::Text(name.clone()))],
+        Chunk::ActorName {
+            name,
+            actor_type,
+            render_style,
+            ..
+        } => vec![
+            ("name", Value::Text(name.clone())),
+            ("actor_type", Value::Num(u64::from(*actor_type))),
+            ("render_style", Value::Num(u64::from(*render_style))),
+        ],
+        Chunk::ActorTransform(params) => vec![(
+            "matrix",
+            Value::List(params.chunks(3).map(floats).collect()),
+        )],
+        Chunk::MapBoundingBox { min, max } => vec![("min", floats(min)), ("max", floats(max))],
+    }
+}
+
+/// Walk all chunks in the file data. Stops at the first chunk the reader fails on,
+/// the error is kept together with the offset of that chunk.
+fn inspect(data: &[u8], hex: bool) -> Dump {
+    let mut entries = Vec::new();
+    let mut offset = 0;
+    let mut depth: usize = 0;
+    while offset < data.len() {
+        let mut source = &data[offset..];
+        let result = ChunkHeader::load(&mut source).and_then(|header| {
+            let (chunk_type, size) = (header.chunk_type, header.size);
+            Chunk::load_body(header, &mut source).map(|chunk| (chunk_type, size, chunk))
+        });
+        let (chunk_type, size, chunk) = match result {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                return Dump {
+                    entries,
+                    error: Some((offset, e.at_offset(offset as u64))),
+                }
+            }
+        };
+        let end = data.len() - source.len();
+        let read = end - offset - 8;
+
+        if let Chunk::ActorNodeUp() = chunk {
+            depth = depth.saturating_sub(1);
+        }
+        let payload = match &chunk {
+            Chunk::Unknown { payload, .. } => Some(payload.clone()),
+            _ if hex => Some(data[offset + 8..end].to_vec()),
+            _ => None,
+        };
+        entries.push(Entry {
+            offset,
+            chunk_type,
+            size,
+            read,
+            depth,
+            fields: describe(&chunk),
+            payload,
+        });
+        match chunk {
+            Chunk::ActorNodeDown() => depth += 1,
+            Chunk::Null() => depth = 0,
+            _ => {}
+        }
+        offset = end;
+    }
+    Dump {
+        entries,
+        error: None,
+    }
+}
+
+fn type_name(chunk_type: u32) -> String {
+    support::chunk_name(chunk_type)
+        .map(String::from)
+        .unwrap_or_else(|| format!("UNKNOWN_{:02X}_CHUNK", chunk_type))
+}
+
+fn hex_dump(out: &mut String, indent: &str, base: usize, bytes: &[u8]) {
+    for (n, line) in bytes.chunks(16).enumerate() {
+        let mut hex = String::new();
+        for b in line {
+            write!(hex, "{:02x} ", b).unwrap();
+        }
+        let text: String = line
+            .iter()
+            .map(|&b| {
+                if b.is_ascii_graphic() || b == b' ' {
+                    b as char
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+        writeln!(
+            out,
+            "{}{:08x}  {:<48} |{}|",
+            indent,
+            base + n * 16,
+            hex,
+            text
+        )
+        .unwrap();
+    }
+}
+
+fn text_value(value: &Value) -> String {
+    match value {
+        Value::Num(n) => n.to_string(),
+        Value::Hex(n) => format!("{:#x}", n),
+        Value::Float(f) => f.to_string(),
+        Value::Text(s) => format!("{:?}", s),
+        Value::List(items) => {
+            let items: Vec<_> = items.iter().map(text_value).collect();
+            format!("[{}]", items.join(", "))
+        }
+        Value::Record(fields) => {
+            let fields: Vec<_> = fields
+                .iter()
+                .map(|(name, value)| format!("{}: {}", name, text_value(value)))
+                .collect();
+            format!("{{{}}}", fields.join(", "))
+        }
+    }
+}
+
+fn write_text(out: &mut String, dump: &Dump) {
+    for entry in &dump.entries {
+        let indent = "  ".repeat(entry.depth);
+        write!(
+            out,
+            "{:08x} {}{} ({:#x}) size {}",
+            entry.offset,
+            indent,
+            type_name(entry.chunk_type),
+            entry.chunk_type,
+            entry.size
+        )
+        .unwrap();
+        if entry.read != entry.size as usize {
+            write!(out, ", read {}", entry.read).unwrap();
+        }
+        out.push('\n');
+        let indent = format!("{}    {}", " ".repeat(8), indent);
+        for (name, value) in &entry.fields {
+            match value {
+                // Lists of vectors and records go one item per line, with item index
+                Value::List(items)
+                    if items
+                        .iter()
+                        .any(|i| matches!(i, Value::List(_) | Value::Record(_))) =>
+                {
+                    writeln!(out, "{}{}:", indent, name).unwrap();
+                    for (n, item) in items.iter().enumerate() {
+                        writeln!(out, "{}  {:>5}: {}", indent, n, text_value(item)).unwrap();
+                    }
+                }
+                value => writeln!(out, "{}{}: {}", indent, name, text_value(value)).unwrap(),
+            }
+        }
+        if let Some(payload) = &entry.payload {
+            hex_dump(out, &indent, entry.offset + 8, payload);
+        }
+        if entry.chunk_type == support::NULL_CHUNK {
+            out.push('\n');
+        }
+    }
+    if let Some((offset, error)) = &dump.error {
+        writeln!(out, "{:08x} error: {}", offset, error).unwrap();
+    }
+}
+
+fn json_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+fn json_value(out: &mut String, value: &Value) {
+    match value {
+        Value::Num(n) | Value::Hex(n) => write!(out, "{}", n).unwrap(),
+        Value::Float(f) if f.is_finite() => write!(out, "{}", f).unwrap(),
+        Value::Float(_) => out.push_str("null"),
+        Value::Text(s) => json_string(out, s),
+        Value::List(items) => {
+            out.push('[');
+            for (n, item) in items.iter().enumerate() {
+                if n > 0 {
+                    out.push(',');
+                }
+                json_value(out, item);
+            }
+            out.push(']');
+        }
+        Value::Record(fields) => json_object(out, fields),
+    }
+}
+
+fn json_object(out: &mut String, fields: &[(&'static str, Value)]) {
+    out.push('{');
+    for (n, (name, value)) in fields.iter().enumerate() {
+        if n > 0 {
+            out.push(',');
+        }
+        json_string(out, name);
+        out.push(':');
+        json_value(out, value);
+    }
+    out.push('}');
+}
+
+fn write_json(out: &mut String, file: &str, dump: &Dump) {
+    out.push_str("{\"file\":");
+    json_string(out, file);
+    out.push_str(",\"chunks\":[");
+    for (n, entry) in dump.entries.iter().enumerate() {
+        if n > 0 {
+            out.push(',');
+        }
+        write!(
+            out,
+            "{{\"offset\":{},\"type\":{},\"name\":\"{}\",\"size\":{},\"read\":{},\"depth\":{},\"fields\":",
+            entry.offset,
+            entry.chunk_type,
+            type_name(entry.chunk_type),
+            entry.size,
+            entry.read,
+            entry.depth
+        )
+        .unwrap();
+        json_object(out, &entry.fields);
+        if let Some(payload) = &entry.payload {
+            out.push_str(",\"payload\":\"");
+            for b in payload {
+                write!(out, "{:02x}", b).unwrap();
+            }
+            out.push('"');
+        }
+        out.push('}');
+    }
+    out.push(']');
+    if let Some((offset, error)) = &dump.error {
+        write!(out, ",\"error\":{{\"offset\":{},\"message\":", offset).unwrap();
+        json_string(out, &error.to_string());
+        out.push('}');
+    }
+    out.push_str("}\n");
+}
+
+fn usage() -> ! {
+    eprintln!("Usage: carma-inspect [--hex] [--json] FILE...");
+    process::exit(2);
+}
+
+fn main() {
+    let mut hex = false;
+    let mut json = false;
+    let mut files = Vec::new();
+    for arg in env::args().skip(1) {
+        match arg.as_str() {
+            "--hex" => hex = true,
+            "--json" => json = true,
+            "-h" | "--help" => usage(),
+            _ if arg.starts_with('-') => usage(),
+            _ => files.push(arg),
+        }
+    }
+    if files.is_empty() {
+        usage();
+    }
+
+    let mut failed = false;
+    for file in files {
+        let data = match fs::read(&file) {
+            Ok(data) => data,
+            Err(e) => {
+                eprintln!("{}", Error::from(e).in_file(Path::new(&file)));
+                failed = true;
+                continue;
+            }
+        };
+        let mut dump = inspect(&data, hex);
+        failed |= dump.error.is_some();
+        dump.error = dump
+            .error
+            .map(|(offset, e)| (offset, e.in_file(Path::new(&file))));
+
+        let mut out = String::new();
+        if json {
+            write_json(&mut out, &file, &dump);
+        } else {
+            writeln!(out, "{}:", file).unwrap();
+            write_text(&mut out, &dump);
+        }
+        // Quietly stop when output is piped into head and such
+        if io::stdout().write_all(out.as_bytes()).is_err() {
+            process::exit(1);
+        }
+    }
+    if failed {
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[rustfmt::skip]
+    #[test]
+    fn test_inspect_chunks() {
+        let data = vec![
+            0x0, 0x0, 0x0, 0x12, // Chunk type - FILE_HEADER_CHUNK
+            0x0, 0x0, 0x0, 0x8, // Chunk size
+            0x0, 0x0, 0x0, 0x5, // File type - material
+            0x0, 0x0, 0x0, 0x2, // Version
+            0x0, 0x0, 0x0, 0x1c, // Chunk type - PIXELMAP_REF_CHUNK
+            0x0, 0x0, 0x0, 0x1, // Chunk size, game files undercount strings
+            b'A', 0, // Chunk contents
+            0x0, 0x0, 0xbe, 0xef, // Chunk type - unknown
+            0x0, 0x0, 0x0, 0x3, // Chunk size
+            0x1, 0x2, 0x3, // Chunk contents
+            0x0, 0x0, 0x0, 0x0, // Chunk type - NULL_CHUNK
+            0x0, 0x0, 0x0, 0x0, // Chunk size
+            0x0, 0x0, 0x0, 0x17, // Chunk type - VERTEX_LIST_CHUNK
+            0x0, 0x0, 0x0, 0x1c, // Chunk size
+            0x0, 0x0, 0x0, 0x2, // Vertex count, but data for only one
+        ];
+
+        let dump = inspect(&data, false);
+        assert_eq!(4, dump.entries.len());
+        assert_eq!(vec![0x0, 0x10, 0x1a, 0x25], dump.entries.iter().map(|e| e.offset).collect::<Vec<_>>());
+        assert_eq!(vec![("file_type", Value::Hex(5)), ("file_type_name", Value::Text("MATERIAL_FILE_TYPE".into())), ("version", Value::Num(2))], dump.entries[0].fields);
+        assert_eq!((1, 2), (dump.entries[1].size, dump.entries[1].read));
+        assert_eq!(None, dump.entries[1].payload);
+        assert_eq!(Some(vec![1, 2, 3]), dump.entries[2].payload);
+        assert!(matches!(dump.error, Some((0x2d, Error::Truncated { offset: 0x2d, .. }))));
+
+        let dump = inspect(&data, true);
+        assert_eq!(Some(vec![b'A', 0]), dump.entries[1].payload);
+
+        let mut out = String::new();
+        write_text(&mut out, &dump);
+        assert!(out.contains("00000010 PIXELMAP_REF_CHUNK (0x1c) size 1, read 2\n"));
+        assert!(out.contains("0000001a UNKNOWN_BEEF_CHUNK (0xbeef) size 3\n"));
+        assert!(out.contains("\n\n0000002d error: "));
+
+        let mut out = String::new();
+        write_json(&mut out, "TEST.MAT", &dump);
+        assert!(out.starts_with("{\"file\":\"TEST.MAT\",\"chunks\":[{\"offset\":0,\"type\":18,\"name\":\"FILE_HEADER_CHUNK\",\"size\":8,\"read\":8,\"depth\":0,\"fields\":{\"file_type\":5,\"file_type_name\":\"MATERIAL_FILE_TYPE\",\"version\":2}"));
+        assert!(out.contains("\"payload\":\"010203\""));
+        assert!(out.contains("\"error\":{\"offset\":45,\"message\":"));
+    }
+
+    #[test]
+    fn test_inspect_actor_tree() {
+        let data = fs::read("DecodedData/DATA/ACTORS/ARENA.ACT").unwrap();
+        let dump = inspect(&data, false);
+        assert!(dump.error.is_none());
+        assert!(dump.entries.iter().any(|e| e.depth > 0));
+        assert_eq!(support::NULL_CHUNK, dump.entries.last().unwrap().chunk_type);
+    }
+}
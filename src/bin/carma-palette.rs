@@ -0,0 +1,55 @@
+//
+// Part of Roadkill Project.
+//
+// Copyright 2010-2018, Berkus <berkus+github@metta.systems>
+//
+// Distributed under the Boost Software License, Version 1.0.
+// (See file LICENSE_1_0.txt or a copy at http://www.boost.org/LICENSE_1_0.txt)
+//
+//! Convert palettes between the game .PAL format and formats paint programs use:
+//! JASC-PAL, GIMP .gpl and Adobe .act.
+//!
+//!     $ cargo run --bin carma-palette -- [--jasc|--gimp|--act|--game] INPUT OUTPUT
+//!
+//! Input format is detected from contents. Output format is picked from the OUTPUT
+//! extension (.PAL is the game format, like DRRENDER.PAL) unless given explicitly,
+//! `--jasc` is needed for JASC-PAL files since they use .pal too.
+use {
+    carma::support::palette::{Palette, PaletteFormat},
+    std::{env, path::Path, process},
+};
+
+fn usage() -> ! {
+    eprintln!("Usage: carma-palette [--jasc|--gimp|--act|--game] INPUT OUTPUT");
+    process::exit(2);
+}
+
+fn main() {
+    let mut format = None;
+    let mut files = Vec::new();
+    for arg in env::args().skip(1) {
+        match arg.as_str() {
+            "--jasc" => format = Some(PaletteFormat::Jasc),
+            "--gimp" => format = Some(PaletteFormat::Gimp),
+            "--act" => format = Some(PaletteFormat::Act),
+            "--game" => format = Some(PaletteFormat::Pixelmap),
+            "-h" | "--help" => usage(),
+            _ if arg.starts_with('-') => usage(),
+            _ => files.push(arg),
+        }
+    }
+    let [input, output] = <[String; 2]>::try_from(files).unwrap_or_else(|_| usage());
+    let Some(format) = format.or_else(|| PaletteFormat::from_extension(Path::new(&output))) else {
+        eprintln!(
+            "Can't tell palette format from {}, give it explicitly",
+            output
+        );
+        usage();
+    };
+
+    let result = Palette::import_from(input).and_then(|palette| palette.export_to(output, format));
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,88 @@
+//
+// Part of Roadkill Project.
+//
+// Copyright 2010-2018, Berkus <berkus+github@metta.systems>
+//
+// Distributed under the Boost Software License, Version 1.0.
+// (See file LICENSE_1_0.txt or a copy at http://www.boost.org/LICENSE_1_0.txt)
+//
+//! Import PNG images as game pixelmaps.
+//!
+//!     $ cargo run --bin carma-pix -- [--palette PAL] [--dither] [--format FORMAT] INPUT.png OUTPUT.PIX
+//!
+//! By default images are quantized to DRRENDER.PAL, use DRACEFLC.PAL for menu images.
+//! Transparent pixels become index 0. FORMAT is Index8 or one of the direct color
+//! formats, e.g. Rgb565, Rgb555 or Rgba4444, which need no palette.
+use {
+    carma::support::{
+        palette::Palette,
+        texture::{Dither, PixelFormat, PixelMap},
+        Error,
+    },
+    std::{env, process},
+};
+
+const FORMATS: [PixelFormat; 8] = [
+    PixelFormat::Index8,
+    PixelFormat::Rgb555,
+    PixelFormat::Rgb565,
+    PixelFormat::Bgr555,
+    PixelFormat::Rgba4444,
+    PixelFormat::Rgb888,
+    PixelFormat::Rgbx888,
+    PixelFormat::Rgba8888,
+];
+
+fn usage() -> ! {
+    eprintln!("Usage: carma-pix [--palette PAL] [--dither] [--format FORMAT] INPUT.png OUTPUT.PIX");
+    eprintln!(
+        "Formats: {}",
+        FORMATS.map(|format| format.to_string()).join(", ")
+    );
+    process::exit(2);
+}
+
+fn import(
+    input: String,
+    palette: String,
+    dither: Dither,
+    format: PixelFormat,
+) -> Result<PixelMap, Error> {
+    let image = PixelMap::import_png(input)?;
+    match format {
+        PixelFormat::Index8 => image.quantize_via(&Palette::load_from(palette)?, dither),
+        format => image.convert_to(format),
+    }
+}
+
+fn main() {
+    let mut palette = String::from("DecodedData/DATA/REG/PALETTES/DRRENDER.PAL");
+    let mut dither = Dither::None;
+    let mut format = PixelFormat::Index8;
+    let mut files = Vec::new();
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--palette" => palette = args.next().unwrap_or_else(|| usage()),
+            "--dither" => dither = Dither::FloydSteinberg,
+            "--format" => {
+                let name = args.next().unwrap_or_else(|| usage());
+                format = FORMATS
+                    .into_iter()
+                    .find(|format| format.to_string().eq_ignore_ascii_case(&name))
+                    .unwrap_or_else(|| usage());
+            }
+            "-h" | "--help" => usage(),
+            _ if arg.starts_with('-') => usage(),
+            _ => files.push(arg),
+        }
+    }
+    let [input, output] = <[String; 2]>::try_from(files).unwrap_or_else(|_| usage());
+
+    let result = import(input, palette, dither, format)
+        .and_then(|pixelmap| PixelMap::save_to(output, &[pixelmap]));
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
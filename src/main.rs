@@ -6,22 +6,30 @@
 // Distributed under the Boost Software License, Version 1.0.
 // (See file LICENSE_1_0.txt or a copy at http://www.boost.org/LICENSE_1_0.txt)
 //
-pub mod support;
-
 #[cfg(feature = "convert")]
-use crate::support::texture::PixelMap;
+use carma::support::texture::PixelMap;
 
 use {
-    crate::support::{camera::CameraState, car::Car, render_manager::RenderManager},
+    carma::support::{
+        self,
+        camera::CameraState,
+        car::Car,
+        font::Font,
+        headup::{Hud, HudState, HudView, System},
+        material::MaterialLibrary,
+        palette::Palette,
+        render_manager::{RenderManager, RenderMode},
+        resolution::ResolutionProfile,
+    },
     cgmath::Vector3,
     glium::{
         glutin::{
-            event::{Event, WindowEvent},
+            event::{ElementState, Event, KeyboardInput, VirtualKeyCode, WindowEvent},
             event_loop::ControlFlow,
         },
         Surface,
     },
-    log::info,
+    log::{error, info},
 };
 
 fn setup_logging() -> Result<(), fern::InitError> {
@@ -79,9 +87,8 @@ fn visit_dirs(dir: &Path, cb: &mut dyn for<'r> FnMut(&'r DirEntry)) -> Result<()
 }
 
 #[cfg(feature = "convert")]
-fn convert_pixmap(fname: String, palette: &PixelMap) -> Result<(), support::Error> {
-    let pmap = PixelMap::load_from(fname.clone())
-        .expect(format!("Couldnt open pix file {:?}", fname).as_ref());
+fn convert_pixmap(fname: String, palette: &Palette) -> Result<(), support::Error> {
+    let pmap = PixelMap::load_from(fname.clone())?;
     // let mut counter = 0;
     for pix in pmap {
         // counter += 1;
@@ -91,46 +98,116 @@ fn convert_pixmap(fname: String, palette: &PixelMap) -> Result<(), support::Erro
         pngname.set_extension("png");
 
         info!("Creating file {:?}", pngname);
-        let file = File::create(&pngname)
-            .expect(format!("Couldnt create png file {:?}", pngname).as_ref());
+        let file = File::create(&pngname)?;
         let w = &mut BufWriter::new(file);
 
-        pix.write_png_remapped_via(palette, w)
-            .expect("Failed to write PNG");
+        pix.write_png_remapped_via(palette, w)?;
     }
     Ok(())
 }
 
 /// Uses different palette for race-selection part
 #[cfg(feature = "convert")]
+#[allow(dead_code)]
 fn convert_menu_pixmap(fname: String) -> Result<(), support::Error> {
-    let palette =
-        &PixelMap::load_from(String::from("DecodedData/DATA/REG/PALETTES/DRACEFLC.PAL"))?[0];
+    let palette = &Palette::load_from(String::from("DecodedData/DATA/REG/PALETTES/DRACEFLC.PAL"))?;
     convert_pixmap(fname, palette)
 }
 
 #[cfg(feature = "convert")]
 fn convert_game_pixmap(fname: String) -> Result<(), support::Error> {
-    let palette =
-        &PixelMap::load_from(String::from("DecodedData/DATA/REG/PALETTES/DRRENDER.PAL"))?[0];
+    let palette = &Palette::load_from(String::from("DecodedData/DATA/REG/PALETTES/DRRENDER.PAL"))?;
     convert_pixmap(fname, palette)
 }
 
 /// Load palette once and then apply to a bunch of pixmap data
 #[cfg(feature = "convert")]
 fn convert_all_pixmaps() -> Result<(), support::Error> {
-    let palette =
-        &PixelMap::load_from(String::from("DecodedData/DATA/REG/PALETTES/DRRENDER.PAL"))?[0];
+    let palette = &Palette::load_from(String::from("DecodedData/DATA/REG/PALETTES/DRRENDER.PAL"))?;
     visit_dirs(Path::new("DecodedData"), &mut |dir_entry| {
         if let Ok(file_type) = dir_entry.file_type() {
             let fname = String::from(dir_entry.path().to_str().unwrap());
             if file_type.is_file() && fname.ends_with(".PIX") {
-                convert_pixmap(fname, palette).unwrap();
+                if let Err(e) = convert_pixmap(fname.clone(), palette) {
+                    error!("Skipping {}: {}", fname, e);
+                }
             }
         }
     })
 }
 
+const LABEL_FONT: &str = "DecodedData/DATA/64X48X8/FONTS/TYPEABLE.ENC";
+const LABEL_SCALE: f32 = 2.0;
+// Reflected by car screens, in the game they show the sky of the track.
+const ENVIRONMENT_MAP: &str = "DecodedData/DATA/PIXELMAP/CHROME.PIX";
+
+/// Car names above the cars and a status line in the top left corner.
+fn queue_labels(
+    render_manager: &mut RenderManager,
+    dimensions: (u32, u32),
+    font: &str,
+    cars: &[Car],
+    camera: &CameraState,
+) {
+    for car in cars {
+        let above = car.base_translation + Vector3::new(0.0, 0.3, 0.0);
+        if let Some([x, y]) = camera.project(above, dimensions) {
+            let width = render_manager.text_width(font, &car.name) as f32 * LABEL_SCALE;
+            render_manager.queue_text(font, &car.name, [x - width / 2.0, y], LABEL_SCALE);
+        }
+    }
+    let status = format!(
+        "{} cars, {:?} (L toggles)",
+        cars.len(),
+        render_manager.mode()
+    );
+    render_manager.queue_text(font, &status, [8.0, 8.0], LABEL_SCALE);
+}
+
+/// References the car couldn't resolve, one per line under the status line.
+fn queue_report(render_manager: &mut RenderManager, font: &str, car: &Car) {
+    let line = (render_manager.line_height(font) + 2) as f32 * LABEL_SCALE;
+    let header = format!(
+        "{}: {} unresolved (R hides)",
+        car.name,
+        car.unresolved.len()
+    );
+    let lines = std::iter::once(header).chain(car.unresolved.iter().map(|r| r.to_string()));
+    for (row, text) in lines.enumerate() {
+        let y = 8.0 + line * (row + 1) as f32;
+        render_manager.queue_text(font, &text, [8.0, y], LABEL_SCALE);
+    }
+}
+
+// Cockpit art only comes in 640x480.
+fn load_hud(fname: &str) -> Option<Hud> {
+    match Hud::load_from(String::from(fname), ResolutionProfile::Hires) {
+        Ok(hud) => Some(hud),
+        Err(e) => {
+            error!("No cockpit for {}: {}", fname, e);
+            None
+        }
+    }
+}
+
+/// Gauges somewhere mid-range and some damage, to see all parts of the display.
+fn demo_hud_state() -> HudState {
+    HudState {
+        speed: 87.0,
+        revs: 0.6,
+        gear: 3,
+        steering: -0.3,
+        damage: [
+            (System::Engine, 30),
+            (System::LfWheel, 60),
+            (System::RrBrake, 90),
+        ]
+        .into_iter()
+        .collect(),
+        ..Default::default()
+    }
+}
+
 fn main() {
     setup_logging().expect("failed to initialize logging");
 
@@ -141,6 +218,26 @@ fn main() {
             .expect("Conversion failed");
     }
 
+    // Optional resolution to pick car assets for, e.g. 640x480.
+    let profile = match std::env::args().nth(1).map(|arg| arg.parse()) {
+        None => ResolutionProfile::default(),
+        Some(Ok(profile)) => profile,
+        Some(Err(e)) => {
+            error!("{}", e);
+            std::process::exit(2);
+        }
+    };
+    info!("Loading {} assets", profile);
+
+    // Materials are shared between cars, index them once.
+    let library = match MaterialLibrary::load_from(Path::new("DecodedData/DATA"), profile) {
+        Ok(library) => library,
+        Err(e) => {
+            error!("Can't index materials: {}", e);
+            std::process::exit(1);
+        }
+    };
+
     // Load all cars and arrange in a grid 6x7 (40 cars total)
 
     let mut cars = Vec::new();
@@ -149,17 +246,23 @@ fn main() {
         if let Ok(file_type) = entry.file_type() {
             let fname = String::from(entry.path().to_str().unwrap());
             if file_type.is_file() && fname.ends_with(".ENC") {
-                let mut car = Car::load_from(fname).unwrap();
+                let mut car = match Car::load_from_library(fname.clone(), profile, &library) {
+                    Ok(car) => car,
+                    Err(e) => {
+                        error!("Skipping car {}: {}", fname, e);
+                        return;
+                    }
+                };
 
                 let z = 1.0f32 * f32::from(counter / 7);
-                let x = 1.0f32 * f32::from(counter % 7 as u16);
+                let x = 1.0f32 * f32::from(counter % 7_u16);
                 counter += 1;
 
                 info!("Moving car {} to {},0,{}", counter, x, -z);
 
                 car.base_translation = Vector3::from([x, 0f32, -z]);
 
-                cars.push(car);
+                cars.push((car, fname));
             }
         }
     })
@@ -167,7 +270,7 @@ fn main() {
 
     // Prepare window
 
-    let mut events_loop = glium::glutin::event_loop::EventLoop::new();
+    let events_loop = glium::glutin::event_loop::EventLoop::new();
     let window = glium::glutin::window::WindowBuilder::new()
         .with_title("carma")
         .with_inner_size(glium::glutin::dpi::LogicalSize::new(800.0, 600.0));
@@ -176,11 +279,75 @@ fn main() {
     let display = glium::Display::new(window, windowed_context, &events_loop).unwrap();
 
     let mut render_manager = RenderManager::new(&display);
-    for car in &cars {
-        render_manager.prepare_car(car, &display);
+    let (cars, car_files): (Vec<_>, Vec<_>) = cars
+        .into_iter()
+        .filter_map(
+            |(car, fname)| match render_manager.prepare_car(&car, &display) {
+                Ok(()) => Some((car, fname)),
+                Err(e) => {
+                    error!("Skipping car {}: {}", car.name, e);
+                    None
+                }
+            },
+        )
+        .unzip();
+
+    // Screens are drawn black without it.
+    let environment = support::texture::PixelMap::load_from(String::from(ENVIRONMENT_MAP))
+        .and_then(|pixelmaps| {
+            let pixelmap =
+                pixelmaps
+                    .into_iter()
+                    .next()
+                    .ok_or_else(|| support::Error::BadPixelmap {
+                        name: String::from(ENVIRONMENT_MAP),
+                        reason: String::from("no pixelmaps in file"),
+                    })?;
+            let palette =
+                Palette::load_from(String::from("DecodedData/DATA/REG/PALETTES/DRRENDER.PAL"))?;
+            render_manager.load_environment_map(&pixelmap, &palette, &display)
+        });
+    if let Err(e) = environment {
+        error!("No environment map: {}", e);
     }
 
+    // Labels are optional, the viewer works without fonts.
+    let label_font = Font::load_from(String::from(LABEL_FONT)).and_then(|font| {
+        let name = font.name.clone();
+        let palette =
+            Palette::load_from(String::from("DecodedData/DATA/REG/PALETTES/DRRENDER.PAL"))?;
+        render_manager.load_font(font, &palette, &display)?;
+        Ok(name)
+    });
+    let label_font = match label_font {
+        Ok(name) => Some(name),
+        Err(e) => {
+            error!("No text labels: {}", e);
+            None
+        }
+    };
+
     let mut camera = CameraState::new();
+    // Start with the whole grid of cars in view.
+    let bounds = cars
+        .iter()
+        .filter_map(|car| render_manager.car_bounds(car))
+        .reduce(|(min, max), (car_min, car_max)| {
+            (
+                std::array::from_fn(|i| min[i].min(car_min[i])),
+                std::array::from_fn(|i| max[i].max(car_max[i])),
+            )
+        });
+    if let Some((min, max)) = bounds {
+        camera.frame(min, max);
+    }
+
+    // Cockpit of one car at a time, H cycles views and N the car.
+    let mut hud_car = 0;
+    let mut hud_view = None;
+    let mut hud = None;
+    // R shows what the same car couldn't resolve.
+    let mut show_report = false;
 
     events_loop.run(move |event, _, control_flow| {
         println!("{:?}", event);
@@ -189,10 +356,67 @@ fn main() {
         camera.update();
 
         match event {
-            Event::LoopDestroyed => return,
+            Event::LoopDestroyed => (),
             Event::WindowEvent { event, .. } => match event {
                 // WindowEvent::Resized(physical_size) => display.resize(physical_size),
                 WindowEvent::CloseRequested => *control_flow = ControlFlow::Exit,
+                // L toggles lighting through shade tables
+                WindowEvent::KeyboardInput {
+                    input:
+                        KeyboardInput {
+                            state: ElementState::Pressed,
+                            virtual_keycode: Some(VirtualKeyCode::L),
+                            ..
+                        },
+                    ..
+                } => {
+                    let mode = match render_manager.mode() {
+                        RenderMode::Truecolor => RenderMode::ShadeTable,
+                        RenderMode::ShadeTable => RenderMode::Truecolor,
+                    };
+                    info!("Render mode {:?}", mode);
+                    render_manager.set_mode(mode);
+                    display.gl_window().window().request_redraw();
+                }
+                WindowEvent::KeyboardInput {
+                    input:
+                        KeyboardInput {
+                            state: ElementState::Pressed,
+                            virtual_keycode: Some(VirtualKeyCode::R),
+                            ..
+                        },
+                    ..
+                } => {
+                    show_report = !show_report;
+                    display.gl_window().window().request_redraw();
+                }
+                WindowEvent::KeyboardInput {
+                    input:
+                        KeyboardInput {
+                            state: ElementState::Pressed,
+                            virtual_keycode: Some(key @ (VirtualKeyCode::H | VirtualKeyCode::N)),
+                            ..
+                        },
+                    ..
+                } if !car_files.is_empty() => {
+                    if key == VirtualKeyCode::N {
+                        hud_car = (hud_car + 1) % car_files.len();
+                        hud = None;
+                    }
+                    if key == VirtualKeyCode::H {
+                        hud_view = match hud_view {
+                            None => Some(HudView::External),
+                            Some(HudView::External) => Some(HudView::Forward),
+                            Some(HudView::Forward) => Some(HudView::Left),
+                            Some(HudView::Left) => Some(HudView::Right),
+                            Some(HudView::Right) => None,
+                        };
+                    }
+                    if hud_view.is_some() && hud.is_none() {
+                        hud = Some(load_hud(&car_files[hud_car]));
+                    }
+                    display.gl_window().window().request_redraw();
+                }
                 _ => camera.process_input(&event),
             },
             Event::RedrawRequested(_) => {
@@ -203,6 +427,21 @@ fn main() {
                 for car in &cars {
                     render_manager.draw_car(car, &mut frame, &camera);
                 }
+                if let (Some(view), Some(Some(hud))) = (hud_view, &hud) {
+                    let state = HudState {
+                        view,
+                        ..demo_hud_state()
+                    };
+                    render_manager.draw_hud(&display, &mut frame, &car_files[hud_car], hud, &state);
+                }
+                if let Some(font) = &label_font {
+                    let dimensions = frame.get_dimensions();
+                    queue_labels(&mut render_manager, dimensions, font, &cars, &camera);
+                    if let Some(car) = cars.get(hud_car).filter(|_| show_report) {
+                        queue_report(&mut render_manager, font, car);
+                    }
+                    render_manager.draw_queued_text(&display, &mut frame);
+                }
                 frame.finish().unwrap();
                 // windowed_context.swap_buffers().unwrap();
             }
@@ -7,13 +7,18 @@
 // (See file LICENSE_1_0.txt or a copy at http://www.boost.org/LICENSE_1_0.txt)
 //
 use {
-    crate::support::{self, resource::Chunk, Error},
-    byteorder::ReadBytesExt,
+    crate::support::{
+        self,
+        resource::{Chunk, ChunkReader, Strictness, StringSize, StringSizes},
+        Error,
+    },
+    byteorder::WriteBytesExt,
     id_tree::*,
     log::*,
     std::{
         fs::File,
-        io::{BufRead, BufReader},
+        io::{BufReader, BufWriter, Read, Write},
+        path::PathBuf,
     },
 };
 
@@ -21,6 +26,7 @@ use {
 // Root
 // +--Actor(NAME.ACT)
 //    +--Transform()
+//    +--BoundingBox() -- mostly in track actors
 //    +--MeshfileRef
 //    +--Actor(SUBACT.ACT)
 //       +--Transform()
@@ -29,17 +35,28 @@ use {
 #[derive(Debug)]
 pub enum ActorNode {
     Root,
-    Actor { name: String, visible: bool },
+    Actor {
+        name: String,
+        actor_type: u8, // 1 for actors with a model, these are the visible ones
+        render_style: u8,
+    },
     // First 3x3 is scale? or maybe SQT?
     // Last 3 is translate, -x is to the left, -z is to the front
     Transform([f32; 12]),
+    // Axis-aligned bounds of the actor and its children, in actor space
+    BoundingBox {
+        min: [f32; 3],
+        max: [f32; 3],
+    },
     MeshfileRef(String),
     MaterialRef(String),
+    Extra(Chunk), // unexpected chunk kept with Strictness::Keep
 }
 
 pub struct Actor {
     tree: Tree<ActorNode>,
     root_id: NodeId,
+    string_sizes: StringSizes,
 }
 
 impl Actor {
@@ -49,12 +66,13 @@ impl Actor {
             .insert(Node::new(ActorNode::Root), InsertBehavior::AsRoot)
             .unwrap();
         Self {
-            tree: tree,
-            root_id: root_id,
+            tree,
+            root_id,
+            string_sizes: StringSizes::default(),
         }
     }
 
-    pub fn traverse(&self) -> PreOrderTraversal<ActorNode> {
+    pub fn traverse(&self) -> PreOrderTraversal<'_, ActorNode> {
         self.tree.traverse_pre_order(&self.root_id).unwrap()
     }
 
@@ -91,11 +109,7 @@ impl Actor {
             if let &ActorNode::Root = node.data() {
                 println!("{:?}", node.data());
             }
-            if let &ActorNode::Actor {
-                name: _,
-                visible: _,
-            } = node.data()
-            {
+            if let &ActorNode::Actor { .. } = node.data() {
                 if let Some(parent) = node.parent() {
                     print!("  ");
                     for _ in self.tree.ancestors(parent).unwrap() {
@@ -107,7 +121,19 @@ impl Actor {
         }
     }
 
-    pub fn load<R: ReadBytesExt + BufRead>(rdr: &mut R) -> Result<Actor, Error> {
+    pub fn load<R: Read>(rdr: &mut R) -> Result<Actor, Error> {
+        Actor::load_with(rdr, Strictness::default())
+    }
+
+    pub fn load_with<R: Read>(rdr: &mut R, strictness: Strictness) -> Result<Actor, Error> {
+        Actor::from_chunks(&mut ChunkReader::new(rdr), strictness)
+    }
+
+    /// Load actor tree from the chunk stream, up to its Null terminator.
+    pub fn from_chunks<R: Read>(
+        chunks: &mut ChunkReader<R>,
+        strictness: Strictness,
+    ) -> Result<Actor, Error> {
         use id_tree::InsertBehavior::*;
 
         let mut actor = Actor::new(TreeBuilder::new().with_node_capacity(5).build());
@@ -115,18 +141,29 @@ impl Actor {
         {
             let mut current_actor = actor.root_id.clone();
             let mut last_actor = current_actor.clone();
+            let mut bounds = None;
 
             // Read chunks until last chunk is encountered.
             // Certain chunks initialize certain properties.
-            loop {
-                let c = Chunk::load(rdr)?;
+            for chunk in chunks.record() {
+                let (offset, c) = chunk?;
+                actor.string_sizes.keep(&c);
                 match c {
-                    Chunk::ActorName { name, visible } => {
-                        trace!("Actor {} visible {}", name, visible);
+                    Chunk::ActorName {
+                        name,
+                        actor_type,
+                        render_style,
+                        ..
+                    } => {
+                        trace!("Actor {} type {} style {}", name, actor_type, render_style);
                         let child_id: NodeId = actor
                             .tree
                             .insert(
-                                Node::new(ActorNode::Actor { name, visible }),
+                                Node::new(ActorNode::Actor {
+                                    name,
+                                    actor_type,
+                                    render_style,
+                                }),
                                 UnderNode(&current_actor),
                             )
                             .unwrap();
@@ -142,7 +179,22 @@ impl Actor {
                             )
                             .unwrap();
                     }
-                    Chunk::MaterialRef(name) => {
+                    Chunk::MapBoundingBox { min, max } => {
+                        bounds = Some(ActorNode::BoundingBox { min, max });
+                    }
+                    Chunk::ActorBounds() => {
+                        if let Some(bounds) = bounds.take() {
+                            actor
+                                .tree
+                                .insert(
+                                    Node::new(bounds),
+                                    // Like transform, bounds belong to the last loaded actor
+                                    UnderNode(&last_actor),
+                                )
+                                .unwrap();
+                        }
+                    }
+                    Chunk::MaterialRef(name, _) => {
                         actor
                             .tree
                             .insert(
@@ -151,7 +203,7 @@ impl Actor {
                             )
                             .unwrap();
                     }
-                    Chunk::MeshFileRef(name) => {
+                    Chunk::MeshFileRef(name, _) => {
                         actor
                             .tree
                             .insert(
@@ -169,13 +221,24 @@ impl Actor {
                             current_actor = parent.clone();
                         }
                     }
-                    Chunk::Null() => break,
-                    Chunk::FileHeader { file_type } => {
+                    Chunk::FileHeader { file_type, .. } => {
                         if file_type != support::ACTOR_FILE_TYPE {
-                            panic!("Invalid model file type {}", file_type);
+                            return Err(Error::WrongFileType {
+                                file: PathBuf::new(),
+                                offset,
+                                expected: support::ACTOR_FILE_TYPE,
+                                found: file_type,
+                            });
+                        }
+                    }
+                    c => {
+                        if let Some(c) = strictness.check(offset, c)? {
+                            actor
+                                .tree
+                                .insert(Node::new(ActorNode::Extra(c)), UnderNode(&current_actor))
+                                .unwrap();
                         }
                     }
-                    _ => unimplemented!(), // unexpected type here
                 }
             }
         }
@@ -184,9 +247,217 @@ impl Actor {
     }
 
     pub fn load_from(fname: String) -> Result<Actor, Error> {
-        let file = File::open(fname)?;
+        Actor::load_from_with(fname, Strictness::default())
+    }
+
+    pub fn load_from_with(fname: String, strictness: Strictness) -> Result<Actor, Error> {
+        let path = PathBuf::from(fname);
+        let file = File::open(&path).map_err(|e| Error::from(e).in_file(&path))?;
         let mut file = BufReader::new(file);
-        let m = Actor::load(&mut file)?;
+        let m = Actor::load_with(&mut file, strictness).map_err(|e| e.in_file(&path))?;
         Ok(m)
     }
+
+    pub fn save<W: WriteBytesExt>(&self, wr: &mut W) -> Result<(), Error> {
+        Chunk::FileHeader {
+            file_type: support::ACTOR_FILE_TYPE,
+            version: support::FILE_HEADER_VERSION,
+        }
+        .write(wr)?;
+        for child_id in self.tree.get(&self.root_id).unwrap().children() {
+            self.save_node(child_id, wr)?;
+        }
+        Chunk::Null().write(wr)?;
+        Ok(())
+    }
+
+    // Write node and its subtree in the order load() expects to see them:
+    // actor name, its transform, then node down, children and node up.
+    // Like in game files, node down of top level actors is never closed.
+    fn save_node<W: WriteBytesExt>(&self, node_id: &NodeId, wr: &mut W) -> Result<(), Error> {
+        let node = self.tree.get(node_id).unwrap();
+        match node.data() {
+            ActorNode::Actor {
+                name,
+                actor_type,
+                render_style,
+            } => {
+                self.string_sizes
+                    .sized(Chunk::ActorName {
+                        name: name.clone(),
+                        actor_type: *actor_type,
+                        render_style: *render_style,
+                        size: StringSize::Game,
+                    })
+                    .write(wr)?;
+                for child_id in node.children() {
+                    if let ActorNode::Transform(t) = self.tree.get(child_id).unwrap().data() {
+                        Chunk::ActorTransform(*t).write(wr)?;
+                    }
+                }
+                Chunk::ActorNodeDown().write(wr)?;
+                for child_id in node.children() {
+                    self.save_node(child_id, wr)?;
+                }
+                if node.parent() != Some(&self.root_id) {
+                    Chunk::ActorNodeUp().write(wr)?;
+                }
+            }
+            ActorNode::MeshfileRef(name) => self
+                .string_sizes
+                .sized(Chunk::MeshFileRef(name.clone(), StringSize::Game))
+                .write(wr)?,
+            ActorNode::MaterialRef(name) => self
+                .string_sizes
+                .sized(Chunk::MaterialRef(name.clone(), StringSize::Game))
+                .write(wr)?,
+            ActorNode::BoundingBox { min, max } => {
+                Chunk::MapBoundingBox {
+                    min: *min,
+                    max: *max,
+                }
+                .write(wr)?;
+                Chunk::ActorBounds().write(wr)?;
+            }
+            ActorNode::Extra(c) => c.write(wr)?,
+            ActorNode::Root | ActorNode::Transform(_) => {} // written with the actor above
+        }
+        Ok(())
+    }
+
+    pub fn save_to(&self, fname: String) -> Result<(), Error> {
+        let file = File::create(fname)?;
+        let mut file = BufWriter::new(file);
+        self.save(&mut file)?;
+        file.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use {
+        super::*,
+        std::{fs, io::Cursor, path::Path},
+    };
+
+    fn describe(actor: &Actor) -> Vec<String> {
+        actor
+            .traverse()
+            .map(|node| format!("{} {:?}", actor.get_node_depth(node), node.data()))
+            .collect()
+    }
+
+    #[test]
+    fn test_save_actor() {
+        let actor = Actor::load_from(String::from("DecodedData/DATA/ACTORS/EAGYELE.ACT")).unwrap();
+        let mut data = Vec::new();
+        actor.save(&mut data).unwrap();
+        let reloaded = Actor::load(&mut Cursor::new(data)).unwrap();
+        assert_eq!(describe(&actor), describe(&reloaded));
+
+        // Actor type and render style are written back as loaded.
+        let styles = |actor: &Actor| -> Vec<(u8, u8)> {
+            actor
+                .traverse()
+                .filter_map(|node| match node.data() {
+                    ActorNode::Actor {
+                        actor_type,
+                        render_style,
+                        ..
+                    } => Some((*actor_type, *render_style)),
+                    _ => None,
+                })
+                .collect()
+        };
+        assert_eq!((1, 4), styles(&actor)[0]);
+        assert_eq!(styles(&actor), styles(&reloaded));
+    }
+
+    #[test]
+    fn test_load_track_actor_bounds() {
+        let actor = Actor::load_from_with(
+            String::from("DecodedData/DATA/ACTORS/ARENA.ACT"),
+            Strictness::Strict,
+        )
+        .unwrap();
+
+        // Boxes with names of the actors above them, closest first.
+        let boxes: Vec<_> = actor
+            .traverse()
+            .filter_map(|node| match node.data() {
+                ActorNode::BoundingBox { min, max } => {
+                    let mut names = Vec::new();
+                    let mut parent = node.parent();
+                    while let Some(id) = parent {
+                        let up = actor.tree.get(id).unwrap();
+                        if let ActorNode::Actor { name, .. } = up.data() {
+                            names.push(name.as_str());
+                        }
+                        parent = up.parent();
+                    }
+                    Some((names, *min, *max))
+                }
+                _ => None,
+            })
+            .collect();
+        assert_eq!(255, boxes.len());
+
+        // Each box goes to the actor named before it: the root arena actor first,
+        // then unnamed sub-actors nested below it.
+        let root = "16 16 0.160 57";
+        assert_eq!(vec![root], boxes[0].0);
+        assert_eq!(vec!["", root], boxes[1].0);
+        assert_eq!(vec!["", "", root], boxes[2].0);
+        assert!(boxes[1..].iter().all(|(names, ..)| names.len() > 1));
+
+        // Whole arena bounds, centered on the origin
+        let (_, min, max) = &boxes[0];
+        assert_eq!([-max[0], -max[1], -max[2]], *min);
+        assert!((max[0] - 659.486).abs() < 0.001);
+        for (_, min, max) in &boxes {
+            assert!(min[0] <= max[0] && min[1] <= max[1] && min[2] <= max[2]);
+        }
+
+        let mut data = Vec::new();
+        actor.save(&mut data).unwrap();
+        let reloaded = Actor::load_with(&mut Cursor::new(data), Strictness::Strict).unwrap();
+        assert_eq!(describe(&actor), describe(&reloaded));
+    }
+
+    #[test]
+    fn test_save_game_actors() {
+        const CORRUPT: &[&str] = &["&CRANE.ACT", "PROG.ACT"];
+        // Older track actors, with sub-actors missing transform and node down. Their
+        // meshes load into the parent actor, the tree doesn't keep how they were written.
+        const UNNESTED: &[&str] = &[
+            "CITYAA.ACT",
+            "CITYC.ACT",
+            "COASTA.ACT",
+            "COASTC.ACT",
+            "ICELAND.ACT",
+            "INDUSTB.ACT",
+            "MANXX.ACT",
+            "MINES.ACT",
+            "PROTO2.ACT",
+            "SUBURB.ACT",
+        ];
+        let mut saved = 0;
+        for entry in fs::read_dir(Path::new("DecodedData/DATA/ACTORS")).unwrap() {
+            let path = entry.unwrap().path();
+            let name = path.file_name().unwrap().to_str().unwrap();
+            if CORRUPT.contains(&name) || UNNESTED.contains(&name) {
+                continue;
+            }
+            let original = fs::read(&path).unwrap();
+            let actor = Actor::load_with(&mut Cursor::new(&original), Strictness::Strict)
+                .unwrap_or_else(|e| panic!("{:?} does not load: {}", path, e));
+            let mut data = Vec::new();
+            actor.save(&mut data).unwrap();
+            assert!(original == data, "{:?} is not byte-exact", path);
+            saved += 1;
+        }
+        assert!(saved > 300);
+    }
 }
@@ -0,0 +1,280 @@
+//
+// Part of Roadkill Project.
+//
+// Copyright 2010, 2017, Stanislav Karchebnyy <berkus@madfire.net>
+//
+// Distributed under the Boost Software License, Version 1.0.
+// (See file LICENSE_1_0.txt or a copy at http://www.boost.org/LICENSE_1_0.txt)
+//
+use {
+    crate::support::{
+        texture::{PixelFormat, PixelMap, Texture, TextureReference},
+        Error,
+    },
+    log::*,
+    std::collections::{BTreeMap, HashMap},
+};
+
+pub const DEFAULT_ATLAS_SIZE: u16 = 1024;
+// Linear filtering samples one texel around the rect and every mip level
+// doubles that, four keep the first two levels clear of the neighbours.
+pub const DEFAULT_PADDING: u16 = 4;
+
+/**
+ * Set of megatexture pages with all packed pixelmaps, looked up by pixelmap name.
+ */
+#[derive(Default)]
+pub struct Atlas {
+    pub pages: Vec<Texture>,
+    pub refs: HashMap<String, TextureReference>,
+}
+
+// Row of rects of at most h pixels high, filled left to right.
+struct Shelf {
+    y: u32,
+    h: u32,
+    x: u32,
+}
+
+struct Page {
+    w: u32,
+    h: u32,
+    bpp: usize,
+    shelves: Vec<Shelf>,
+    data: Vec<u8>,
+}
+
+impl Page {
+    fn new(w: u32, h: u32, bpp: usize) -> Page {
+        Page {
+            w,
+            h,
+            bpp,
+            shelves: Vec::new(),
+            data: vec![0; (w * h) as usize * bpp],
+        }
+    }
+
+    // Find top left corner for a w x h rect, opening a new shelf if needed.
+    fn place(&mut self, w: u32, h: u32) -> Option<(u32, u32)> {
+        for shelf in &mut self.shelves {
+            if h <= shelf.h && shelf.x + w <= self.w {
+                let x = shelf.x;
+                shelf.x += w;
+                return Some((x, shelf.y));
+            }
+        }
+        let y = self.shelves.last().map_or(0, |s| s.y + s.h);
+        if y + h > self.h || w > self.w {
+            return None;
+        }
+        self.shelves.push(Shelf { y, h, x: w });
+        Some((0, y))
+    }
+
+    // Copy pixels with their top left corner at x,y and repeat the edge pixels
+    // into the padding around them, so filtering near the edge does not pick up neighbours.
+    fn blit(&mut self, pm: &PixelMap, x: u32, y: u32, padding: u32) {
+        let (w, h, bpp) = (u32::from(pm.w), u32::from(pm.h), self.bpp);
+        for dy in 0..h + 2 * padding {
+            let sy = dy.saturating_sub(padding).min(h - 1);
+            for dx in 0..w + 2 * padding {
+                let sx = dx.saturating_sub(padding).min(w - 1);
+                let src = (sy * w + sx) as usize * bpp;
+                let dst = ((y + dy - padding) * self.w + x + dx - padding) as usize * bpp;
+                self.data[dst..dst + bpp].copy_from_slice(&pm.data[src..src + bpp]);
+            }
+        }
+    }
+}
+
+impl Atlas {
+    pub fn build<'a, I>(pixelmaps: I) -> Result<Atlas, Error>
+    where
+        I: IntoIterator<Item = &'a PixelMap>,
+    {
+        Atlas::build_with(pixelmaps, DEFAULT_ATLAS_SIZE, DEFAULT_PADDING)
+    }
+
+    /// Pack remapped RGBA or 8-bit indexed pixelmaps into size x size pages using shelves
+    /// of similar height. All pixelmaps must have the same format, which pages get too.
+    /// A pixelmap too big for a page gets a page of its own size.
+    /// Layout depends only on names and sizes, so indexed and remapped versions
+    /// of the same set of pixelmaps are packed alike.
+    pub fn build_with<'a, I>(pixelmaps: I, size: u16, padding: u16) -> Result<Atlas, Error>
+    where
+        I: IntoIterator<Item = &'a PixelMap>,
+    {
+        let (size, padding) = (u32::from(size), u32::from(padding));
+
+        // Sorted by name first so the layout does not depend on hashmap order.
+        let by_name: BTreeMap<_, _> = pixelmaps
+            .into_iter()
+            .filter(|pm| pm.w > 0 && pm.h > 0)
+            .map(|pm| (pm.name.as_str(), pm))
+            .collect();
+        let mut sorted: Vec<_> = by_name.into_values().collect();
+        sorted.sort_by(|a, b| b.h.cmp(&a.h).then(b.w.cmp(&a.w)));
+
+        let format = sorted.first().map_or(PixelFormat::Rgba8888, |pm| pm.format);
+        let bpp = match format {
+            PixelFormat::Rgba8888 => 4,
+            _ => 1,
+        };
+        let mut pages = Vec::<Page>::new();
+        let mut refs = HashMap::new();
+        for pm in sorted {
+            let (w, h) = (usize::from(pm.w), usize::from(pm.h));
+            if !matches!(pm.format, PixelFormat::Rgba8888 | PixelFormat::Index8)
+                || pm.format != format
+                || (pm.row_bytes != 0 && usize::from(pm.row_bytes) != w * bpp)
+                || pm.data.len() < w * h * bpp
+            {
+                return Err(Error::BadPixelmap {
+                    name: pm.name.clone(),
+                    reason: format!(
+                        "atlas of {} needs tightly packed pixels, got {}",
+                        format, pm.format
+                    ),
+                });
+            }
+
+            let (pw, ph) = (w as u32 + 2 * padding, h as u32 + 2 * padding);
+            let placed = pages
+                .iter_mut()
+                .enumerate()
+                .find_map(|(id, page)| page.place(pw, ph).map(|(x, y)| (id, x, y)));
+            let (id, x, y) = match placed {
+                Some(spot) => spot,
+                None => {
+                    let mut page = Page::new(size.max(pw), size.max(ph), bpp);
+                    let (x, y) = page.place(pw, ph).unwrap();
+                    pages.push(page);
+                    (pages.len() - 1, x, y)
+                }
+            };
+
+            let page = &mut pages[id];
+            let (x, y) = (x + padding, y + padding);
+            page.blit(pm, x, y, padding);
+            trace!("Atlas page {} at {},{}: {}", id, x, y, pm);
+            refs.insert(
+                pm.name.clone(),
+                TextureReference {
+                    id: id as i32,
+                    x0: x as f32 / page.w as f32,
+                    y0: y as f32 / page.h as f32,
+                    x1: (x + w as u32) as f32 / page.w as f32,
+                    y1: (y + h as u32) as f32 / page.h as f32,
+                    name: pm.name.clone(),
+                },
+            );
+        }
+
+        debug!("Packed {} pixelmaps into {} pages", refs.len(), pages.len());
+        Ok(Atlas {
+            pages: pages
+                .into_iter()
+                .map(|page| Texture {
+                    w: page.w as u16,
+                    h: page.h as u16,
+                    format,
+                    data: page.data,
+                })
+                .collect(),
+            refs,
+        })
+    }
+
+    pub fn get(&self, name: &str) -> Option<&TextureReference> {
+        self.refs.get(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn solid(name: &str, w: u16, h: u16, color: [u8; 4]) -> PixelMap {
+        let mut pm = PixelMap::default();
+        pm.name = String::from(name);
+        pm.w = w;
+        pm.h = h;
+        pm.format = PixelFormat::Rgba8888;
+        pm.unit_bytes = 4;
+        pm.units = u32::from(w) * u32::from(h);
+        pm.data = color.repeat(pm.units as usize);
+        pm
+    }
+
+    fn pixel(page: &Texture, x: u32, y: u32) -> [u8; 4] {
+        let at = ((y * u32::from(page.w) + x) * 4) as usize;
+        page.data[at..at + 4].try_into().unwrap()
+    }
+
+    #[test]
+    fn test_pack_with_padding() {
+        let red = solid("RED.PIX", 16, 8, [255, 0, 0, 255]);
+        let blue = solid("BLUE.PIX", 8, 8, [0, 0, 255, 255]);
+        let atlas = Atlas::build_with([&red, &blue], 32, 1).unwrap();
+        assert_eq!(1, atlas.pages.len());
+
+        let page = &atlas.pages[0];
+        let r = atlas.get("RED.PIX").unwrap();
+        let b = atlas.get("BLUE.PIX").unwrap();
+        // Wider one goes first on the shelf, the other follows after both paddings.
+        assert_eq!(
+            (1.0 / 32.0, 1.0 / 32.0, 17.0 / 32.0, 9.0 / 32.0),
+            (r.x0, r.y0, r.x1, r.y1)
+        );
+        assert_eq!((19.0 / 32.0, 1.0 / 32.0), (b.x0, b.y0));
+        // Padding repeats the edges, the space between rects is left empty.
+        assert_eq!([255, 0, 0, 255], pixel(page, 0, 0));
+        assert_eq!([255, 0, 0, 255], pixel(page, 17, 9));
+        assert_eq!([0, 0, 255, 255], pixel(page, 18, 0));
+        assert_eq!([0, 0, 0, 0], pixel(page, 0, 10));
+
+        // Bottom left corner of the pixelmap in GL coordinates lands on the bottom left of its rect.
+        assert_eq!([r.x0, 1.0 - r.y1], r.map([0.0, 0.0]));
+        assert_eq!([r.x1, 1.0 - r.y0], r.map([1.0, 1.0]));
+    }
+
+    #[test]
+    fn test_pack_overflow() {
+        let maps: Vec<_> = (0..5)
+            .map(|n| solid(&format!("{}.PIX", n), 16, 16, [n, n, n, 255]))
+            .collect();
+        let big = solid("BIG.PIX", 64, 8, [1, 2, 3, 4]);
+        let atlas = Atlas::build_with(maps.iter().chain([&big]), 32, 0).unwrap();
+        // Four 16x16 fill a page, the fifth starts another and the big one gets its own.
+        assert_eq!(3, atlas.pages.len());
+        assert_eq!((64, 32), (atlas.pages[2].w, atlas.pages[2].h));
+        assert_eq!(2, atlas.get("BIG.PIX").unwrap().id);
+        assert_eq!(1, atlas.get("4.PIX").unwrap().id);
+        assert_eq!(6, atlas.refs.len());
+    }
+
+    #[test]
+    fn test_pack_indexed() {
+        let rgba = solid("A.PIX", 4, 4, [1, 2, 3, 4]);
+        let mut indexed = rgba.clone();
+        indexed.format = PixelFormat::Index8;
+        indexed.unit_bytes = 1;
+        indexed.data = vec![7; 16];
+
+        let pages = Atlas::build_with([&indexed], 8, 1).unwrap();
+        assert_eq!(PixelFormat::Index8, pages.pages[0].format);
+        assert_eq!(64, pages.pages[0].data.len());
+        assert_eq!(7, pages.pages[0].data[5 * 8 + 5]);
+        assert_eq!(Atlas::build_with([&rgba], 8, 1).unwrap().refs, pages.refs);
+
+        // Formats can't be mixed in one atlas.
+        let mut other = indexed.clone();
+        other.name = String::from("B.PIX");
+        other.format = PixelFormat::Rgb565;
+        assert!(matches!(
+            Atlas::build([&indexed, &other]),
+            Err(Error::BadPixelmap { name, .. }) if name == "B.PIX"
+        ));
+    }
+}
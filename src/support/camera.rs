@@ -8,6 +8,18 @@
 //
 use {cgmath::*, glium::glutin};
 
+/// Corners of an axis-aligned box.
+pub fn box_corners(min: [f32; 3], max: [f32; 3]) -> [Point3<f32>; 8] {
+    let pick = |corner: usize, axis: usize| {
+        if corner & (1 << axis) == 0 {
+            min[axis]
+        } else {
+            max[axis]
+        }
+    };
+    std::array::from_fn(|corner| Point3::new(pick(corner, 0), pick(corner, 1), pick(corner, 2)))
+}
+
 pub struct CameraState {
     aspect_ratio: f32,
     fov: f32,
@@ -26,6 +38,12 @@ pub struct CameraState {
     rotating_right: bool,
 }
 
+impl Default for CameraState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl CameraState {
     pub fn new() -> CameraState {
         CameraState {
@@ -80,6 +98,44 @@ impl CameraState {
         Matrix4::look_at_rh(self.position, Point3::from_vec(self.direction), up).into()
     }
 
+    /// Pixel position of a world point on a w x h screen, counted from the top left.
+    /// None for points behind the camera.
+    pub fn project(&self, point: Vector3<f32>, (w, h): (u32, u32)) -> Option<[f32; 2]> {
+        let clip = Matrix4::from(self.get_perspective())
+            * Matrix4::from(self.get_view())
+            * point.extend(1.0);
+        if clip.w <= 0.0 {
+            return None;
+        }
+        Some([
+            (clip.x / clip.w + 1.0) * 0.5 * w as f32,
+            (1.0 - clip.y / clip.w) * 0.5 * h as f32,
+        ])
+    }
+
+    /// Whether a box in model space may be in view. It is left out only when all
+    /// of its corners are on the outer side of the same frustum plane.
+    pub fn in_view(&self, model: &Matrix4<f32>, min: [f32; 3], max: [f32; 3]) -> bool {
+        let clip = Matrix4::from(self.get_perspective()) * Matrix4::from(self.get_view()) * model;
+        let corners = box_corners(min, max).map(|c| clip * c.to_homogeneous());
+        !(0..3).any(|axis| {
+            corners.iter().all(|c| c[axis] < -c.w) || corners.iter().all(|c| c[axis] > c.w)
+        })
+    }
+
+    /// Move the camera to see all of a box in world space, from the front and above.
+    pub fn frame(&mut self, min: [f32; 3], max: [f32; 3]) {
+        let (min, max) = (Point3::from(min), Point3::from(max));
+        let centre = min.midpoint(max);
+        let radius = min.distance(max) / 2.0;
+        // Sphere around the box fits into the vertical field of view, the narrower one.
+        let distance = radius / (Deg(self.fov) / 2.0).sin();
+        self.position = centre + Vector3::new(0.0, 0.5, 1.0).normalize() * distance;
+        // get_view() looks at direction as a point.
+        self.direction = centre.to_vec();
+        self.zfar = self.zfar.max(distance + radius);
+    }
+
     pub fn update(&mut self) {
         let f = self.direction.normalize();
         let up = Vector3 {
@@ -160,3 +216,41 @@ impl CameraState {
         };
     }
 }
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_in_view() {
+        let camera = CameraState::new();
+        let model = Matrix4::identity();
+        // In front of the camera, behind it and off to the side.
+        assert!(camera.in_view(&model, [-0.5, -0.5, -2.0], [0.5, 0.5, -1.0]));
+        assert!(!camera.in_view(&model, [-0.5, -0.5, 2.0], [0.5, 0.5, 3.0]));
+        assert!(!camera.in_view(&model, [10.0, -0.5, -2.0], [11.0, 0.5, -1.0]));
+        // Moved into view by the model transform.
+        let model = Matrix4::from_translation(Vector3::new(-10.5, 0.0, 0.0));
+        assert!(camera.in_view(&model, [10.0, -0.5, -2.0], [11.0, 0.5, -1.0]));
+        // Bigger than the view and around it.
+        assert!(camera.in_view(&model, [-50.0, -50.0, -50.0], [50.0, 50.0, 50.0]));
+    }
+
+    #[test]
+    fn test_frame() {
+        let mut camera = CameraState::new();
+        let (min, max) = ([0.0, 0.0, -5.0], [6.0, 0.5, 0.0]);
+        let on_screen = |camera: &CameraState| {
+            box_corners(min, max).iter().all(|c| {
+                camera
+                    .project(c.to_vec(), (800, 600))
+                    .is_some_and(|[x, y]| (0.0..=800.0).contains(&x) && (0.0..=600.0).contains(&y))
+            })
+        };
+        assert!(!on_screen(&camera));
+        camera.frame(min, max);
+        assert!(on_screen(&camera));
+        assert!(camera.in_view(&Matrix4::identity(), min, max));
+    }
+}
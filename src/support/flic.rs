@@ -0,0 +1,674 @@
+//
+// Part of Roadkill Project.
+//
+// Copyright 2010, 2017, Stanislav Karchebnyy <berkus@madfire.net>
+//
+// Distributed under the Boost Software License, Version 1.0.
+// (See file LICENSE_1_0.txt or a copy at http://www.boost.org/LICENSE_1_0.txt)
+//
+use {
+    crate::support::{
+        palette::Palette,
+        texture::{PixelFormat, PixelMap, Transparency},
+        Error,
+    },
+    log::*,
+    png,
+    std::{
+        fs::{self, File},
+        io::{BufWriter, Read, Write},
+        path::{Path, PathBuf},
+    },
+};
+
+/// The game draws its FLICs with this palette, most of them carry no colours of their own.
+pub const DEFAULT_PALETTE: &str = "DecodedData/DATA/REG/PALETTES/DRACEFLC.PAL";
+
+pub const FLI_MAGIC: u16 = 0xaf11; // original Animator, 320x200 and speed in 1/70 s
+pub const FLC_MAGIC: u16 = 0xaf12; // Animator Pro, any size and speed in ms
+const HEADER_SIZE: usize = 128;
+const FRAME_MAGIC: u16 = 0xf1fa;
+const FRAME_HEADER_SIZE: usize = 16;
+
+// Frame subchunk types.
+const COLOR_256: u16 = 4;
+const DELTA_FLC: u16 = 7;
+const COLOR_64: u16 = 11;
+const DELTA_FLI: u16 = 12;
+const BLACK: u16 = 13;
+const BYTE_RUN: u16 = 15;
+const FLI_COPY: u16 = 16;
+
+/**
+ * Autodesk FLI/FLC animation, kept in memory and decoded frame by frame.
+ * Frames are 8-bit, palette chunks in the file change the palette given at load.
+ */
+pub struct Flic {
+    pub name: String,
+    pub w: u16,
+    pub h: u16,
+    pub frame_count: u16,
+    pub delay_ms: u32,
+    palette: Palette,
+    data: Vec<u8>,
+    first_frame: usize,
+}
+
+/**
+ * One decoded frame with the palette in effect for it.
+ */
+#[derive(Clone)]
+pub struct Frame {
+    pub number: u16,
+    pub w: u16,
+    pub h: u16,
+    pub pixels: Vec<u8>, // w x h indices, top row first
+    pub palette: Palette,
+}
+
+/// Iterator over decoded frames, see Flic::frames().
+pub struct Frames<'a> {
+    flic: &'a Flic,
+    offset: usize,
+    number: u16,
+    pixels: Vec<u8>,
+    palette: Palette,
+}
+
+// Little-endian reader over a chunk, running out of data is an error.
+struct Body<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Body<'a> {
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], String> {
+        if n > self.data.len() {
+            return Err(format!("needs {} more bytes, {} left", n, self.data.len()));
+        }
+        let (head, tail) = self.data.split_at(n);
+        self.data = tail;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn i8(&mut self) -> Result<i8, String> {
+        Ok(self.u8()? as i8)
+    }
+
+    fn u16(&mut self) -> Result<u16, String> {
+        Ok(u16::from_le_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+}
+
+impl Frame {
+    /// Frame as a tightly packed 8-bit pixelmap, e.g. KINGMERC_003.PIX for frame 3 of KINGMERC.FLI.
+    pub fn to_pixelmap(&self, flic_name: &str) -> PixelMap {
+        let stem = flic_name
+            .rsplit_once('.')
+            .map_or(flic_name, |(stem, _)| stem);
+        PixelMap {
+            name: format!("{}_{:03}.PIX", stem, self.number),
+            w: self.w,
+            h: self.h,
+            format: PixelFormat::Index8,
+            units: u32::from(self.w) * u32::from(self.h),
+            unit_bytes: 1,
+            data: self.pixels.clone(),
+            ..Default::default()
+        }
+    }
+
+    /// Frame remapped to RGBA, animations are shown whole so every colour is opaque.
+    pub fn to_rgba(&self, flic_name: &str) -> Result<PixelMap, Error> {
+        self.to_pixelmap(flic_name)
+            .remap_with(&self.palette, Transparency::OPAQUE)
+    }
+}
+
+// Copy src into pixels at offset, staying inside the frame.
+fn put(pixels: &mut [u8], at: usize, src: &[u8]) -> Result<(), String> {
+    pixels
+        .get_mut(at..at + src.len())
+        .ok_or_else(|| format!("{} pixels at {} run out of frame", src.len(), at))?
+        .copy_from_slice(src);
+    Ok(())
+}
+
+fn fill(pixels: &mut [u8], at: usize, count: usize, value: u8) -> Result<(), String> {
+    pixels
+        .get_mut(at..at + count)
+        .ok_or_else(|| format!("{} pixels at {} run out of frame", count, at))?
+        .fill(value);
+    Ok(())
+}
+
+impl<'a> Frames<'a> {
+    fn decode_colors(&mut self, mut body: Body, scale: bool) -> Result<(), String> {
+        let packets = body.u16()?;
+        let mut index = 0usize;
+        for _ in 0..packets {
+            index += usize::from(body.u8()?);
+            let count = match body.u8()? {
+                0 => 256,
+                count => usize::from(count),
+            };
+            if index + count > 256 {
+                return Err(format!("{} colours from index {}", count, index));
+            }
+            for rgb in body.bytes(count * 3)?.chunks(3) {
+                // 64 level colours scale up filling the low bits like expand_bits() does.
+                let rgb = match scale {
+                    true => [rgb[0], rgb[1], rgb[2]].map(|c| (c << 2) | (c >> 4)),
+                    false => [rgb[0], rgb[1], rgb[2]],
+                };
+                self.palette
+                    .set_rgba(index as u8, [rgb[0], rgb[1], rgb[2], 255]);
+                index += 1;
+            }
+        }
+        Ok(())
+    }
+
+    // Line and packet compression of words, changed lines only.
+    fn decode_delta_flc(&mut self, mut body: Body) -> Result<(), String> {
+        let (w, h) = (usize::from(self.flic.w), usize::from(self.flic.h));
+        let lines = body.u16()?;
+        let mut y = 0usize;
+        for _ in 0..lines {
+            let packets = loop {
+                let word = body.u16()?;
+                match word >> 14 {
+                    0b11 => y += usize::from(word.wrapping_neg()),
+                    0b10 if y < h => self.pixels[y * w + w - 1] = word as u8,
+                    0b00 => break word,
+                    _ => return Err(format!("bad line opcode {:04x} at line {}", word, y)),
+                }
+            };
+            if y >= h {
+                return Err(format!("line {} out of frame", y));
+            }
+            let mut x = 0usize;
+            for _ in 0..packets {
+                x += usize::from(body.u8()?);
+                let count = body.i8()?;
+                let at = y * w + x;
+                if count >= 0 {
+                    let n = usize::from(count as u8) * 2;
+                    put(&mut self.pixels[..(y + 1) * w], at, body.bytes(n)?)?;
+                    x += n;
+                } else {
+                    let pair = body.bytes(2)?;
+                    let n = usize::from(count.unsigned_abs());
+                    put(&mut self.pixels[..(y + 1) * w], at, &pair.repeat(n))?;
+                    x += n * 2;
+                }
+            }
+            y += 1;
+        }
+        Ok(())
+    }
+
+    // Line and packet compression of bytes from the original FLI format.
+    fn decode_delta_fli(&mut self, mut body: Body) -> Result<(), String> {
+        let (w, h) = (usize::from(self.flic.w), usize::from(self.flic.h));
+        let first = usize::from(body.u16()?);
+        let lines = usize::from(body.u16()?);
+        if first + lines > h {
+            return Err(format!("{} lines from {} out of frame", lines, first));
+        }
+        for y in first..first + lines {
+            let row = &mut self.pixels[y * w..(y + 1) * w];
+            let packets = body.u8()?;
+            let mut x = 0usize;
+            for _ in 0..packets {
+                x += usize::from(body.u8()?);
+                let count = body.i8()?;
+                if count >= 0 {
+                    let n = usize::from(count as u8);
+                    put(row, x, body.bytes(n)?)?;
+                    x += n;
+                } else {
+                    let n = usize::from(count.unsigned_abs());
+                    fill(row, x, n, body.u8()?)?;
+                    x += n;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Run length compressed whole frame, usually the first one.
+    fn decode_byte_run(&mut self, mut body: Body) -> Result<(), String> {
+        let w = usize::from(self.flic.w);
+        for row in self.pixels.chunks_mut(w) {
+            body.u8()?; // packet count, unreliable for wide frames
+            let mut x = 0usize;
+            while x < w {
+                let count = body.i8()?;
+                if count > 0 {
+                    let n = usize::from(count as u8);
+                    fill(row, x, n, body.u8()?)?;
+                    x += n;
+                } else if count < 0 {
+                    let n = usize::from(count.unsigned_abs());
+                    put(row, x, body.bytes(n)?)?;
+                    x += n;
+                } else {
+                    return Err(String::from("empty run"));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn decode_frame(&mut self, mut body: Body) -> Result<(), String> {
+        let chunks = body.u16()?;
+        body.bytes(FRAME_HEADER_SIZE - 8)?; // delay, reserved and size overrides
+        for _ in 0..chunks {
+            let size = body.u32()? as usize;
+            let kind = body.u16()?;
+            if size < 6 {
+                return Err(format!("chunk type {} of {} bytes", kind, size));
+            }
+            let chunk = Body {
+                data: body.bytes(size - 6)?,
+            };
+            trace!("Frame {} chunk type {}, {} bytes", self.number, kind, size);
+            match kind {
+                COLOR_256 => self.decode_colors(chunk, false)?,
+                COLOR_64 => self.decode_colors(chunk, true)?,
+                DELTA_FLC => self.decode_delta_flc(chunk)?,
+                DELTA_FLI => self.decode_delta_fli(chunk)?,
+                BLACK => self.pixels.fill(0),
+                BYTE_RUN => self.decode_byte_run(chunk)?,
+                FLI_COPY => {
+                    let mut chunk = chunk;
+                    let len = self.pixels.len();
+                    put(&mut self.pixels, 0, chunk.bytes(len)?)?
+                }
+                // Postage stamp and other chunks don't change the picture.
+                _ => debug!("Skipping frame chunk type {}", kind),
+            }
+        }
+        Ok(())
+    }
+
+    fn next_frame(&mut self) -> Result<Frame, Error> {
+        let error = |reason| Error::BadFlic {
+            name: self.flic.name.clone(),
+            reason,
+        };
+        // Skip prefix, segment table and other chunks between frames.
+        loop {
+            let at = self.offset;
+            let mut header = Body {
+                data: self.flic.data.get(at..).unwrap_or_default(),
+            };
+            let size = header
+                .u32()
+                .map_err(|e| error(format!("frame {} at {}: {}", self.number, at, e)))?
+                as usize;
+            let kind = header
+                .u16()
+                .map_err(|e| error(format!("frame {} at {}: {}", self.number, at, e)))?;
+            let too_short = match kind {
+                FRAME_MAGIC => FRAME_HEADER_SIZE,
+                _ => 6,
+            };
+            if size < too_short || size > self.flic.data.len() - at {
+                return Err(error(format!(
+                    "chunk type {:04x} at {} of {} bytes",
+                    kind, at, size
+                )));
+            }
+            self.offset += size;
+            if kind != FRAME_MAGIC {
+                debug!("Skipping chunk type {:04x} at {}", kind, at);
+                continue;
+            }
+            let body = Body {
+                data: &self.flic.data[at + 6..at + size],
+            };
+            self.decode_frame(body)
+                .map_err(|e| error(format!("frame {} at {}: {}", self.number, at, e)))?;
+            return Ok(Frame {
+                number: self.number,
+                w: self.flic.w,
+                h: self.flic.h,
+                pixels: self.pixels.clone(),
+                palette: self.palette.clone(),
+            });
+        }
+    }
+}
+
+impl<'a> Iterator for Frames<'a> {
+    type Item = Result<Frame, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.number >= self.flic.frame_count {
+            return None;
+        }
+        let frame = self.next_frame();
+        // Deltas after a broken frame make no sense, stop there.
+        self.number = match frame {
+            Ok(_) => self.number + 1,
+            Err(_) => self.flic.frame_count,
+        };
+        Some(frame)
+    }
+}
+
+impl Flic {
+    /// Parse the header of FLI or FLC data, frames are decoded when iterated.
+    pub fn read_from<R: Read>(rdr: &mut R, name: String, palette: Palette) -> Result<Flic, Error> {
+        let mut data = Vec::new();
+        rdr.read_to_end(&mut data)?;
+        let error = |reason| Error::BadFlic {
+            name: name.clone(),
+            reason,
+        };
+        if data.len() < HEADER_SIZE {
+            return Err(error(format!("{} bytes is too short", data.len())));
+        }
+        // Header is long enough for all the fields.
+        let field = |at: usize| u16::from_le_bytes([data[at], data[at + 1]]);
+        let (magic, frame_count, w, h, depth) =
+            (field(4), field(6), field(8), field(10), field(12));
+        let speed = u32::from_le_bytes(data[16..20].try_into().unwrap());
+        if depth != 8 && depth != 0 {
+            return Err(error(format!("{} bits per pixel", depth)));
+        }
+
+        let (w, h, delay_ms, first_frame) = match magic {
+            // Original FLI is always 320x200 but some writers leave size out.
+            FLI_MAGIC => (
+                if w == 0 { 320 } else { w },
+                if h == 0 { 200 } else { h },
+                (speed & 0xffff) * 1000 / 70,
+                HEADER_SIZE,
+            ),
+            FLC_MAGIC => {
+                let oframe1 = u32::from_le_bytes(data[80..84].try_into().unwrap()) as usize;
+                let first = match oframe1 {
+                    at if at >= HEADER_SIZE && at < data.len() => at,
+                    _ => HEADER_SIZE,
+                };
+                (w, h, speed, first)
+            }
+            other => return Err(error(format!("bad magic {:04x}", other))),
+        };
+        if w == 0 || h == 0 {
+            return Err(error(format!("{}x{} frames", w, h)));
+        }
+        debug!(
+            "Flic {}: {}x{}, {} frames, {} ms each",
+            name, w, h, frame_count, delay_ms
+        );
+        Ok(Flic {
+            name,
+            w,
+            h,
+            frame_count,
+            delay_ms,
+            palette,
+            data,
+            first_frame,
+        })
+    }
+
+    /// Load FLI or FLC file drawn with the game's DRACEFLC.PAL.
+    pub fn load_from(fname: String) -> Result<Flic, Error> {
+        let palette = Palette::load_from(String::from(DEFAULT_PALETTE))?;
+        Flic::load_from_with(fname, palette)
+    }
+
+    pub fn load_from_with(fname: String, palette: Palette) -> Result<Flic, Error> {
+        let path = PathBuf::from(&fname);
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let mut file = File::open(&path).map_err(|e| Error::from(e).in_file(&path))?;
+        Flic::read_from(&mut file, name, palette).map_err(|e| e.in_file(&path))
+    }
+
+    /// Decode frames in order, each one applied on top of the previous.
+    /// The loop frame FLICs end with is left out.
+    pub fn frames(&self) -> Frames<'_> {
+        Frames {
+            flic: self,
+            offset: self.first_frame,
+            number: 0,
+            pixels: vec![0; usize::from(self.w) * usize::from(self.h)],
+            palette: self.palette.clone(),
+        }
+    }
+
+    /// Write every frame as NAME_000.png, NAME_001.png and so on into dir.
+    pub fn write_png_sequence(&self, dir: &Path) -> Result<Vec<PathBuf>, Error> {
+        fs::create_dir_all(dir)?;
+        let mut files = Vec::new();
+        for frame in self.frames() {
+            let frame = frame?;
+            let pm = frame.to_pixelmap(&self.name);
+            let mut path = dir.join(&pm.name);
+            path.set_extension("png");
+            let mut file = BufWriter::new(File::create(&path)?);
+            pm.write_png_remapped_with(&frame.palette, Transparency::OPAQUE, &mut file)?;
+            file.flush()?;
+            files.push(path);
+        }
+        Ok(files)
+    }
+
+    /// Write all frames as one animated PNG, looping forever.
+    pub fn write_apng<W: Write>(&self, w: W) -> Result<(), Error> {
+        let error = |e: png::EncodingError| Error::BadFlic {
+            name: self.name.clone(),
+            reason: e.to_string(),
+        };
+        let mut encoder = png::Encoder::new(w, u32::from(self.w), u32::from(self.h));
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder
+            .set_animated(u32::from(self.frame_count), 0)
+            .map_err(error)?;
+        let mut writer = encoder.write_header().map_err(error)?;
+        let delay = self.delay_ms.min(u32::from(u16::MAX)) as u16;
+        for frame in self.frames() {
+            let rgba = frame?.to_rgba(&self.name)?;
+            writer.set_frame_delay(delay, 1000).map_err(error)?;
+            writer.write_image_data(&rgba.data).map_err(error)?;
+        }
+        writer.finish().map_err(error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(kind: u16, body: &[u8]) -> Vec<u8> {
+        let mut data = ((body.len() + 6) as u32).to_le_bytes().to_vec();
+        data.extend(kind.to_le_bytes());
+        data.extend(body);
+        data
+    }
+
+    fn frame(chunks: &[Vec<u8>]) -> Vec<u8> {
+        let mut body = (chunks.len() as u16).to_le_bytes().to_vec();
+        body.extend([0; FRAME_HEADER_SIZE - 8]);
+        body.extend(chunks.concat());
+        chunk(FRAME_MAGIC, &body)
+    }
+
+    fn flic(magic: u16, w: u16, h: u16, speed: u32, frames: &[Vec<u8>]) -> Vec<u8> {
+        let mut data = vec![0; HEADER_SIZE];
+        data[4..6].copy_from_slice(&magic.to_le_bytes());
+        data[6..8].copy_from_slice(&(frames.len() as u16 - 1).to_le_bytes());
+        data[8..10].copy_from_slice(&w.to_le_bytes());
+        data[10..12].copy_from_slice(&h.to_le_bytes());
+        data[12..14].copy_from_slice(&8u16.to_le_bytes());
+        data[16..20].copy_from_slice(&speed.to_le_bytes());
+        if magic == FLC_MAGIC {
+            // Prefix chunk before the first frame is skipped.
+            data.extend(chunk(0xf100, &[1, 2, 3, 4]));
+            data[80..84].copy_from_slice(&(HEADER_SIZE as u32).to_le_bytes());
+        }
+        data.extend(frames.concat());
+        let size = data.len() as u32;
+        data[0..4].copy_from_slice(&size.to_le_bytes());
+        data
+    }
+
+    fn decode(data: &[u8]) -> Vec<Frame> {
+        let flic =
+            Flic::read_from(&mut &data[..], String::from("TEST.FLC"), Palette::default()).unwrap();
+        flic.frames().collect::<Result<_, _>>().unwrap()
+    }
+
+    #[rustfmt::skip]
+    #[test]
+    fn test_decode_flc() {
+        let data = flic(FLC_MAGIC, 4, 3, 50, &[
+            frame(&[
+                // Colours 1 and 2, then 255 after skipping the rest.
+                chunk(COLOR_256, &[2, 0, 1, 2, 10, 20, 30, 40, 50, 60, 252, 1, 7, 8, 9]),
+                chunk(BYTE_RUN, &[
+                    0, 4, 1, // run of four 1s
+                    0, 0xfc, 1, 2, 1, 2, // literal
+                    0, 2, 2, 2, 255, // two runs
+                ]),
+            ]),
+            frame(&[chunk(DELTA_FLC, &[
+                2, 0, // two lines
+                0xff, 0xff, // skip one line
+                0x07, 0x80, // last pixel 7
+                1, 0, 1, 1, 1, 2, // one packet: skip 1, one word
+                1, 0, 0, 0xff, 4, 5, // one packet: word repeated once
+            ])]),
+            frame(&[chunk(DELTA_FLI, &[
+                2, 0, 1, 0, // one line from line 2
+                2, 1, 0xfe, 9, 0, 1, 3, // fill two 9s at 1, copy one 3 after
+            ])]),
+            frame(&[chunk(BLACK, &[])]),
+            // Ring frame back to the start isn't shown.
+            frame(&[chunk(FLI_COPY, &[6; 12])]),
+        ]);
+        let frames = decode(&data);
+        assert_eq!(4, frames.len());
+        assert_eq!([10, 20, 30, 255], frames[0].palette.rgba(1));
+        assert_eq!([7, 8, 9, 255], frames[0].palette.rgba(255));
+        assert_eq!(vec![1, 1, 1, 1, 1, 2, 1, 2, 2, 2, 255, 255], frames[0].pixels);
+        assert_eq!(vec![1, 1, 1, 1, 1, 1, 2, 7, 4, 5, 255, 255], frames[1].pixels);
+        assert_eq!(vec![1, 1, 1, 1, 1, 1, 2, 7, 4, 9, 9, 3], frames[2].pixels);
+        assert_eq!(vec![0; 12], frames[3].pixels);
+
+        let pm = frames[2].to_pixelmap("TEST.FLC");
+        assert_eq!("TEST_002.PIX", pm.name);
+        let rgba = frames[0].to_rgba("TEST.FLC").unwrap();
+        assert_eq!([10, 20, 30, 255], rgba.data[..4]);
+    }
+
+    #[test]
+    fn test_decode_fli() {
+        let data = flic(
+            FLI_MAGIC,
+            0,
+            0,
+            7,
+            &[
+                frame(&[
+                    chunk(COLOR_64, &[1, 0, 0, 2, 0, 0, 63, 0x3f, 0x20, 0]),
+                    chunk(FLI_COPY, &[1; 320 * 200]),
+                ]),
+                frame(&[]),
+            ],
+        );
+        let flic =
+            Flic::read_from(&mut &data[..], String::from("T.FLI"), Palette::default()).unwrap();
+        assert_eq!((320, 200, 100), (flic.w, flic.h, flic.delay_ms));
+        let frames = decode(&data);
+        assert_eq!([0, 0, 255, 255], frames[0].palette.rgba(0));
+        assert_eq!([255, 130, 0, 255], frames[0].palette.rgba(1));
+        assert!(frames[0].pixels.iter().all(|&p| p == 1));
+    }
+
+    #[test]
+    fn test_bad_frames() {
+        // Runs past the end of the frame or the chunk stop iteration with an error.
+        for body in [
+            chunk(DELTA_FLI, &[2, 0, 2, 0]),
+            chunk(DELTA_FLI, &[0, 0, 1, 0, 1, 0, 8, 1]),
+            chunk(BYTE_RUN, &[0, 0]),
+            chunk(FLI_COPY, &[0; 3]),
+        ] {
+            let data = flic(
+                FLC_MAGIC,
+                4,
+                3,
+                10,
+                &[frame(&[body]), frame(&[]), frame(&[])],
+            );
+            let flic = Flic::read_from(&mut &data[..], String::from("BAD.FLC"), Palette::default())
+                .unwrap();
+            let frames: Vec<_> = flic.frames().collect();
+            assert_eq!(1, frames.len());
+            assert!(matches!(frames[0], Err(Error::BadFlic { .. })));
+        }
+
+        let mut data = flic(FLC_MAGIC, 4, 3, 10, &[frame(&[]), frame(&[])]);
+        data[4] = 0;
+        assert!(matches!(
+            Flic::read_from(&mut &data[..], String::from("BAD.FLC"), Palette::default()),
+            Err(Error::BadFlic { .. })
+        ));
+    }
+
+    #[test]
+    fn test_export_with_default_palette() {
+        let data = flic(
+            FLC_MAGIC,
+            2,
+            2,
+            40,
+            &[
+                frame(&[chunk(FLI_COPY, &[0, 1, 2, 3])]),
+                frame(&[chunk(BLACK, &[])]),
+                frame(&[]),
+            ],
+        );
+        let dir = std::env::temp_dir().join(format!("carma-flic-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let fname = dir.join("TEST.FLC");
+        fs::write(&fname, &data).unwrap();
+
+        let flic = Flic::load_from(fname.to_string_lossy().into_owned()).unwrap();
+        let draceflc = Palette::load_from(String::from(DEFAULT_PALETTE)).unwrap();
+        let first = flic.frames().next().unwrap().unwrap();
+        assert_eq!(draceflc.colors(), first.palette.colors());
+
+        let files = flic.write_png_sequence(&dir).unwrap();
+        assert_eq!(
+            vec![dir.join("TEST_000.png"), dir.join("TEST_001.png")],
+            files
+        );
+        let png = PixelMap::read_png(String::new(), File::open(&files[0]).unwrap()).unwrap();
+        assert_eq!(first.to_rgba(&flic.name).unwrap().data, png.data);
+
+        let mut apng = Vec::new();
+        flic.write_apng(&mut apng).unwrap();
+        let reader = png::Decoder::new(apng.as_slice()).read_info().unwrap();
+        let control = reader.info().animation_control.unwrap();
+        assert_eq!(2, control.num_frames);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,308 @@
+//
+// Part of Roadkill Project.
+//
+// Copyright 2010, 2017, Stanislav Karchebnyy <berkus@madfire.net>
+//
+// Distributed under the Boost Software License, Version 1.0.
+// (See file LICENSE_1_0.txt or a copy at http://www.boost.org/LICENSE_1_0.txt)
+//
+use {
+    crate::support::{
+        path_subst,
+        resolution::ResolutionProfile,
+        text::TextReader,
+        texture::{PixelFormat, PixelMap},
+        Error,
+    },
+    log::*,
+    std::{
+        io,
+        path::{Path, PathBuf},
+    },
+};
+
+/**
+ * Bitmap font described by FONTS/NAME.ENC with all glyphs stacked top to bottom
+ * in the 8-bit NAME.PIX, one font height apart. Index 0 of glyphs is transparent.
+ */
+#[derive(Clone)]
+pub struct Font {
+    pub name: String,
+    pub height: u16,
+    pub spacing: i16, // added after every glyph, negative for overlapping digits of TIMER
+    pub first_char: u8, // character of the first glyph
+    widths: Vec<u16>, // per glyph, all the same for fixed width fonts
+    pub glyphs: PixelMap,
+}
+
+/// Glyph placed on a line of text, see Font::layout().
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PlacedGlyph {
+    pub x: i32,
+    pub index: usize, // glyph number, its rows start at index * height in the pixelmap
+    pub w: u16,
+}
+
+impl Font {
+    /// Parse a font description and check glyphs cover all of its characters.
+    pub fn parse(rdr: &mut TextReader, glyphs: PixelMap) -> Result<Font, Error> {
+        let height = rdr.parse_line::<u16>("font height")?;
+        let width = rdr.parse_line::<i16>("glyph width or -1 for proportional")?;
+        let spacing = rdr.parse_line::<i16>("spacing")?;
+        let first_char = rdr.parse_line::<u8>("first character code")?;
+        let count = rdr.parse_line::<u16>("number of glyphs")?;
+        // Proportional fonts list widths of all glyphs.
+        let widths = match width {
+            w if w > 0 => vec![w as u16; usize::from(count)],
+            _ => (0..count)
+                .map(|_| rdr.parse_line::<u16>("glyph width"))
+                .collect::<Result<_, _>>()?,
+        };
+
+        let name = rdr
+            .file()
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        if glyphs.format != PixelFormat::Index8
+            || u32::from(glyphs.h) < u32::from(height) * u32::from(count)
+            || widths.iter().any(|&w| w > glyphs.w)
+        {
+            return Err(Error::BadPixelmap {
+                name: glyphs.name.clone(),
+                reason: format!(
+                    "{} {} glyphs {} high don't fit in {}x{} {}",
+                    count, name, height, glyphs.w, glyphs.h, glyphs.format
+                ),
+            });
+        }
+        Ok(Font {
+            name,
+            height,
+            spacing,
+            first_char,
+            widths,
+            glyphs,
+        })
+    }
+
+    /// Load FONTS/NAME.ENC with glyphs from NAME.PIX next to it or in
+    /// the PIXELMAP directories of the same resolution or the shared one.
+    pub fn load_from(fname: String) -> Result<Font, Error> {
+        let path = PathBuf::from(&fname);
+        let pix = path.with_extension("PIX");
+        // Most fonts keep their glyphs in the shared DATA/PIXELMAP.
+        let candidates = [
+            pix.clone(),
+            path_subst(&pix, Path::new("PIXELMAP"), None),
+            ResolutionProfile::resolve_base(
+                &path,
+                Path::new("PIXELMAP"),
+                Some(String::from("PIX")),
+            ),
+        ];
+        let Some(pix) = candidates.into_iter().find(|p| p.exists()) else {
+            return Err(Error::from(io::Error::from(io::ErrorKind::NotFound)).in_file(&pix));
+        };
+        debug!("Font {:?} glyphs from {:?}", path, pix);
+
+        let glyphs = PixelMap::load_from(pix.to_string_lossy().into_owned())?
+            .into_iter()
+            .next()
+            .ok_or_else(|| Error::BadPixelmap {
+                name: pix.display().to_string(),
+                reason: String::from("no glyphs in file"),
+            })?;
+        Font::parse(&mut TextReader::open(&path)?, glyphs)
+    }
+
+    pub fn glyph_count(&self) -> usize {
+        self.widths.len()
+    }
+
+    fn glyph(&self, c: char) -> Option<usize> {
+        let index = u32::from(c).checked_sub(u32::from(self.first_char))? as usize;
+        (index < self.widths.len()).then_some(index)
+    }
+
+    /// Place glyphs of a single line of text starting at x = 0.
+    /// Characters the font has no glyph for are left out.
+    pub fn layout(&self, text: &str) -> Vec<PlacedGlyph> {
+        let mut x = 0i32;
+        text.chars()
+            .filter_map(|c| self.glyph(c))
+            .map(|index| {
+                let w = self.widths[index];
+                let glyph = PlacedGlyph { x, index, w };
+                x += i32::from(w) + i32::from(self.spacing);
+                glyph
+            })
+            .collect()
+    }
+
+    /// Width of text in pixels.
+    pub fn text_width(&self, text: &str) -> u32 {
+        self.layout(text)
+            .iter()
+            .map(|g| (g.x + i32::from(g.w)).max(0) as u32)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Draw text with its top left corner at x,y into an 8-bit pixelmap.
+    /// Transparent glyph pixels leave target alone, anything outside of it is clipped.
+    pub fn draw(&self, target: &mut PixelMap, x: i32, y: i32, text: &str) -> Result<(), Error> {
+        if target.format != PixelFormat::Index8 {
+            return Err(Error::BadPixelmap {
+                name: target.name.clone(),
+                reason: format!("can't draw text into {}", target.format),
+            });
+        }
+        let src_stride = usize::from(self.glyphs.row_bytes).max(usize::from(self.glyphs.w));
+        let dst_stride = usize::from(target.row_bytes).max(usize::from(target.w));
+        let (tw, th) = (i32::from(target.w), i32::from(target.h));
+        for glyph in self.layout(text) {
+            for gy in 0..usize::from(self.height) {
+                let ty = y + gy as i32;
+                if ty < 0 || ty >= th {
+                    continue;
+                }
+                let src_row = (glyph.index * usize::from(self.height) + gy) * src_stride;
+                for gx in 0..usize::from(glyph.w) {
+                    let tx = x + glyph.x + gx as i32;
+                    let pixel = self.glyphs.data.get(src_row + gx).copied().unwrap_or(0);
+                    if tx < 0 || tx >= tw || pixel == 0 {
+                        continue;
+                    }
+                    if let Some(dst) = target.data.get_mut(ty as usize * dst_stride + tx as usize) {
+                        *dst = pixel;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Text drawn into a new tight 8-bit pixelmap just big enough for it, with index 0 around the glyphs.
+    pub fn render(&self, text: &str) -> PixelMap {
+        let w = self.text_width(text).min(u32::from(u16::MAX)) as u16;
+        let mut pm = PixelMap {
+            name: format!("{}.PIX", self.name),
+            w,
+            h: self.height,
+            format: PixelFormat::Index8,
+            units: u32::from(w) * u32::from(self.height),
+            unit_bytes: 1,
+            data: vec![0; usize::from(w) * usize::from(self.height)],
+            ..Default::default()
+        };
+        // Can't fail, the target is 8-bit.
+        let _ = self.draw(&mut pm, 0, 0, text);
+        pm
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TYPEABLE: &str = "DecodedData/DATA/64X48X8/FONTS/TYPEABLE.ENC";
+
+    // Glyphs as big as their number, filled with index 10 + glyph.
+    fn glyphs(count: u16, height: u16, w: u16) -> PixelMap {
+        PixelMap {
+            name: String::from("TEST.PIX"),
+            w,
+            h: height * count,
+            format: PixelFormat::Index8,
+            data: (0..count * height)
+                .flat_map(|row| {
+                    let glyph = row / height;
+                    (0..w).map(move |x| if x < glyph + 1 { 10 + glyph as u8 } else { 0 })
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_load_game_font() {
+        let font = Font::load_from(String::from(TYPEABLE)).unwrap();
+        assert_eq!("TYPEABLE", font.name);
+        assert_eq!(
+            (9, 1, 32, 96),
+            (
+                font.height,
+                font.spacing,
+                font.first_char,
+                font.glyph_count()
+            )
+        );
+        // Fixed width, every glyph 5 wide and one pixel apart.
+        assert_eq!(5 * 3 + 2, font.text_width("CAR"));
+
+        let label = font.render("Carma 1");
+        assert_eq!((41, 9), (label.w, label.h));
+        assert!(label.data.iter().any(|&index| index != 0));
+        assert!(label.data.contains(&0));
+    }
+
+    #[test]
+    fn test_proportional_layout() {
+        let text = "7\n-1\n-1\n48\n3\n1\n2\n3\n";
+        let mut rdr = TextReader::new(PathBuf::from("DIGITS.TXT"), text);
+        let font = Font::parse(&mut rdr, glyphs(3, 7, 4)).unwrap();
+        let layout = font.layout("0 12x");
+        assert_eq!(
+            vec![
+                PlacedGlyph {
+                    x: 0,
+                    index: 0,
+                    w: 1
+                },
+                PlacedGlyph {
+                    x: 0,
+                    index: 1,
+                    w: 2
+                },
+                PlacedGlyph {
+                    x: 1,
+                    index: 2,
+                    w: 3
+                },
+            ],
+            layout
+        );
+        assert_eq!(4, font.text_width("012"));
+
+        // Later glyphs overlap earlier ones, clipped at the target edges.
+        let mut target = PixelMap {
+            w: 3,
+            h: 8,
+            row_bytes: 4,
+            data: vec![1; 32],
+            ..Default::default()
+        };
+        font.draw(&mut target, 1, 2, "12").unwrap();
+        assert_eq!([1, 11, 12, 1], target.data[2 * 4..3 * 4]);
+        assert_eq!([1, 1, 1, 1], target.data[..4]);
+        assert_eq!([1, 11, 12, 1], target.data[7 * 4..]);
+    }
+
+    #[test]
+    fn test_bad_fonts() {
+        // Descriptions with lines run together fail to parse.
+        let mut rdr = TextReader::new(PathBuf::from("NEWBLUE.TXT"), "5-11325905555\r\n");
+        assert!(matches!(
+            Font::parse(&mut rdr, glyphs(3, 7, 4)),
+            Err(Error::ParseText { .. })
+        ));
+
+        // Too few glyphs for the description.
+        let mut rdr = TextReader::new(PathBuf::from("TEST.TXT"), "7\n4\n1\n32\n4\n");
+        assert!(matches!(
+            Font::parse(&mut rdr, glyphs(3, 7, 4)),
+            Err(Error::BadPixelmap { .. })
+        ));
+    }
+}
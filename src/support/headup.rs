@@ -0,0 +1,823 @@
+//
+// Part of Roadkill Project.
+//
+// Copyright 2010, 2017, Stanislav Karchebnyy <berkus@madfire.net>
+//
+// Distributed under the Boost Software License, Version 1.0.
+// (See file LICENSE_1_0.txt or a copy at http://www.boost.org/LICENSE_1_0.txt)
+//
+use {
+    crate::support::{
+        palette::Palette,
+        resolution::ResolutionProfile,
+        text::TextReader,
+        texture::{PixelFormat, PixelMap, Transparency},
+        Error,
+    },
+    log::*,
+    std::{
+        collections::HashMap,
+        f32::consts::PI,
+        path::{Path, PathBuf},
+    },
+};
+
+/// Screen area, right and bottom edges included like in the game files.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Area {
+    pub left: i32,
+    pub top: i32,
+    pub right: i32,
+    pub bottom: i32,
+}
+
+/// Horizontal alignment of head-up text against its position.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Justification {
+    Left,
+    Centre,
+    Right,
+}
+
+/// Place of one text message of the head-up display, like the timer or lap count.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Headup {
+    pub x: i32,
+    pub y: i32,
+    pub font: u8, // number of the game's head-up font
+    pub justification: Justification,
+    pub cockpit_anchored: bool, // "c" instead of "h" after justification, only the pedestrian warning has it
+    pub dim: Option<Area>,      // darkened background
+}
+
+/**
+ * Head-up layout (HEADUP.ENC) lists text messages drawn over the scene,
+ * first for the external view, then for the cockpit view.
+ */
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct HeadupLayout {
+    pub external: Vec<Headup>,
+    pub internal: Vec<Headup>,
+}
+
+/// Image drawn at a screen position.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Sprite {
+    pub x: i32,
+    pub y: i32,
+    pub image: String,
+}
+
+/// Speedometer or rev counter.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Gauge {
+    /// Digits cut from a strip of ten, or for rev counters a bar revealed left to right.
+    Digital { sprite: Sprite, x_pitch: i32 },
+    /// Needle over an optional dial image, swept from start to end angle in degrees
+    /// counterclockwise from the right.
+    Analog {
+        dial: Option<Sprite>,
+        centre: [i32; 2],
+        radius: [i32; 2], // needle starts at the first and ends at the second
+        angles: [i32; 2],
+        needle_colour: i32,     // negative ones aren't palette indices
+        max_speed: Option<i32>, // only speedometers have it
+    },
+}
+
+/// Two hands on the wheel, missing ones are hidden behind it.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct HandsFrame {
+    pub right: Option<Sprite>,
+    pub left: Option<Sprite>,
+}
+
+/// Car systems with damage lights, in cockpit file order.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum System {
+    Engine,
+    Transmission,
+    Steering,
+    LfBrake,
+    RfBrake,
+    LrBrake,
+    RrBrake,
+    LfWheel,
+    RfWheel,
+    LrWheel,
+    RrWheel,
+}
+
+impl System {
+    pub const ALL: [System; 11] = [
+        System::Engine,
+        System::Transmission,
+        System::Steering,
+        System::LfBrake,
+        System::RfBrake,
+        System::LrBrake,
+        System::RrBrake,
+        System::LfWheel,
+        System::RfWheel,
+        System::LrWheel,
+        System::RrWheel,
+    ];
+}
+
+/// Damage light image holds an on and off frame for each of DAMAGE_LEVELS levels,
+/// flashing at the rate given for the level.
+pub const DAMAGE_LEVELS: usize = 5;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DamageLight {
+    pub sprite: Sprite,
+    pub flash: [i32; DAMAGE_LEVELS],
+}
+
+/// Which way the driver looks from the cockpit.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum HudView {
+    #[default]
+    External, // no cockpit, gauges over the scene
+    Forward,
+    Left,
+    Right,
+}
+
+/**
+ * Cockpit description (CARS/NAME.ENC of a resolution variant) places cockpit art,
+ * gauges and damage lights of a car on screen. Gauges come in pairs, for the
+ * external view first and for the cockpit second. Everything drawn in the cockpit
+ * is placed on the cockpit image, with the top left of its render area at the
+ * top left of the screen.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cockpit {
+    pub images: [String; 3],     // forward, left, right views
+    pub render_areas: [Area; 3], // where the scene shows through each of them
+    pub speedos: [Option<Gauge>; 2],
+    pub tachos: [Option<Gauge>; 2],
+    pub gears: [Sprite; 2],     // gear strip, reverse, neutral, then 1st up
+    pub hands: Vec<HandsFrame>, // from hard left to hard right
+    pub mirror: Area,
+    pub pratcam: Area,
+    pub damage_lights: Vec<DamageLight>, // one per System, in order
+    pub damage_offset: [i32; 2],         // moves lights over damage_background in external view
+    pub damage_background: Sprite,
+    pub dim_areas: [Vec<Area>; 2],
+}
+
+/// State of the car shown on head-up gauges.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct HudState {
+    pub view: HudView,
+    pub speed: f32,                  // in speedometer units
+    pub revs: f32,                   // fraction of the rev counter range
+    pub gear: i8,                    // -1 for reverse, 0 neutral
+    pub steering: f32,               // -1 hard left to 1 hard right
+    pub damage: HashMap<System, u8>, // percent
+    pub flash: bool,                 // show the second frame of flashing damage lights
+}
+
+// Comma separated fields of a line, parse errors point at it.
+struct Fields<'a> {
+    rdr: &'a TextReader,
+    line: String,
+    fields: Vec<String>,
+    pos: usize,
+}
+
+impl<'a> Fields<'a> {
+    fn next(rdr: &'a mut TextReader) -> Result<Fields<'a>, Error> {
+        let line = rdr.next_line()?;
+        let fields = line.split(',').map(|f| f.trim().to_owned()).collect();
+        Ok(Fields {
+            rdr,
+            line,
+            fields,
+            pos: 0,
+        })
+    }
+
+    fn len(&self) -> usize {
+        self.fields.len()
+    }
+
+    fn text(&mut self, expected: &str) -> Result<String, Error> {
+        let field = self
+            .fields
+            .get(self.pos)
+            .filter(|f| !f.is_empty())
+            .cloned()
+            .ok_or_else(|| self.rdr.error(expected, &self.line))?;
+        self.pos += 1;
+        Ok(field)
+    }
+
+    fn int(&mut self, expected: &str) -> Result<i32, Error> {
+        let field = self.text(expected)?;
+        field
+            .parse()
+            .map_err(|_| self.rdr.error(expected, &self.line))
+    }
+
+    // Image name, "none" for no image.
+    fn image(&mut self) -> Result<Option<String>, Error> {
+        let name = self.text("image file name")?;
+        Ok(Some(name).filter(|name| !name.eq_ignore_ascii_case("none")))
+    }
+
+    fn area(&mut self) -> Result<Area, Error> {
+        Ok(Area {
+            left: self.int("left")?,
+            top: self.int("top")?,
+            right: self.int("right")?,
+            bottom: self.int("bottom")?,
+        })
+    }
+
+    fn sprite(&mut self) -> Result<Option<Sprite>, Error> {
+        let x = self.int("x")?;
+        let y = self.int("y")?;
+        Ok(self.image()?.map(|image| Sprite { x, y, image }))
+    }
+
+    fn required_sprite(&mut self) -> Result<Sprite, Error> {
+        self.sprite()?
+            .ok_or_else(|| self.rdr.error("x, y, image file name", &self.line))
+    }
+}
+
+fn read_areas(rdr: &mut TextReader) -> Result<Vec<Area>, Error> {
+    let count = rdr.parse_line::<usize>("number of areas")?;
+    (0..count).map(|_| Fields::next(rdr)?.area()).collect()
+}
+
+impl HeadupLayout {
+    pub fn parse(rdr: &mut TextReader) -> Result<HeadupLayout, Error> {
+        let mut read_headups = || -> Result<Vec<Headup>, Error> {
+            let count = rdr.parse_line::<usize>("number of headups")?;
+            (0..count)
+                .map(|_| {
+                    let mut fields = Fields::next(rdr)?;
+                    let x = fields.int("x")?;
+                    let y = fields.int("y")?;
+                    let font = fields.int("font")?;
+                    let anchor = fields.text("justification")?.to_ascii_lowercase();
+                    let justification = match anchor.chars().next() {
+                        Some('l') => Justification::Left,
+                        Some('c') => Justification::Centre,
+                        Some('r') => Justification::Right,
+                        _ => return Err(fields.rdr.error("justification l, c or r", &anchor)),
+                    };
+                    let dim = match fields.len() {
+                        5 if fields.int("-1 for no dim area")? == -1 => None,
+                        8 => Some(fields.area()?),
+                        _ => return Err(fields.rdr.error("-1 or dim area", &fields.line)),
+                    };
+                    Ok(Headup {
+                        x,
+                        y,
+                        font: u8::try_from(font)
+                            .map_err(|_| fields.rdr.error("font number", &fields.line))?,
+                        justification,
+                        cockpit_anchored: anchor.ends_with('c'),
+                        dim,
+                    })
+                })
+                .collect()
+        };
+        let external = read_headups()?;
+        let internal = read_headups()?;
+        Ok(HeadupLayout { external, internal })
+    }
+
+    pub fn load_from(fname: String) -> Result<HeadupLayout, Error> {
+        HeadupLayout::parse(&mut TextReader::open(Path::new(&fname))?)
+    }
+}
+
+impl Cockpit {
+    fn parse_gauge(rdr: &mut TextReader, speedo: bool) -> Result<Option<Gauge>, Error> {
+        let mut fields = Fields::next(rdr)?;
+        match fields.text("gauge type d, a or none")?.as_str() {
+            "none" => Ok(None),
+            "d" => Ok(Some(Gauge::Digital {
+                sprite: fields.required_sprite()?,
+                // Digital rev counters are a single bar.
+                x_pitch: if fields.len() > 4 {
+                    fields.int("x pitch")?
+                } else {
+                    0
+                },
+            })),
+            "a" => Ok(Some(Gauge::Analog {
+                dial: fields.sprite()?,
+                centre: [fields.int("centre x")?, fields.int("centre y")?],
+                radius: [fields.int("inner radius")?, fields.int("outer radius")?],
+                angles: [fields.int("start angle")?, fields.int("end angle")?],
+                needle_colour: fields.int("needle colour")?,
+                max_speed: match speedo {
+                    true => Some(fields.int("max speed")?),
+                    false => None,
+                },
+            })),
+            other => Err(fields.rdr.error("gauge type d, a or none", other)),
+        }
+    }
+
+    pub fn parse(rdr: &mut TextReader) -> Result<Cockpit, Error> {
+        let view = |rdr: &mut TextReader| -> Result<(String, Area), Error> {
+            Ok((rdr.next_line()?, Fields::next(rdr)?.area()?))
+        };
+        let views = [view(rdr)?, view(rdr)?, view(rdr)?];
+        let speedos = [
+            Cockpit::parse_gauge(rdr, true)?,
+            Cockpit::parse_gauge(rdr, true)?,
+        ];
+        let tachos = [
+            Cockpit::parse_gauge(rdr, false)?,
+            Cockpit::parse_gauge(rdr, false)?,
+        ];
+        let gears = [
+            Fields::next(rdr)?.required_sprite()?,
+            Fields::next(rdr)?.required_sprite()?,
+        ];
+
+        let count = rdr.parse_line::<usize>("number of hands frames")?;
+        let hands = (0..count)
+            .map(|_| {
+                let mut fields = Fields::next(rdr)?;
+                Ok(HandsFrame {
+                    right: fields.sprite()?,
+                    left: fields.sprite()?,
+                })
+            })
+            .collect::<Result<_, Error>>()?;
+        let mirror = Fields::next(rdr)?.area()?;
+        let pratcam = Fields::next(rdr)?.area()?;
+
+        let damage_lights = System::ALL
+            .iter()
+            .map(|_| {
+                let mut fields = Fields::next(rdr)?;
+                let x = fields.int("x")?;
+                let y = fields.int("y")?;
+                let mut flash = [0; DAMAGE_LEVELS];
+                for rate in &mut flash {
+                    *rate = fields.int("flash rate")?;
+                }
+                let image = fields.text("image file name")?;
+                Ok(DamageLight {
+                    sprite: Sprite { x, y, image },
+                    flash,
+                })
+            })
+            .collect::<Result<_, Error>>()?;
+        let mut fields = Fields::next(rdr)?;
+        let damage_offset = [
+            fields.int("damage x offset")?,
+            fields.int("damage y offset")?,
+        ];
+        let damage_background = fields.required_sprite()?;
+        let dim_areas = [read_areas(rdr)?, read_areas(rdr)?];
+
+        Ok(Cockpit {
+            images: views.clone().map(|(image, _)| image),
+            render_areas: views.map(|(_, area)| area),
+            speedos,
+            tachos,
+            gears,
+            hands,
+            mirror,
+            pratcam,
+            damage_lights,
+            damage_offset,
+            damage_background,
+            dim_areas,
+        })
+    }
+
+    pub fn load_from(fname: String) -> Result<Cockpit, Error> {
+        Cockpit::parse(&mut TextReader::open(Path::new(&fname))?)
+    }
+
+    /// All images this cockpit draws.
+    pub fn image_names(&self) -> Vec<&String> {
+        let gauges = self.speedos.iter().chain(&self.tachos).flatten();
+        let mut names: Vec<_> = self
+            .gears
+            .iter()
+            .chain(
+                self.hands
+                    .iter()
+                    .flat_map(|f| f.right.iter().chain(&f.left)),
+            )
+            .chain(self.damage_lights.iter().map(|light| &light.sprite))
+            .chain([&self.damage_background])
+            .chain(gauges.filter_map(|gauge| match gauge {
+                Gauge::Digital { sprite, .. } => Some(sprite),
+                Gauge::Analog { dial, .. } => dial.as_ref(),
+            }))
+            .map(|sprite| &sprite.image)
+            .chain(&self.images)
+            .collect();
+        names.sort();
+        names.dedup();
+        names
+    }
+}
+
+// RGBA image the head-up display is composed in.
+struct Canvas {
+    w: i32,
+    h: i32,
+    origin: [i32; 2], // screen position of 0,0, moved to draw in cockpit image coordinates
+    data: Vec<u8>,
+}
+
+impl Canvas {
+    fn put(&mut self, x: i32, y: i32, rgba: [u8; 4]) {
+        let (x, y) = (x + self.origin[0], y + self.origin[1]);
+        if x >= 0 && y >= 0 && x < self.w && y < self.h {
+            let at = ((y * self.w + x) * 4) as usize;
+            self.data[at..at + 4].copy_from_slice(&rgba);
+        }
+    }
+
+    // Half transparent black over the scene, anything drawn later stays opaque.
+    fn dim(&mut self, area: &Area) {
+        for y in area.top..=area.bottom {
+            for x in area.left..=area.right {
+                self.put(x, y, [0, 0, 0, 128]);
+            }
+        }
+    }
+
+    // Part of an 8-bit pixelmap, colour key holes left out.
+    fn blit(&mut self, pm: &PixelMap, palette: &Palette, src: Area, x: i32, y: i32) {
+        let stride = usize::from(pm.row_bytes).max(usize::from(pm.w));
+        for sy in src.top.max(0)..=src.bottom.min(i32::from(pm.h) - 1) {
+            for sx in src.left.max(0)..=src.right.min(i32::from(pm.w) - 1) {
+                let index = pm.data.get(sy as usize * stride + sx as usize);
+                let rgba = Transparency::default().lookup(palette, index.copied().unwrap_or(0));
+                if rgba[3] != 0 {
+                    self.put(x + sx - src.left, y + sy - src.top, rgba);
+                }
+            }
+        }
+    }
+
+    fn line(&mut self, from: [f32; 2], to: [f32; 2], rgba: [u8; 4]) {
+        let steps = (to[0] - from[0]).abs().max((to[1] - from[1]).abs()).ceil() as i32;
+        for step in 0..=steps {
+            let t = step as f32 / steps.max(1) as f32;
+            let x = from[0] + (to[0] - from[0]) * t;
+            let y = from[1] + (to[1] - from[1]) * t;
+            self.put(x.round() as i32, y.round() as i32, rgba);
+        }
+    }
+}
+
+/**
+ * Head-up display of a car: cockpit art, gauges and damage lights laid out by
+ * the car's cockpit description and text places from HEADUP.ENC, drawn for a HudState.
+ */
+pub struct Hud {
+    pub layout: HeadupLayout,
+    pub cockpit: Cockpit,
+    pub w: u16,
+    pub h: u16,
+    palette: Palette,
+    pixelmaps: HashMap<String, PixelMap>, // by image file name
+}
+
+impl Hud {
+    /// Load cockpit of the car described by fname, from the CARS of the profile's
+    /// resolution variant, and head-up art from its PIXELMAP directory.
+    pub fn load_from(fname: String, profile: ResolutionProfile) -> Result<Hud, Error> {
+        let path = PathBuf::from(&fname);
+        let root = ResolutionProfile::data_root(&path);
+        let name = path.file_name().unwrap_or_default();
+        let cockpit_file = profile.find(&root, &Path::new("CARS").join(name));
+        info!("### Opening cockpit {:?}", cockpit_file);
+        let cockpit = Cockpit::load_from(cockpit_file.to_string_lossy().into_owned())?;
+        let layout_file = profile.find(&root, Path::new("HEADUP.ENC"));
+        let layout = HeadupLayout::load_from(layout_file.to_string_lossy().into_owned())?;
+        let palette_file = root.join("REG/PALETTES/DRRENDER.PAL");
+        let palette = Palette::load_from(palette_file.to_string_lossy().into_owned())?;
+
+        // Cockpits differ in details, missing art is left out of the display.
+        let mut pixelmaps = HashMap::new();
+        for image in cockpit.image_names() {
+            let file = profile.find(&root, &Path::new("PIXELMAP").join(image));
+            match PixelMap::load_from(file.to_string_lossy().into_owned()) {
+                Ok(pms)
+                    if pms
+                        .first()
+                        .is_some_and(|pm| pm.format == PixelFormat::Index8) =>
+                {
+                    pixelmaps.insert(image.to_ascii_uppercase(), pms.into_iter().next().unwrap());
+                }
+                Ok(_) => warn!("Skipping head-up image {}: not 8-bit", image),
+                Err(e) => warn!("Skipping head-up image {}: {}", image, e),
+            }
+        }
+        let (w, h) = profile.screen_size();
+        Ok(Hud {
+            layout,
+            cockpit,
+            w,
+            h,
+            palette,
+            pixelmaps,
+        })
+    }
+
+    fn pixelmap(&self, image: &str) -> Option<&PixelMap> {
+        self.pixelmaps.get(&image.to_ascii_uppercase())
+    }
+
+    // Frame out of count stacked top to bottom.
+    fn draw_frame(&self, canvas: &mut Canvas, sprite: &Sprite, frame: usize, count: usize) {
+        if let Some(pm) = self.pixelmap(&sprite.image) {
+            let h = i32::from(pm.h) / count as i32;
+            let top = h * frame.min(count - 1) as i32;
+            let src = Area {
+                left: 0,
+                top,
+                right: i32::from(pm.w) - 1,
+                bottom: top + h - 1,
+            };
+            canvas.blit(pm, &self.palette, src, sprite.x, sprite.y);
+        }
+    }
+
+    fn draw_gauge(&self, canvas: &mut Canvas, gauge: &Gauge, value: f32, fraction: f32) {
+        match gauge {
+            Gauge::Digital { sprite, x_pitch: 0 } => {
+                // Bar revealed up to the fraction.
+                if let Some(pm) = self.pixelmap(&sprite.image) {
+                    let src = Area {
+                        left: 0,
+                        top: 0,
+                        right: (f32::from(pm.w) * fraction.clamp(0.0, 1.0)) as i32 - 1,
+                        bottom: i32::from(pm.h) - 1,
+                    };
+                    canvas.blit(pm, &self.palette, src, sprite.x, sprite.y);
+                }
+            }
+            Gauge::Digital { sprite, x_pitch } => {
+                let number = value.clamp(0.0, 999.0) as u32;
+                let digits = [number / 100, number / 10 % 10, number % 10];
+                for (n, &digit) in digits.iter().enumerate() {
+                    // No leading zeroes.
+                    if n < 2 && number < 10u32.pow(2 - n as u32) {
+                        continue;
+                    }
+                    let place = Sprite {
+                        x: sprite.x + x_pitch * n as i32,
+                        ..sprite.clone()
+                    };
+                    self.draw_frame(canvas, &place, digit as usize, 10);
+                }
+            }
+            Gauge::Analog {
+                dial,
+                centre,
+                radius,
+                angles,
+                needle_colour,
+                max_speed,
+            } => {
+                if let Some(dial) = dial {
+                    self.draw_frame(canvas, dial, 0, 1);
+                }
+                let fraction = match max_speed {
+                    Some(max) if *max > 0 => value / *max as f32,
+                    _ => fraction,
+                };
+                let [start, end] = angles.map(|a| a as f32);
+                let angle = (start + (end - start) * fraction.clamp(0.0, 1.0)) * PI / 180.0;
+                let point = |r: i32| {
+                    [
+                        centre[0] as f32 + r as f32 * angle.cos(),
+                        centre[1] as f32 - r as f32 * angle.sin(),
+                    ]
+                };
+                // Negative colours aren't palette indices, such needles are drawn white.
+                let rgba = match u8::try_from(*needle_colour) {
+                    Ok(index) => Transparency::OPAQUE.lookup(&self.palette, index),
+                    Err(_) => [255, 255, 255, 255],
+                };
+                canvas.line(point(radius[0]), point(radius[1]), rgba);
+            }
+        }
+    }
+
+    /// Draw the display for the state into an RGBA pixelmap of screen size,
+    /// transparent where the scene shows through.
+    pub fn compose(&self, state: &HudState) -> PixelMap {
+        let (w, h) = (i32::from(self.w), i32::from(self.h));
+        let mut canvas = Canvas {
+            w,
+            h,
+            origin: [0, 0],
+            data: vec![0; (w * h * 4) as usize],
+        };
+        let cockpit = &self.cockpit;
+        let (side, headups) = match state.view {
+            HudView::External => (0, &self.layout.external),
+            _ => (1, &self.layout.internal),
+        };
+
+        for area in headups.iter().filter_map(|h| h.dim.as_ref()) {
+            canvas.dim(area);
+        }
+        let view = match state.view {
+            HudView::External => None,
+            HudView::Forward => Some(0),
+            HudView::Left => Some(1),
+            HudView::Right => Some(2),
+        };
+        // Cockpits are bigger than the screen to shake around, the scene is rendered
+        // in their top left part. Everything else in the cockpit is placed on the image.
+        let forward = &cockpit.render_areas[0];
+        if let Some(view) = view {
+            let area = &cockpit.render_areas[view];
+            canvas.origin = [-area.left, -area.top];
+            if let Some(pm) = self.pixelmap(&cockpit.images[view]) {
+                let src = Area {
+                    left: 0,
+                    top: 0,
+                    right: i32::from(pm.w) - 1,
+                    bottom: i32::from(pm.h) - 1,
+                };
+                canvas.blit(pm, &self.palette, src, 0, 0);
+            }
+        }
+        for area in &cockpit.dim_areas[side] {
+            canvas.dim(area);
+        }
+        // Side views don't show the dashboard.
+        if matches!(state.view, HudView::External | HudView::Forward) {
+            if let Some(speedo) = &cockpit.speedos[side] {
+                self.draw_gauge(&mut canvas, speedo, state.speed, 0.0);
+            }
+            if let Some(tacho) = &cockpit.tachos[side] {
+                self.draw_gauge(&mut canvas, tacho, 0.0, state.revs);
+            }
+            let gear = (i32::from(state.gear) + 1).clamp(0, 7) as usize;
+            self.draw_frame(&mut canvas, &cockpit.gears[side], gear, 8);
+        }
+
+        match state.view {
+            HudView::External => {
+                self.draw_frame(&mut canvas, &cockpit.damage_background, 0, 1);
+                // Lights are placed for the forward cockpit, moved by offset over the background.
+                canvas.origin = [
+                    cockpit.damage_offset[0] - forward.left,
+                    cockpit.damage_offset[1] - forward.top,
+                ];
+            }
+            HudView::Forward => (),
+            _ => return self.to_pixelmap(canvas),
+        }
+        for (system, light) in System::ALL.iter().zip(&cockpit.damage_lights) {
+            let damage = usize::from(state.damage.get(system).copied().unwrap_or(0).min(100));
+            let level = (damage * DAMAGE_LEVELS / 100).min(DAMAGE_LEVELS - 1);
+            let frame = level * 2 + usize::from(state.flash && light.flash[level] > 0);
+            self.draw_frame(&mut canvas, &light.sprite, frame, DAMAGE_LEVELS * 2);
+        }
+
+        if state.view == HudView::Forward && !cockpit.hands.is_empty() {
+            let last = cockpit.hands.len() - 1;
+            let frame = ((state.steering.clamp(-1.0, 1.0) + 1.0) / 2.0 * last as f32).round();
+            let hands = &cockpit.hands[frame as usize];
+            for sprite in hands.right.iter().chain(&hands.left) {
+                self.draw_frame(&mut canvas, sprite, 0, 1);
+            }
+        }
+        self.to_pixelmap(canvas)
+    }
+
+    fn to_pixelmap(&self, canvas: Canvas) -> PixelMap {
+        PixelMap {
+            name: String::from("HEADUP.PIX"),
+            w: self.w,
+            h: self.h,
+            origin_x: self.w / 2,
+            origin_y: self.h / 2,
+            format: PixelFormat::Rgba8888,
+            units: u32::from(self.w) * u32::from(self.h),
+            unit_bytes: 4,
+            data: canvas.data,
+            ..Default::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, std::fs};
+
+    const CAR: &str = "DecodedData/DATA/CARS/BLKEAGLE.ENC";
+
+    #[test]
+    fn test_load_headup_layout() {
+        let layout =
+            HeadupLayout::load_from(String::from("DecodedData/DATA/64X48X8/HEADUP.ENC")).unwrap();
+        assert_eq!((15, 15), (layout.external.len(), layout.internal.len()));
+        let timer = &layout.external[7];
+        assert_eq!((320, 5, 1), (timer.x, timer.y, timer.font));
+        assert_eq!(Justification::Centre, timer.justification);
+        assert_eq!(
+            Some(Area {
+                left: 266,
+                top: 0,
+                right: 383,
+                bottom: 49
+            }),
+            timer.dim
+        );
+        assert!(layout.external[3].cockpit_anchored);
+        assert!(!layout.external[3..]
+            .iter()
+            .skip(1)
+            .any(|h| h.cockpit_anchored));
+    }
+
+    #[test]
+    fn test_load_all_cockpits() {
+        for entry in fs::read_dir("DecodedData/DATA/64X48X8/CARS").unwrap() {
+            let path = entry.unwrap().path();
+            let cockpit = Cockpit::load_from(path.to_string_lossy().into_owned()).unwrap();
+            assert_eq!(System::ALL.len(), cockpit.damage_lights.len());
+            assert!(cockpit.tachos[0].is_some());
+        }
+
+        let cockpit =
+            Cockpit::load_from(String::from("DecodedData/DATA/64X48X8/CARS/IVAN.ENC")).unwrap();
+        assert_eq!("CKPT89F.PIX", cockpit.images[0]);
+        assert_eq!(
+            Some(Gauge::Analog {
+                dial: Some(Sprite {
+                    x: 190,
+                    y: 484,
+                    image: String::from("HSPDMSK9.PIX")
+                }),
+                centre: [258, 504],
+                radius: [12, 54],
+                angles: [160, 20],
+                needle_colour: -4,
+                max_speed: Some(85),
+            }),
+            cockpit.speedos[1]
+        );
+        // Hands in one frame are hidden behind the wheel.
+        assert_eq!(7, cockpit.hands.len());
+        assert!(cockpit.hands[0].left.is_none() && cockpit.hands[6].right.is_none());
+        assert_eq!([34, -14], cockpit.damage_offset);
+    }
+
+    #[test]
+    fn test_bad_cockpit() {
+        let text = "CKPT80F.PIX\n32,40,672,427\nCKPT80L.PIX\n32,45,672\n";
+        let mut rdr = TextReader::new(PathBuf::from("BROKEN.ENC"), text);
+        match Cockpit::parse(&mut rdr) {
+            Err(Error::ParseText { line, .. }) => assert_eq!(4, line),
+            _ => panic!("short area must not parse"),
+        }
+    }
+
+    #[test]
+    fn test_compose_hud() {
+        let hud = Hud::load_from(String::from(CAR), ResolutionProfile::Hires).unwrap();
+        assert_eq!((640, 480), (hud.w, hud.h));
+        let opaque = |pm: &PixelMap, x: usize, y: usize| pm.data[(y * 640 + x) * 4 + 3] == 255;
+
+        let external = hud.compose(&HudState::default());
+        assert_eq!(PixelFormat::Rgba8888, external.format);
+        // Scene shows through the middle, the digital speedo shows a single 0 in the last place.
+        assert!(!opaque(&external, 320, 240));
+        assert!((88 + 28..88 + 28 + 16).any(|x| opaque(&external, x, 442 + 14)));
+        assert!(!(88..88 + 28).any(|x| opaque(&external, x, 442 + 14)));
+
+        let mut state = HudState {
+            view: HudView::Forward,
+            speed: 123.0,
+            ..Default::default()
+        };
+        let forward = hud.compose(&state);
+        assert!(opaque(&forward, 320, 470)); // dashboard
+        assert!(!opaque(&forward, 320, 200)); // windscreen
+
+        // Damage lights are off at first, they are placed on the cockpit image.
+        let light = &hud.cockpit.damage_lights[0].sprite;
+        let (x, y) = (light.x as usize + 8 - 32, light.y as usize + 11 - 40);
+        let dashboard = forward.data[(y * 640 + x) * 4..][..4].to_vec();
+        state.damage.insert(System::Engine, 50);
+        let damaged = hud.compose(&state);
+        assert_ne!(dashboard, damaged.data[(y * 640 + x) * 4..][..4]);
+    }
+}
@@ -7,22 +7,172 @@
 // (See file LICENSE_1_0.txt or a copy at http://www.boost.org/LICENSE_1_0.txt)
 //
 use {
-    crate::support::{self, resource::Chunk, Error},
-    byteorder::ReadBytesExt,
+    crate::support::{
+        self,
+        resolution::ResolutionProfile,
+        resource::{load_records, Chunk, ChunkReader, Strictness, StringSize, StringSizes},
+        Error,
+    },
+    byteorder::{BigEndian, ReadBytesExt, WriteBytesExt},
+    log::*,
     std::{
-        fs::File,
-        io::{BufRead, BufReader},
+        collections::HashMap,
+        fs::{self, File},
+        io::{self, BufReader, BufWriter, Read, Write},
+        path::{Path, PathBuf},
     },
 };
 
+/**
+ * Surface properties of a material from its MaterialDesc chunk, laid out like
+ * BRender materials: lighting coefficients, rendering flags and the transform
+ * applied to mesh UVs before looking up the pixelmap.
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct MaterialProperties {
+    pub colour: [u8; 3], // RGB, used when there is no pixelmap
+    pub opacity: u8,     // 255 is opaque
+    pub ambient: f32,
+    pub diffuse: f32,
+    pub specular: f32,
+    pub power: f32, // specular exponent
+    pub flags: u16,
+    pub map_transform: [[f32; 2]; 3], // rows of 2x3 matrix, u and v axes then offset
+    pub index_base: u8,               // first colour of the ramp for untextured lit faces
+    pub index_range: u8,
+}
+
+impl Default for MaterialProperties {
+    fn default() -> Self {
+        MaterialProperties {
+            colour: [255, 255, 255],
+            opacity: 255,
+            ambient: 0.0,
+            diffuse: 0.75,
+            specular: 0.0,
+            power: 20.0,
+            flags: MaterialProperties::LIGHT,
+            map_transform: [[1.0, 0.0], [0.0, 1.0], [0.0, 0.0]],
+            index_base: 0,
+            index_range: 0,
+        }
+    }
+}
+
+impl MaterialProperties {
+    pub const LIGHT: u16 = 0x1;
+    pub const PRELIT: u16 = 0x2;
+    pub const SMOOTH: u16 = 0x4;
+    pub const ENVIRONMENT_I: u16 = 0x8; // environment map from infinite viewer
+    pub const ENVIRONMENT_L: u16 = 0x10; // environment map from local viewer
+    pub const PERSPECTIVE: u16 = 0x20;
+    pub const DECAL: u16 = 0x40;
+    pub const ALWAYS_VISIBLE: u16 = 0x800;
+    pub const TWO_SIDED: u16 = 0x1000;
+    pub const FORCE_Z_0: u16 = 0x2000;
+    pub const DITHER: u16 = 0x4000;
+
+    pub fn load<R: ReadBytesExt>(rdr: &mut R) -> Result<MaterialProperties, Error> {
+        let mut colour = [0u8; 3];
+        rdr.read_exact(&mut colour)?;
+        let opacity = rdr.read_u8()?;
+        let ambient = rdr.read_f32::<BigEndian>()?;
+        let diffuse = rdr.read_f32::<BigEndian>()?;
+        let specular = rdr.read_f32::<BigEndian>()?;
+        let power = rdr.read_f32::<BigEndian>()?;
+        let flags = rdr.read_u16::<BigEndian>()?;
+        let mut map_transform = [[0f32; 2]; 3];
+        for row in map_transform.iter_mut() {
+            for x in row.iter_mut() {
+                *x = rdr.read_f32::<BigEndian>()?;
+            }
+        }
+        let index_base = rdr.read_u8()?;
+        let index_range = rdr.read_u8()?;
+        Ok(MaterialProperties {
+            colour,
+            opacity,
+            ambient,
+            diffuse,
+            specular,
+            power,
+            flags,
+            map_transform,
+            index_base,
+            index_range,
+        })
+    }
+
+    pub fn write<W: WriteBytesExt>(&self, wr: &mut W) -> Result<(), Error> {
+        wr.write_all(&self.colour)?;
+        wr.write_u8(self.opacity)?;
+        wr.write_f32::<BigEndian>(self.ambient)?;
+        wr.write_f32::<BigEndian>(self.diffuse)?;
+        wr.write_f32::<BigEndian>(self.specular)?;
+        wr.write_f32::<BigEndian>(self.power)?;
+        wr.write_u16::<BigEndian>(self.flags)?;
+        for x in self.map_transform.iter().flatten() {
+            wr.write_f32::<BigEndian>(*x)?;
+        }
+        wr.write_u8(self.index_base)?;
+        wr.write_u8(self.index_range)?;
+        Ok(())
+    }
+
+    fn has(&self, flag: u16) -> bool {
+        self.flags & flag != 0
+    }
+
+    /// Lit by scene lights, otherwise drawn with the pixelmap colours as they are.
+    pub fn lit(&self) -> bool {
+        self.has(MaterialProperties::LIGHT)
+    }
+
+    /// Gouraud shaded, otherwise each face is lit flat.
+    pub fn smooth(&self) -> bool {
+        self.has(MaterialProperties::SMOOTH)
+    }
+
+    /// Pixelmap is a reflection of the surroundings, UVs come from the view direction.
+    pub fn environment_mapped(&self) -> bool {
+        self.has(MaterialProperties::ENVIRONMENT_I | MaterialProperties::ENVIRONMENT_L)
+    }
+
+    /// Back faces are drawn too, not culled.
+    pub fn two_sided(&self) -> bool {
+        self.has(MaterialProperties::TWO_SIDED)
+    }
+
+    pub fn transparent(&self) -> bool {
+        self.opacity < 255
+    }
+
+    /// Map transform as a column-major 3x3 matrix for homogeneous UVs.
+    pub fn uv_transform(&self) -> [[f32; 3]; 3] {
+        let [u, v, offset] = self.map_transform;
+        [
+            [u[0], u[1], 0.0],
+            [v[0], v[1], 0.0],
+            [offset[0], offset[1], 1.0],
+        ]
+    }
+
+    /// UVs are used as they are.
+    pub fn identity_uv(&self) -> bool {
+        self.map_transform == MaterialProperties::default().map_transform
+    }
+}
+
 // MAT file is an index of: material internal name, PIX file name and TAB file name.
-// @todo: keep material properties and internal name, replace pix and tab with megatexture reference
-#[derive(Default, Debug)]
+// @todo: replace pix and tab with megatexture reference
+#[derive(Clone, Default, Debug)]
 pub struct Material {
-    params: [f32; 12],
+    pub properties: MaterialProperties,
     pub name: String,
     pub pixelmap_name: String,
-    rendertab_name: String, // Palette file used to convert u8 indexed color to RGBA
+    pub rendertab_name: String, // Shade table used to light u8 indexed colours
+    pub extra_chunks: Vec<Chunk>, // unexpected chunks kept with Strictness::Keep
+    pub(crate) string_sizes: StringSizes,
 }
 
 impl std::fmt::Display for Material {
@@ -36,27 +186,61 @@ impl std::fmt::Display for Material {
 }
 
 impl Material {
-    pub fn load<R: ReadBytesExt + BufRead>(rdr: &mut R) -> Result<Material, Error> {
+    /// Same look, ignoring extra chunks.
+    pub fn same_definition(&self, other: &Material) -> bool {
+        self.properties == other.properties
+            && self
+                .pixelmap_name
+                .eq_ignore_ascii_case(&other.pixelmap_name)
+            && self
+                .rendertab_name
+                .eq_ignore_ascii_case(&other.rendertab_name)
+    }
+
+    pub fn load<R: Read>(rdr: &mut R) -> Result<Material, Error> {
+        Material::load_with(rdr, Strictness::default())
+    }
+
+    pub fn load_with<R: Read>(rdr: &mut R, strictness: Strictness) -> Result<Material, Error> {
+        Material::from_chunks(&mut ChunkReader::new(rdr), strictness)
+    }
+
+    /// Load single material from the chunk stream, up to its Null terminator.
+    pub fn from_chunks<R: Read>(
+        chunks: &mut ChunkReader<R>,
+        strictness: Strictness,
+    ) -> Result<Material, Error> {
         let mut mat = Material::default();
 
         // Read chunks until last chunk is encountered.
         // Certain chunks initialize certain properties.
-        loop {
-            let c = Chunk::load(rdr)?;
+        for chunk in chunks.record() {
+            let (offset, c) = chunk?;
+            mat.string_sizes.keep(&c);
             match c {
-                Chunk::MaterialDesc { name, params } => {
-                    mat.params = params;
+                Chunk::MaterialDesc {
+                    name, properties, ..
+                } => {
+                    mat.properties = properties;
                     mat.name = name;
                 }
-                Chunk::PixelmapRef(name) => mat.pixelmap_name = name,
-                Chunk::RenderTabRef(name) => mat.rendertab_name = name,
-                Chunk::Null() => break,
-                Chunk::FileHeader { file_type } => {
+                Chunk::PixelmapRef(name, _) => mat.pixelmap_name = name,
+                Chunk::RenderTabRef(name, _) => mat.rendertab_name = name,
+                Chunk::FileHeader { file_type, .. } => {
                     if file_type != support::MATERIAL_FILE_TYPE {
-                        panic!("Invalid material file type {}", file_type);
+                        return Err(Error::WrongFileType {
+                            file: PathBuf::new(),
+                            offset,
+                            expected: support::MATERIAL_FILE_TYPE,
+                            found: file_type,
+                        });
+                    }
+                }
+                c => {
+                    if let Some(c) = strictness.check(offset, c)? {
+                        mat.extra_chunks.push(c);
                     }
                 }
-                _ => unimplemented!(), // unexpected type here
             }
         }
 
@@ -67,16 +251,379 @@ impl Material {
      * Load multiple materials from a file.
      */
     pub fn load_from(fname: String) -> Result<Vec<Material>, Error> {
-        let file = File::open(fname)?;
-        let mut file = BufReader::new(file);
-        let mut materials = Vec::<Material>::new();
-        loop {
-            let mat = Material::load(&mut file);
-            match mat {
-                Err(_) => break, // fixme: allow only Eof here
-                Ok(mat) => materials.push(mat),
+        Material::load_from_with(fname, Strictness::default())
+    }
+
+    pub fn load_from_with(fname: String, strictness: Strictness) -> Result<Vec<Material>, Error> {
+        let path = PathBuf::from(fname);
+        let file = File::open(&path).map_err(|e| Error::from(e).in_file(&path))?;
+        let file = BufReader::new(file);
+        load_records(file, support::MATERIAL_FILE_TYPE, |chunks| {
+            Material::from_chunks(chunks, strictness)
+        })
+        .map_err(|e| e.in_file(&path))
+    }
+
+    pub fn save<W: WriteBytesExt>(&self, wr: &mut W) -> Result<(), Error> {
+        let sizes = &self.string_sizes;
+        sizes
+            .sized(Chunk::MaterialDesc {
+                name: self.name.clone(),
+                properties: self.properties,
+                size: StringSize::Game,
+            })
+            .write(wr)?;
+        if !self.pixelmap_name.is_empty() {
+            sizes
+                .sized(Chunk::PixelmapRef(
+                    self.pixelmap_name.clone(),
+                    StringSize::Game,
+                ))
+                .write(wr)?;
+        }
+        if !self.rendertab_name.is_empty() {
+            sizes
+                .sized(Chunk::RenderTabRef(
+                    self.rendertab_name.clone(),
+                    StringSize::Game,
+                ))
+                .write(wr)?;
+        }
+        for c in &self.extra_chunks {
+            c.write(wr)?;
+        }
+        Chunk::Null().write(wr)?;
+        Ok(())
+    }
+
+    /**
+     * Save multiple materials to a file.
+     */
+    pub fn save_to(fname: String, materials: &[Material]) -> Result<(), Error> {
+        let file = File::create(fname)?;
+        let mut file = BufWriter::new(file);
+        Chunk::FileHeader {
+            file_type: support::MATERIAL_FILE_TYPE,
+            version: support::FILE_HEADER_VERSION,
+        }
+        .write(&mut file)?;
+        for mat in materials {
+            mat.save(&mut file)?;
+        }
+        file.flush()?;
+        Ok(())
+    }
+}
+
+/// Materials of a single MAT file.
+#[derive(Debug)]
+pub struct MaterialFile {
+    pub path: PathBuf,
+    pub materials: Vec<Material>,
+}
+
+/// Something odd found while indexing material files, none of these stop loading.
+#[derive(Debug)]
+pub enum LibraryIssue {
+    /// Material defined the same way in several files.
+    Duplicate { name: String, files: Vec<PathBuf> },
+    /// Material defined differently in several files, the first one in search order is used.
+    Conflict { name: String, files: Vec<PathBuf> },
+    /// File that couldn't be loaded, materials in it can't be found.
+    Unreadable { file: PathBuf, error: Error },
+}
+
+impl std::fmt::Display for LibraryIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let list = |files: &[PathBuf]| {
+            files
+                .iter()
+                .map(|file| file.display().to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        match self {
+            LibraryIssue::Duplicate { name, files } => {
+                write!(f, "{} defined the same in {}", name, list(files))
+            }
+            LibraryIssue::Conflict { name, files } => {
+                write!(f, "{} defined differently in {}", name, list(files))
+            }
+            LibraryIssue::Unreadable { file, error } => {
+                write!(f, "{} can't be loaded: {}", file.display(), error)
+            }
+        }
+    }
+}
+
+/**
+ * Index of all material files in the DATA tree, by file name and by internal name
+ * of every material defined in them. Directories are searched in order and the first
+ * file or definition found wins, like the game loads car materials from MATERIAL
+ * before falling back to the shared ones in REG/MATERIAL.
+ * Names are matched ignoring case.
+ */
+#[derive(Debug, Default)]
+pub struct MaterialLibrary {
+    pub dirs: Vec<PathBuf>, // search order
+    files: Vec<MaterialFile>,
+    by_file: HashMap<String, usize>, // file name -> first file
+    by_name: HashMap<String, (usize, usize)>, // internal name -> first file and material in it
+    pub issues: Vec<LibraryIssue>,
+}
+
+impl MaterialLibrary {
+    /// Directories with materials for the profile under the DATA directory root, in search order.
+    pub fn search_dirs(root: &Path, profile: ResolutionProfile) -> Vec<PathBuf> {
+        profile
+            .variant_dir()
+            .map(|variant| root.join(variant).join("MATERIAL"))
+            .into_iter()
+            .chain([root.join("MATERIAL"), root.join("REG/MATERIAL")])
+            .collect()
+    }
+
+    /// Index material files of the DATA directory root for the profile.
+    pub fn load_from(root: &Path, profile: ResolutionProfile) -> Result<MaterialLibrary, Error> {
+        MaterialLibrary::load_from_dirs(MaterialLibrary::search_dirs(root, profile))
+    }
+
+    /// Index all MAT files in dirs, missing directories are skipped.
+    /// Files in a directory are indexed in name order.
+    pub fn load_from_dirs(dirs: Vec<PathBuf>) -> Result<MaterialLibrary, Error> {
+        let mut library = MaterialLibrary::default();
+        for dir in &dirs {
+            if !dir.is_dir() {
+                continue;
             }
+            let mut paths = Vec::new();
+            for entry in fs::read_dir(dir).map_err(|e| Error::from(e).in_file(dir))? {
+                let path = entry.map_err(|e| Error::from(e).in_file(dir))?.path();
+                let is_mat = path
+                    .extension()
+                    .is_some_and(|ext| ext.eq_ignore_ascii_case("MAT"));
+                if is_mat && path.is_file() {
+                    paths.push(path);
+                }
+            }
+            paths.sort();
+            for path in paths {
+                match Material::load_from(path.to_string_lossy().into_owned()) {
+                    Ok(materials) => library.add(MaterialFile { path, materials }),
+                    Err(error) => {
+                        warn!("Material library skips {:?}: {}", path, error);
+                        library
+                            .issues
+                            .push(LibraryIssue::Unreadable { file: path, error });
+                    }
+                }
+            }
+        }
+        library.dirs = dirs;
+        library.check_definitions();
+        Ok(library)
+    }
+
+    fn add(&mut self, file: MaterialFile) {
+        let index = self.files.len();
+        if let Some(name) = file.path.file_name() {
+            self.by_file
+                .entry(name.to_string_lossy().to_ascii_uppercase())
+                .or_insert(index);
+        }
+        for (n, mat) in file.materials.iter().enumerate() {
+            self.by_name
+                .entry(mat.name.to_ascii_uppercase())
+                .or_insert((index, n));
+        }
+        self.files.push(file);
+    }
+
+    // Report materials defined in more than one file.
+    fn check_definitions(&mut self) {
+        let mut definitions = HashMap::<String, Vec<(&Path, &Material)>>::new();
+        for file in &self.files {
+            for mat in &file.materials {
+                definitions
+                    .entry(mat.name.to_ascii_uppercase())
+                    .or_default()
+                    .push((&file.path, mat));
+            }
+        }
+        let mut issues: Vec<_> = definitions
+            .into_iter()
+            .filter(|(_, defs)| defs.len() > 1)
+            .map(|(name, defs)| {
+                let files = defs.iter().map(|(path, _)| path.to_path_buf()).collect();
+                if defs.iter().all(|(_, mat)| mat.same_definition(defs[0].1)) {
+                    LibraryIssue::Duplicate { name, files }
+                } else {
+                    LibraryIssue::Conflict { name, files }
+                }
+            })
+            .collect();
+        issues.sort_by_key(|issue| issue.to_string());
+        for issue in &issues {
+            debug!("Material library: {}", issue);
         }
-        Ok(materials)
+        self.issues.append(&mut issues);
+        info!(
+            "Material library: {} files, {} duplicate and {} conflicting definitions",
+            self.files.len(),
+            self.duplicates().count(),
+            self.conflicts().count()
+        );
+    }
+
+    pub fn files(&self) -> &[MaterialFile] {
+        &self.files
+    }
+
+    /// First file with this file name in search order.
+    pub fn file(&self, file_name: &str) -> Option<&MaterialFile> {
+        self.by_file
+            .get(&file_name.to_ascii_uppercase())
+            .map(|&index| &self.files[index])
+    }
+
+    /// First definition of the material with this internal name in search order.
+    pub fn material(&self, name: &str) -> Option<&Material> {
+        self.by_name
+            .get(&name.to_ascii_uppercase())
+            .map(|&(file, n)| &self.files[file].materials[n])
+    }
+
+    /// Materials a reference from a car or track description stands for: everything
+    /// in the file of that name, or the one material with that internal name when
+    /// no file is called so. NotFound error names the file it was expected in.
+    pub fn resolve(&self, reference: &str) -> Result<&[Material], Error> {
+        if let Some(file) = self.file(reference) {
+            return Ok(&file.materials);
+        }
+        if let Some(mat) = self.material(reference) {
+            return Ok(std::slice::from_ref(mat));
+        }
+        let expected = self
+            .dirs
+            .iter()
+            .find(|dir| dir.is_dir())
+            .or(self.dirs.first())
+            .map_or_else(|| PathBuf::from(reference), |dir| dir.join(reference));
+        Err(Error::from(io::Error::from(io::ErrorKind::NotFound)).in_file(&expected))
+    }
+
+    pub fn duplicates(&self) -> impl Iterator<Item = &LibraryIssue> {
+        self.issues
+            .iter()
+            .filter(|issue| matches!(issue, LibraryIssue::Duplicate { .. }))
+    }
+
+    pub fn conflicts(&self) -> impl Iterator<Item = &LibraryIssue> {
+        self.issues
+            .iter()
+            .filter(|issue| matches!(issue, LibraryIssue::Conflict { .. }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load_one(fname: &str) -> Material {
+        let mut materials = Material::load_from(String::from(fname)).unwrap();
+        assert_eq!(1, materials.len());
+        materials.remove(0)
+    }
+
+    #[test]
+    fn test_load_material_properties() {
+        let mat = load_one("DecodedData/DATA/MATERIAL/ACIDDROP.MAT");
+        assert_eq!("ACIDDROP.MAT", mat.name);
+        let props = mat.properties;
+        assert_eq!(([255, 255, 255], 255), (props.colour, props.opacity));
+        assert_eq!(
+            (0.2, 0.5, 0.05, 20.0),
+            (props.ambient, props.diffuse, props.specular, props.power)
+        );
+        assert_eq!(0x25, props.flags);
+        assert!(props.lit() && props.smooth());
+        assert!(!props.two_sided() && !props.environment_mapped() && !props.transparent());
+        assert!(props.identity_uv());
+        assert_eq!((227, 10), (props.index_base, props.index_range));
+
+        let post = load_one("DecodedData/DATA/MATERIAL/TRAFPOST.MAT").properties;
+        assert!(post.two_sided());
+
+        // Environment maps repeat several times around.
+        let screen = load_one("DecodedData/DATA/MATERIAL/ACIDSCRN.MAT").properties;
+        assert!(screen.environment_mapped());
+        assert!(!screen.identity_uv());
+        assert_eq!(
+            [[5.0, 0.0, 0.0], [0.0, 5.0, 0.0], [0.0, 0.0, 1.0]],
+            screen.uv_transform()
+        );
+    }
+
+    #[test]
+    fn test_material_library() {
+        let library =
+            MaterialLibrary::load_from(Path::new("DecodedData/DATA"), ResolutionProfile::default())
+                .unwrap();
+
+        // Shared materials only exist in REG/MATERIAL.
+        let specvol = library.resolve("specvol.mat").unwrap();
+        assert_eq!(1, specvol.len());
+        assert_eq!("SPECVOL.MAT", specvol[0].name);
+        // ROAD1.MAT is in both, MATERIAL comes first.
+        assert_eq!(
+            Path::new("DecodedData/DATA/MATERIAL/ROAD1.MAT"),
+            library.file("ROAD1.MAT").unwrap().path
+        );
+        assert!(library.duplicates().any(|issue| matches!(
+            issue,
+            LibraryIssue::Duplicate { name, files } if name == "ROAD1.MAT" && files.len() == 2
+        )));
+        // Material only known by its internal name, SIMPMAT.MAT defines M49.MAT.
+        assert_eq!("M49.MAT", library.resolve("M49.MAT").unwrap()[0].name);
+        assert_eq!("M49.MAT", library.resolve("SIMPMAT.MAT").unwrap()[0].name);
+
+        // Track pieces of the same name with different textures.
+        assert!(library.conflicts().any(|issue| matches!(
+            issue,
+            LibraryIssue::Conflict { name, .. } if name == "WTDOOR.MAT"
+        )));
+        assert!(library.issues.iter().any(|issue| matches!(
+            issue,
+            LibraryIssue::Unreadable { file, .. } if file.ends_with("FONE.MAT")
+        )));
+
+        assert!(matches!(
+            library.resolve("NOSUCH.MAT"),
+            Err(Error::FileIO { file, .. }) if file == Path::new("DecodedData/DATA/MATERIAL/NOSUCH.MAT")
+        ));
+    }
+
+    #[test]
+    fn test_properties_roundtrip() {
+        let mat = Material {
+            properties: MaterialProperties {
+                colour: [10, 20, 30],
+                opacity: 128,
+                flags: MaterialProperties::TWO_SIDED | MaterialProperties::ENVIRONMENT_L,
+                map_transform: [[2.0, 0.5], [-0.5, 2.0], [0.25, 0.75]],
+                index_base: 64,
+                index_range: 31,
+                ..Default::default()
+            },
+            name: String::from("TEST.MAT"),
+            pixelmap_name: String::from("TEST.PIX"),
+            ..Default::default()
+        };
+        let mut data = Vec::new();
+        mat.save(&mut data).unwrap();
+        let loaded = Material::load(&mut &data[..]).unwrap();
+        assert_eq!(mat.properties, loaded.properties);
+        assert!(loaded.properties.transparent());
+        assert_eq!("TEST.PIX", loaded.pixelmap_name);
     }
 }
@@ -9,72 +9,119 @@
 #[allow(unused_imports)]
 use cgmath::{InnerSpace, Vector3, Zero};
 use {
-    crate::support::{self, resource::Chunk, Error, Vertex},
-    byteorder::{BigEndian, ReadBytesExt},
+    crate::support::{
+        self,
+        resource::{load_records, Chunk, ChunkReader, Strictness, StringSize, StringSizes},
+        texture::TextureReference,
+        Error, Vertex,
+    },
+    byteorder::{BigEndian, ReadBytesExt, WriteBytesExt},
     std::{
+        collections::{HashMap, HashSet},
         fs::File,
-        io::{BufRead, BufReader},
+        hash::Hash,
+        io::{BufReader, BufWriter, Read, Write},
+        path::PathBuf,
     },
 };
 
-#[derive(Copy, Clone, Default)]
+#[derive(Copy, Clone, Debug, Default)]
 pub struct UvCoord {
-    u: f32,
-    v: f32,
+    pub u: f32,
+    pub v: f32,
 }
 
 impl UvCoord {
     pub fn load<R: ReadBytesExt>(rdr: &mut R) -> Result<UvCoord, Error> {
-        let mut uv = UvCoord::default();
-        uv.u = rdr.read_f32::<BigEndian>()?;
-        uv.v = rdr.read_f32::<BigEndian>()?;
-        Ok(uv)
+        let u = rdr.read_f32::<BigEndian>()?;
+        let v = rdr.read_f32::<BigEndian>()?;
+        Ok(UvCoord { u, v })
+    }
+
+    pub fn write<W: WriteBytesExt>(&self, wr: &mut W) -> Result<(), Error> {
+        wr.write_f32::<BigEndian>(self.u)?;
+        wr.write_f32::<BigEndian>(self.v)?;
+        Ok(())
     }
 }
 
-#[derive(Default)]
+#[derive(Clone, Debug, Default)]
 pub struct Face {
     pub v1: u16, // vertex indices (works with glDrawElements() e.g.)
     pub v2: u16,
     pub v3: u16,
-    flags: u16, // looks like flags, always only one bit set -- not always, see CITYA81.DAT!!
+    pub flags: u16, // smoothing groups, usually one bit set -- not always, see CITYA81.DAT!!
+    pub what: u8,   // something, no idea yet, might be related to flags
     pub material_id: u16, // comes from FACE_MAT_LIST chunk
 }
 
 impl Face {
     pub fn load<R: ReadBytesExt>(rdr: &mut R) -> Result<Face, Error> {
-        let mut s = Face::default();
-        s.v1 = rdr.read_u16::<BigEndian>()?;
-        s.v2 = rdr.read_u16::<BigEndian>()?;
-        s.v3 = rdr.read_u16::<BigEndian>()?;
-        s.flags = rdr.read_u16::<BigEndian>()?;
-        rdr.read_i8()?; // something, no idea yet, might be related to flags
-        Ok(s)
+        let v1 = rdr.read_u16::<BigEndian>()?;
+        let v2 = rdr.read_u16::<BigEndian>()?;
+        let v3 = rdr.read_u16::<BigEndian>()?;
+        let flags = rdr.read_u16::<BigEndian>()?;
+        let what = rdr.read_u8()?;
+        Ok(Face {
+            v1,
+            v2,
+            v3,
+            flags,
+            what,
+            ..Default::default()
+        })
+    }
+
+    pub fn write<W: WriteBytesExt>(&self, wr: &mut W) -> Result<(), Error> {
+        wr.write_u16::<BigEndian>(self.v1)?;
+        wr.write_u16::<BigEndian>(self.v2)?;
+        wr.write_u16::<BigEndian>(self.v3)?;
+        wr.write_u16::<BigEndian>(self.flags)?;
+        wr.write_u8(self.what)?;
+        Ok(())
     }
 }
 
 #[derive(Default)]
 pub struct Mesh {
     pub name: String,
+    subtype: u16, // looks like flags: 0x3 or 0x7 in car models, 0x0 in most track models
     pub vertices: Vec<Vertex>,
     pub faces: Vec<Face>,
     pub material_names: Vec<String>,
+    pub extra_chunks: Vec<Chunk>, // unexpected chunks kept with Strictness::Keep
+    face_mat_unknown: Option<u32>, // as loaded, 2 in all game files
+    string_sizes: StringSizes,
 }
 
 impl Mesh {
-    pub fn load<R: ReadBytesExt + BufRead>(rdr: &mut R) -> Result<Mesh, Error> {
+    pub fn load<R: Read>(rdr: &mut R) -> Result<Mesh, Error> {
+        Mesh::load_with(rdr, Strictness::default())
+    }
+
+    pub fn load_with<R: Read>(rdr: &mut R, strictness: Strictness) -> Result<Mesh, Error> {
+        Mesh::from_chunks(&mut ChunkReader::new(rdr), strictness)
+    }
+
+    /// Load single mesh from the chunk stream, up to its Null terminator.
+    pub fn from_chunks<R: Read>(
+        chunks: &mut ChunkReader<R>,
+        strictness: Strictness,
+    ) -> Result<Mesh, Error> {
         let mut m = Mesh::default();
         let mut fmlist = Vec::<u16>::new();
         let mut uvcoords = Vec::<UvCoord>::new();
+        let mut faces_offset = 0;
 
         // Read chunks until last chunk is encountered.
         // Certain chunks initialize certain properties.
-        loop {
-            let c = Chunk::load(rdr)?;
+        for chunk in chunks.record() {
+            let (offset, c) = chunk?;
+            m.string_sizes.keep(&c);
             match c {
                 Chunk::FileName { name, subtype } => {
                     m.name = name;
-                    assert!(subtype == support::MODEL_FILE_SUBTYPE);
+                    m.subtype = subtype;
                 }
                 Chunk::VertexList(r) => {
                     m.vertices = r;
@@ -84,51 +131,126 @@ impl Mesh {
                 }
                 Chunk::FaceList(r) => {
                     m.faces = r;
+                    faces_offset = offset;
                 }
-                Chunk::MaterialList(r) => {
+                Chunk::MaterialList(r, _) => {
                     m.material_names = r;
                 }
-                Chunk::FaceMatList(r) => {
-                    fmlist = r;
+                Chunk::FaceMatList { materials, unknown } => {
+                    fmlist = materials;
+                    m.face_mat_unknown = Some(unknown);
                 }
-                Chunk::Null() => break,
-                Chunk::FileHeader { file_type } => {
+                Chunk::FileHeader { file_type, .. } => {
                     if file_type != support::MESH_FILE_TYPE {
-                        panic!("Invalid mesh file type {}", file_type);
+                        return Err(Error::WrongFileType {
+                            file: PathBuf::new(),
+                            offset,
+                            expected: support::MESH_FILE_TYPE,
+                            found: file_type,
+                        });
+                    }
+                }
+                c => {
+                    if let Some(c) = strictness.check(offset, c)? {
+                        m.extra_chunks.push(c);
                     }
                 }
-                _ => unimplemented!(), // unexpected type here
             }
         }
 
-        if fmlist.len() > 0 && m.faces.len() == fmlist.len() {
-            for i in 0..m.faces.len() {
-                m.faces[i].material_id = fmlist[i];
+        for (n, face) in m.faces.iter().enumerate() {
+            let vertex = face.v1.max(face.v2).max(face.v3);
+            if vertex as usize >= m.vertices.len() {
+                return Err(Error::CorruptChunk {
+                    file: PathBuf::new(),
+                    offset: faces_offset,
+                    chunk_type: support::FACE_LIST_CHUNK,
+                    reason: format!("face {} uses vertex {} of {}", n, vertex, m.vertices.len()),
+                });
+            }
+        }
+
+        if !fmlist.is_empty() && m.faces.len() == fmlist.len() {
+            for (face, material_id) in m.faces.iter_mut().zip(fmlist) {
+                face.material_id = material_id;
             }
         }
 
-        for n in 0..uvcoords.len() {
+        for (vertex, uv) in m.vertices.iter_mut().zip(uvcoords) {
             // Carma uses 0.0,0.0 for the top left corner, OpenGL for the bottom left.
-            m.vertices[n].tex_coords = [uvcoords[n].u, 1.0 - uvcoords[n].v];
+            vertex.tex_coords = [uv.u, 1.0 - uv.v];
         }
 
-        m.calc_normals();
         Ok(m)
     }
 
     // Single mesh file may contain multiple meshes
     pub fn load_from(fname: String) -> Result<Vec<Mesh>, Error> {
-        let file = File::open(fname)?;
-        let mut file = BufReader::new(file);
-        let mut meshes = Vec::<Mesh>::new();
-        loop {
-            let m = Mesh::load(&mut file);
-            match m {
-                Err(_) => break, // fixme: allow only Eof here
-                Ok(m) => meshes.push(m),
+        Mesh::load_from_with(fname, Strictness::default())
+    }
+
+    pub fn load_from_with(fname: String, strictness: Strictness) -> Result<Vec<Mesh>, Error> {
+        let path = PathBuf::from(fname);
+        let file = File::open(&path).map_err(|e| Error::from(e).in_file(&path))?;
+        let file = BufReader::new(file);
+        load_records(file, support::MESH_FILE_TYPE, |chunks| {
+            Mesh::from_chunks(chunks, strictness)
+        })
+        .map_err(|e| e.in_file(&path))
+    }
+
+    pub fn save<W: WriteBytesExt>(&self, wr: &mut W) -> Result<(), Error> {
+        Chunk::FileName {
+            name: self.name.clone(),
+            subtype: self.subtype,
+        }
+        .write(wr)?;
+        Chunk::VertexList(self.vertices.clone()).write(wr)?;
+        Chunk::UvMapList(
+            self.vertices
+                .iter()
+                .map(|v| UvCoord {
+                    u: v.tex_coords[0],
+                    v: 1.0 - v.tex_coords[1], // Flip back to Carma's top left origin.
+                })
+                .collect(),
+        )
+        .write(wr)?;
+        Chunk::FaceList(self.faces.clone()).write(wr)?;
+        if !self.material_names.is_empty() {
+            self.string_sizes
+                .sized(Chunk::MaterialList(
+                    self.material_names.clone(),
+                    StringSize::Game,
+                ))
+                .write(wr)?;
+            Chunk::FaceMatList {
+                materials: self.faces.iter().map(|f| f.material_id).collect(),
+                unknown: self.face_mat_unknown.unwrap_or(2),
             }
+            .write(wr)?;
+        }
+        for c in &self.extra_chunks {
+            c.write(wr)?;
+        }
+        Chunk::Null().write(wr)?;
+        Ok(())
+    }
+
+    // Save multiple meshes into a single mesh file
+    pub fn save_to(fname: String, meshes: &[Mesh]) -> Result<(), Error> {
+        let file = File::create(fname)?;
+        let mut file = BufWriter::new(file);
+        Chunk::FileHeader {
+            file_type: support::MESH_FILE_TYPE,
+            version: support::FILE_HEADER_VERSION,
+        }
+        .write(&mut file)?;
+        for m in meshes {
+            m.save(&mut file)?;
         }
-        Ok(meshes)
+        file.flush()?;
+        Ok(())
     }
 
     // Calculate normal from vertices in counter-clockwise order.
@@ -136,17 +258,183 @@ impl Mesh {
         (v1 - v2).cross(v2 - v3).normalize()
     }
 
-    pub fn calc_normals(&mut self) {
+    /// Smooth vertex normals. Faces at the same position share their normals when
+    /// their smoothing groups overlap, weighted by the angle of each face there.
+    /// Faces without groups stay flat. Vertices used by faces ending up with
+    /// different normals are split so that each copy gets its own.
+    pub fn calc_normals(&mut self) -> Result<(), Error> {
+        let position = |v: u16| Vector3::<f32>::from(self.vertices[v as usize].position);
+        // Vertices are duplicated along UV seams, so look faces up by position.
+        let weld = |v: u16| self.vertices[v as usize].position.map(f32::to_bits);
+
+        let flat: Vec<_> = self
+            .faces
+            .iter()
+            .map(|face| Mesh::calc_normal(position(face.v1), position(face.v2), position(face.v3)))
+            .collect();
+        // Face and its corner angle at every position, degenerate faces don't count.
+        let mut around = HashMap::<[u32; 3], Vec<(usize, f32)>>::new();
+        for (n, face) in self.faces.iter().enumerate() {
+            for [v, a, b] in [
+                [face.v1, face.v2, face.v3],
+                [face.v2, face.v3, face.v1],
+                [face.v3, face.v1, face.v2],
+            ] {
+                let angle = (position(a) - position(v))
+                    .angle(position(b) - position(v))
+                    .0;
+                if angle.is_finite() && flat[n].x.is_finite() {
+                    around.entry(weld(v)).or_default().push((n, angle));
+                }
+            }
+        }
+
+        let corner_normal = |n: usize, v: u16| {
+            let groups = self.faces[n].flags;
+            let sum = around.get(&weld(v)).map_or(Vector3::zero(), |faces| {
+                faces
+                    .iter()
+                    .filter(|&&(m, _)| m == n || groups & self.faces[m].flags != 0)
+                    .map(|&(m, angle)| flat[m] * angle)
+                    .sum()
+            });
+            if sum.magnitude2() > 0.0 {
+                sum.normalize()
+            } else {
+                flat[n]
+            }
+        };
+        let normals: Vec<_> = (0..self.faces.len())
+            .map(|n| {
+                let face = &self.faces[n];
+                [face.v1, face.v2, face.v3].map(|v| corner_normal(n, v))
+            })
+            .collect();
+
+        // Uses of a vertex with other normals get a copy.
+        let mut owner = vec![None::<[i32; 3]>; self.vertices.len()];
+        let mut copies = HashMap::<(u16, [i32; 3]), u16>::new();
+        for (n, corners) in normals.into_iter().enumerate() {
+            let face = &self.faces[n];
+            let mut vertices = [face.v1, face.v2, face.v3];
+            for (v, normal) in vertices.iter_mut().zip(corners) {
+                let normal: [f32; 3] = normal.into();
+                // Coplanar faces come out a rounding error apart, that's no reason to split.
+                let key = normal.map(|x| (x * 4096.0).round() as i32);
+                if let Some(slot) = self.split_vertex(v, key, &mut owner, &mut copies)? {
+                    self.vertices[slot].normal = normal;
+                }
+            }
+            let face = &mut self.faces[n];
+            [face.v1, face.v2, face.v3] = vertices;
+        }
+        Ok(())
+    }
+
+    /// Point v at the vertex for key. First use of a vertex keeps its slot, uses with
+    /// other keys get a copy, made once per key. Returns the slot to fill in, or None
+    /// when it has been filled in for this key already.
+    fn split_vertex<K: Copy + Eq + Hash>(
+        &mut self,
+        v: &mut u16,
+        key: K,
+        owner: &mut [Option<K>],
+        copies: &mut HashMap<(u16, K), u16>,
+    ) -> Result<Option<usize>, Error> {
+        let index = *v as usize;
+        match owner[index] {
+            None => {
+                owner[index] = Some(key);
+                Ok(Some(index))
+            }
+            Some(k) if k == key => Ok(None),
+            Some(_) => {
+                if let Some(&copy) = copies.get(&(*v, key)) {
+                    *v = copy;
+                    return Ok(None);
+                }
+                let copy =
+                    u16::try_from(self.vertices.len()).map_err(|_| Error::TooManyVertices {
+                        mesh: self.name.clone(),
+                        count: self.vertices.len() + 1,
+                    })?;
+                self.vertices.push(self.vertices[index]);
+                copies.insert((*v, key), copy);
+                *v = copy;
+                Ok(Some(copy as usize))
+            }
+        }
+    }
+
+    /// Vertices with smoothed normals and faces using them, for drawing.
+    /// The mesh itself keeps vertices as they are in the file, see calc_normals().
+    pub fn render_vertices(&self) -> Result<(Vec<Vertex>, Vec<Face>), Error> {
+        self.render_vertices_in(|_| None)
+    }
+
+    /// Same as render_vertices(), with UVs of faces whose material is found by lookup
+    /// moved into atlas space, see remap_to_atlas().
+    pub fn render_vertices_in<'a, F>(&self, lookup: F) -> Result<(Vec<Vertex>, Vec<Face>), Error>
+    where
+        F: Fn(u16) -> Option<&'a TextureReference>,
+    {
+        let mut m = Mesh {
+            name: self.name.clone(),
+            vertices: self.vertices.clone(),
+            faces: self.faces.clone(),
+            ..Default::default()
+        };
+        m.remap_to_atlas(lookup)?;
+        m.calc_normals()?;
+        Ok((m.vertices, m.faces))
+    }
+
+    /// Material ids whose faces have UVs outside of 0..1 and rely on the texture repeating.
+    /// Such textures can't be moved into an atlas.
+    pub fn tiling_materials(&self) -> HashSet<u16> {
+        // Exported UVs are often a hair outside of the edge.
+        const SLACK: f32 = 1.0 / 256.0;
+        let outside = |x: f32| !(-SLACK..=1.0 + SLACK).contains(&x);
+        self.faces
+            .iter()
+            .filter(|face| {
+                [face.v1, face.v2, face.v3].iter().any(|&v| {
+                    let [u, v] = self.vertices[v as usize].tex_coords;
+                    outside(u) || outside(v)
+                })
+            })
+            .map(|face| face.material_id)
+            .collect()
+    }
+
+    /// Rewrite UVs of faces whose material is found by lookup into atlas space.
+    /// Vertices shared between materials are split so that each copy gets its own UVs.
+    fn remap_to_atlas<'a, F>(&mut self, lookup: F) -> Result<(), Error>
+    where
+        F: Fn(u16) -> Option<&'a TextureReference>,
+    {
+        let source: Vec<_> = self.vertices.iter().map(|v| v.tex_coords).collect();
+        // Key is the material of mapped uses and None for unmapped ones.
+        let mut owner = vec![None::<Option<u16>>; self.vertices.len()];
+        let mut copies = HashMap::<(u16, Option<u16>), u16>::new();
         for n in 0..self.faces.len() {
-            let normal = Mesh::calc_normal(
-                Vector3::<f32>::from(self.vertices[self.faces[n].v1 as usize].position),
-                Vector3::<f32>::from(self.vertices[self.faces[n].v2 as usize].position),
-                Vector3::<f32>::from(self.vertices[self.faces[n].v3 as usize].position),
-            );
-            self.vertices[self.faces[n].v1 as usize].normal = normal.into();
-            self.vertices[self.faces[n].v2 as usize].normal = normal.into();
-            self.vertices[self.faces[n].v3 as usize].normal = normal.into();
+            let face = &self.faces[n];
+            let reference = lookup(face.material_id);
+            let key = reference.map(|_| face.material_id);
+            let mut vertices = [face.v1, face.v2, face.v3];
+            for v in &mut vertices {
+                let uv = source[*v as usize];
+                if let Some(slot) = self.split_vertex(v, key, &mut owner, &mut copies)? {
+                    self.vertices[slot].tex_coords = match reference {
+                        Some(r) => r.map(uv.map(|x| x.clamp(0.0, 1.0))),
+                        None => uv,
+                    };
+                }
+            }
+            let face = &mut self.faces[n];
+            [face.v1, face.v2, face.v3] = vertices;
         }
+        Ok(())
     }
 }
 
@@ -165,7 +453,7 @@ mod tests {
         assert_eq!(0xbabe, f.flags);
     }
 
-    #[cfg_attr(rustfmt, rustfmt_skip)]
+    #[rustfmt::skip]
     #[test]
     fn test_load_mesh() {
         let mut data = Cursor::new(vec![
@@ -183,6 +471,180 @@ mod tests {
         // assert_eq!(0xbabe, m.flags);
     }
 
+    #[rustfmt::skip]
+    #[test]
+    fn test_load_mesh_with_unknown_chunk() {
+        let data = vec![
+            0x0, 0x0, 0x0, 0x36, // Chunk type - FILE_NAME_CHUNK
+            0x0, 0x0, 0x0, 0x8, // Chunk size
+            0x0, 0x3, // subtype u16
+            b'h', b'e', b'l', b'l', b'o', 0, // Chunk contents
+            0x0, 0x0, 0xbe, 0xef, // Chunk type - unknown
+            0x0, 0x0, 0x0, 0x2, // Chunk size
+            0xa, 0xb, // Chunk contents
+            0x0, 0x0, 0x0, 0x0, // Chunk type - NULL_CHUNK
+            0x0, 0x0, 0x0, 0x0, // Chunk size
+        ];
+        assert!(matches!(
+            Mesh::load_with(&mut Cursor::new(&data), Strictness::Strict),
+            Err(Error::UnexpectedChunk {
+                offset: 16,
+                chunk_type: 0xbeef,
+                ..
+            })
+        ));
+
+        let m = Mesh::load_with(&mut Cursor::new(&data), Strictness::Skip).unwrap();
+        assert_eq!("hello", m.name);
+        assert!(m.extra_chunks.is_empty());
+
+        let m = Mesh::load_with(&mut Cursor::new(&data), Strictness::Keep).unwrap();
+        assert_eq!("hello", m.name);
+        assert_eq!(1, m.extra_chunks.len());
+        assert_eq!(0xbeef, m.extra_chunks[0].chunk_type());
+    }
+
+    #[rustfmt::skip]
+    #[test]
+    fn test_load_mesh_errors() {
+        let data = vec![
+            0x0, 0x0, 0x0, 0x36, // Chunk type - FILE_NAME_CHUNK
+            0x0, 0x0, 0x0, 0x8, // Chunk size
+            0x0, 0x7, // subtype u16
+            b'h', b'e', b'l', b'l', b'o', 0, // Chunk contents
+            0x0, 0x0, 0x0, 0x0, // Chunk type - NULL_CHUNK
+            0x0, 0x0, 0x0, 0x0, // Chunk size
+        ];
+        let m = Mesh::load(&mut Cursor::new(&data)).unwrap();
+        assert_eq!(0x7, m.subtype);
+
+        let data = vec![
+            0x0, 0x0, 0x0, 0x36, // Chunk type - FILE_NAME_CHUNK
+            0x0, 0x0, 0x0, 0x8, // Chunk size
+            0x0, 0x3, // subtype u16
+            b'h', b'e', b'l', b'l', b'o', 0, // Chunk contents
+            0x0, 0x0, 0x0, 0x17, // Chunk type - VERTEX_LIST_CHUNK
+            0x0, 0x0, 0x0, 0x10, // Chunk size
+            0x0, 0x0, 0x0, 0x1, // Vertex count
+            0x3f, 0x80, 0x0, 0x0, // Only one coordinate of the vertex
+        ];
+        assert!(matches!(
+            Mesh::load(&mut Cursor::new(&data)),
+            Err(Error::Truncated { offset: 16, .. })
+        ));
+
+        let data = vec![
+            0x0, 0x0, 0x0, 0x17, // Chunk type - VERTEX_LIST_CHUNK
+            0x0, 0x0, 0x0, 0x4, // Chunk size
+            0x0, 0x0, 0x0, 0x0, // Vertex count
+            0x0, 0x0, 0x0, 0x35, // Chunk type - FACE_LIST_CHUNK
+            0x0, 0x0, 0x0, 0xd, // Chunk size
+            0x0, 0x0, 0x0, 0x1, // Face count
+            0x0, 0x0, 0x0, 0x1, 0x0, 0x2, // Vertex indices past the vertex list
+            0x0, 0x1, 0x0, // Flags and what
+            0x0, 0x0, 0x0, 0x0, // Chunk type - NULL_CHUNK
+            0x0, 0x0, 0x0, 0x0, // Chunk size
+        ];
+        assert!(matches!(
+            Mesh::load(&mut Cursor::new(&data)),
+            Err(Error::CorruptChunk { offset: 12, chunk_type: support::FACE_LIST_CHUNK, .. })
+        ));
+    }
+
+    #[test]
+    fn test_save_mesh() {
+        let mut m = Mesh {
+            name: String::from("hello"),
+            subtype: support::MODEL_FILE_SUBTYPE,
+            vertices: vec![Vertex::default(); 3],
+            faces: vec![Face {
+                v1: 0,
+                v2: 1,
+                v3: 2,
+                flags: 0x1,
+                what: 0x4,
+                material_id: 1,
+            }],
+            material_names: vec![String::from("HELLO.MAT")],
+            ..Default::default()
+        };
+        m.vertices[1].position = [1.0, 0.0, 0.0];
+        m.vertices[1].tex_coords = [1.0, 0.25];
+        m.vertices[2].position = [0.0, 1.0, 0.0];
+        m.vertices[2].tex_coords = [0.5, 0.5];
+
+        let mut data = Vec::new();
+        m.save(&mut data).unwrap();
+        let l = Mesh::load(&mut Cursor::new(data)).unwrap();
+
+        assert_eq!(m.name, l.name);
+        assert_eq!(m.subtype, l.subtype);
+        assert_eq!(m.material_names, l.material_names);
+        for (a, b) in m.vertices.iter().zip(&l.vertices) {
+            assert_eq!(a.position, b.position);
+            assert_eq!(a.tex_coords, b.tex_coords);
+        }
+        assert_eq!(1, l.faces.len());
+        assert_eq!((0, 1, 2), (l.faces[0].v1, l.faces[0].v2, l.faces[0].v3));
+        assert_eq!(0x1, l.faces[0].flags);
+        assert_eq!(0x4, l.faces[0].what);
+        assert_eq!(1, l.faces[0].material_id);
+    }
+
+    #[test]
+    fn test_load_from_stops_only_at_clean_eof() {
+        let fname = std::env::temp_dir().join(format!("carma-mesh-{}.DAT", std::process::id()));
+        let fname = String::from(fname.to_str().unwrap());
+        let meshes = vec![
+            Mesh {
+                name: String::from("first"),
+                vertices: vec![Vertex::default(); 3],
+                ..Default::default()
+            },
+            Mesh {
+                name: String::from("second"),
+                vertices: vec![Vertex::default(); 3],
+                ..Default::default()
+            },
+        ];
+
+        Mesh::save_to(fname.clone(), &meshes[..0]).unwrap();
+        assert!(Mesh::load_from(fname.clone()).unwrap().is_empty());
+
+        Mesh::save_to(fname.clone(), &meshes).unwrap();
+        let loaded = Mesh::load_from(fname.clone()).unwrap();
+        assert_eq!(2, loaded.len());
+        assert_eq!("second", loaded[1].name);
+
+        // Cut the second mesh short.
+        let data = std::fs::read(&fname).unwrap();
+        std::fs::write(&fname, &data[..data.len() - 12]).unwrap();
+        let result = Mesh::load_from(fname.clone());
+        std::fs::remove_file(&fname).unwrap();
+        match result {
+            Err(Error::BadRecord { index, error }) => {
+                assert_eq!(1, index);
+                assert!(matches!(*error, Error::Truncated { .. }));
+            }
+            _ => panic!("Expected second mesh to fail loading"),
+        }
+    }
+
+    #[test]
+    fn test_save_loaded_meshes() {
+        // Normals are smoothed on a render copy, saving writes back what was loaded.
+        let meshes = Mesh::load_from(String::from("DecodedData/DATA/MODELS/EAGLE.DAT")).unwrap();
+        for m in &meshes {
+            let mut data = Vec::<u8>::new();
+            m.save(&mut data).unwrap();
+            let l = Mesh::load(&mut Cursor::new(data)).unwrap();
+            assert_eq!(m.vertices.len(), l.vertices.len(), "{}", m.name);
+            assert_eq!(m.faces.len(), l.faces.len(), "{}", m.name);
+        }
+        let wheel = meshes.iter().find(|m| m.name == "WHEEL.DAT").unwrap();
+        assert_eq!(16, wheel.vertices.len());
+    }
+
     // test that normals to unit vectors will be the third unit vector
     #[test]
     fn test_calc_normal() {
@@ -195,4 +657,152 @@ mod tests {
             -Vector3::unit_z()
         );
     }
+
+    // Two faces folded 90 degrees along the edge from vertex 0 to 1, in groups a and b.
+    fn fold(a: u16, b: u16) -> Mesh {
+        let mut m = Mesh::default();
+        for position in [
+            [0.0, 0.0, 0.0],
+            [1.0, 0.0, 0.0],
+            [0.0, 1.0, 0.0],
+            [0.0, 0.0, 1.0],
+        ] {
+            m.vertices.push(Vertex {
+                position,
+                ..Default::default()
+            });
+        }
+        for (v2, v3, flags) in [(1, 2, a), (3, 1, b)] {
+            m.faces.push(Face {
+                v1: 0,
+                v2,
+                v3,
+                flags,
+                ..Default::default()
+            });
+        }
+        m
+    }
+
+    fn corner_normals((vertices, faces): &(Vec<Vertex>, Vec<Face>)) -> Vec<[[f32; 3]; 3]> {
+        faces
+            .iter()
+            .map(|f| [f.v1, f.v2, f.v3].map(|v| vertices[v as usize].normal))
+            .collect()
+    }
+
+    #[test]
+    fn test_smoothing_groups() {
+        let (z, y) = ([0.0, 0.0, 1.0], [0.0, 1.0, 0.0]);
+
+        // Sharing a group, the edge is smoothed over and no vertices are added.
+        let mut m = fold(0x2, 0x3);
+        m.calc_normals().unwrap();
+        assert_eq!(4, m.vertices.len());
+        let half = std::f32::consts::FRAC_1_SQRT_2;
+        let edge = Vector3::from(m.vertices[0].normal);
+        assert!((edge - Vector3::new(0.0, half, half)).magnitude() < 1e-6);
+        assert_eq!(m.vertices[0].normal, m.vertices[1].normal);
+        assert_eq!(z, m.vertices[2].normal);
+        assert_eq!(y, m.vertices[3].normal);
+
+        // Different groups or none at all keep the faces flat and split the edge.
+        for (a, b) in [(0x1, 0x2), (0, 0)] {
+            let render = fold(a, b).render_vertices().unwrap();
+            assert_eq!(6, render.0.len());
+            assert_eq!(vec![[z; 3], [y; 3]], corner_normals(&render));
+        }
+    }
+
+    #[test]
+    fn test_wheel_normals() {
+        let wheel = Mesh::load_from(String::from("DecodedData/DATA/MODELS/EAGLE.DAT"))
+            .unwrap()
+            .into_iter()
+            .find(|m| m.name == "WHEEL.DAT")
+            .unwrap();
+        // Tread is one smoothing group, the rims are flat fans of faces in groups of their own.
+        // Every rim vertex gets a copy for the tread, the mesh itself keeps the file vertices.
+        let render = wheel.render_vertices().unwrap();
+        assert_eq!(16, wheel.vertices.len());
+        assert_eq!(32, render.0.len());
+        for (face, normals) in render.1.iter().zip(corner_normals(&render)) {
+            for normal in normals {
+                let normal = Vector3::from(normal);
+                assert!((normal.magnitude() - 1.0).abs() < 1e-5);
+                match face.flags {
+                    0x1 => assert!(normal.x.abs() < 1e-5, "{:?}", normal),
+                    _ => assert!((normal.x.abs() - 1.0).abs() < 1e-5, "{:?}", normal),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_normals_ignore_face_order() {
+        let body = Mesh::load_from(String::from("DecodedData/DATA/MODELS/EAGLE.DAT"))
+            .unwrap()
+            .remove(0);
+        let reversed = Mesh {
+            vertices: body.vertices.clone(),
+            faces: body.faces.iter().rev().cloned().collect(),
+            ..Default::default()
+        };
+        let mut expected = corner_normals(&body.render_vertices().unwrap());
+        expected.reverse();
+        let reversed = corner_normals(&reversed.render_vertices().unwrap());
+        for (a, b) in expected.iter().zip(reversed) {
+            for (a, b) in a.iter().zip(b) {
+                assert!((Vector3::from(*a) - Vector3::from(b)).magnitude() < 1e-3);
+            }
+        }
+    }
+
+    #[test]
+    fn test_remap_to_atlas() {
+        let mut m = Mesh::default();
+        for uv in [[0.0, 0.0], [1.0, 0.0], [1.0, 1.0], [2.0, 1.0]] {
+            m.vertices.push(Vertex {
+                tex_coords: uv,
+                ..Default::default()
+            });
+        }
+        for (v3, material_id) in [(2, 1), (3, 2)] {
+            m.faces.push(Face {
+                v1: 0,
+                v2: 1,
+                v3,
+                material_id,
+                ..Default::default()
+            });
+        }
+        assert_eq!(HashSet::from([2]), m.tiling_materials());
+
+        let quarter = TextureReference {
+            id: 0,
+            x0: 0.5,
+            y0: 0.0,
+            x1: 1.0,
+            y1: 0.5,
+            name: String::from("QUARTER.PIX"),
+        };
+        m.remap_to_atlas(|id| (id == 1).then_some(&quarter))
+            .unwrap();
+
+        // First face keeps the original vertices, the tiling face gets copies with old UVs.
+        assert_eq!((0, 1, 2), (m.faces[0].v1, m.faces[0].v2, m.faces[0].v3));
+        assert_eq!((4, 5, 3), (m.faces[1].v1, m.faces[1].v2, m.faces[1].v3));
+        let uvs: Vec<_> = m.vertices.iter().map(|v| v.tex_coords).collect();
+        assert_eq!(
+            vec![
+                [0.5, 0.5],
+                [1.0, 0.5],
+                [1.0, 1.0],
+                [2.0, 1.0],
+                [0.0, 0.0],
+                [1.0, 0.0]
+            ],
+            uvs
+        );
+    }
 } // tests mod
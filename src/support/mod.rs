@@ -12,13 +12,14 @@
 // extern crate obj;
 
 use {
-    byteorder::{BigEndian, ReadBytesExt},
+    byteorder::{BigEndian, ReadBytesExt, WriteBytesExt},
     cgmath::Vector3,
     glium::implement_vertex,
     std::{
         self,
         convert::From,
-        io::BufRead,
+        fs,
+        io::{Read, Write},
         ops::Sub,
         path::{Path, PathBuf},
         thread,
@@ -27,16 +28,25 @@ use {
 };
 
 pub mod actor;
+pub mod atlas;
 pub mod camera;
 pub mod car;
+pub mod flic;
+pub mod font;
+pub mod headup;
 pub mod material;
 pub mod mesh;
+pub mod palette;
 pub mod render_manager;
+pub mod resolution;
+pub mod resolver;
 pub mod resource;
+pub mod shade_table;
+pub mod text;
 pub mod texture;
 // pub mod animated_parameter;
 
-#[derive(Copy, Clone, Default)]
+#[derive(Copy, Clone, Debug, Default)]
 pub struct Vertex {
     pub position: [f32; 3],
     pub normal: [f32; 3],
@@ -53,6 +63,13 @@ impl Vertex {
         vertex.position[2] = rdr.read_f32::<BigEndian>()?;
         Ok(vertex)
     }
+
+    pub fn write<W: WriteBytesExt>(&self, wr: &mut W) -> Result<(), Error> {
+        wr.write_f32::<BigEndian>(self.position[0])?;
+        wr.write_f32::<BigEndian>(self.position[1])?;
+        wr.write_f32::<BigEndian>(self.position[2])?;
+        Ok(())
+    }
 }
 
 // This is used only for vector math, using positions
@@ -69,12 +86,231 @@ impl Sub for Vertex {
     }
 }
 
-// @todo use failure
 #[derive(Debug)]
 pub enum Error {
     IO(std::io::Error),
     Utf8(std::str::Utf8Error),
     FromUtf8(std::string::FromUtf8Error),
+    // I/O error while reading or writing a particular file.
+    FileIO {
+        file: PathBuf,
+        error: std::io::Error,
+    },
+    // Loader found a chunk it does not expect in this file type.
+    UnexpectedChunk {
+        file: PathBuf,
+        offset: u64,
+        chunk_type: u32,
+    },
+    // File header chunk announces a different kind of resource file.
+    WrongFileType {
+        file: PathBuf,
+        offset: u64,
+        expected: u32,
+        found: u32,
+    },
+    // File ended in the middle of the chunk starting at offset.
+    Truncated {
+        file: PathBuf,
+        offset: u64,
+    },
+    // Chunk contents do not match its type.
+    CorruptChunk {
+        file: PathBuf,
+        offset: u64,
+        chunk_type: u32,
+        reason: String,
+    },
+    // Text resource line does not match what the parser expects.
+    ParseText {
+        file: PathBuf,
+        line: usize,
+        expected: String,
+        found: String,
+    },
+    UnsupportedVersion {
+        file: PathBuf,
+        line: usize,
+        version: u32,
+    },
+    // Pixelmap data can not be converted: unsupported format or wrong size.
+    BadPixelmap {
+        name: String,
+        reason: String,
+    },
+    // Palette does not have 256 colours or can't be read in the given format.
+    BadPalette {
+        name: String,
+        reason: String,
+    },
+    // FLI/FLC animation header or frame chunks don't make sense.
+    BadFlic {
+        name: String,
+        reason: String,
+    },
+    // Splitting vertices for atlas UVs needs more than u16 indices can address.
+    TooManyVertices {
+        mesh: String,
+        count: usize,
+    },
+    // Loading record number index of a multi-record file failed.
+    BadRecord {
+        index: usize,
+        error: Box<Error>,
+    },
+}
+
+impl Error {
+    /// Attach file name to an error produced by a loader, if it has no file name yet.
+    pub fn in_file(self, path: &Path) -> Error {
+        match self {
+            Error::IO(error) => Error::FileIO {
+                file: path.to_path_buf(),
+                error,
+            },
+            Error::BadRecord { index, error } => Error::BadRecord {
+                index,
+                error: Box::new(error.in_file(path)),
+            },
+            mut e => {
+                match &mut e {
+                    Error::UnexpectedChunk { file, .. }
+                    | Error::WrongFileType { file, .. }
+                    | Error::Truncated { file, .. }
+                    | Error::CorruptChunk { file, .. }
+                    | Error::ParseText { file, .. }
+                    | Error::UnsupportedVersion { file, .. }
+                        if file.as_os_str().is_empty() =>
+                    {
+                        *file = path.to_path_buf();
+                    }
+                    _ => {}
+                }
+                e
+            }
+        }
+    }
+
+    /// Attach offset of the chunk being read to an error produced by the chunk reader.
+    /// Running out of data inside a chunk turns into Truncated.
+    pub fn at_offset(mut self, at: u64) -> Error {
+        match &mut self {
+            Error::IO(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                return Error::Truncated {
+                    file: PathBuf::new(),
+                    offset: at,
+                };
+            }
+            Error::UnexpectedChunk { offset, .. }
+            | Error::WrongFileType { offset, .. }
+            | Error::Truncated { offset, .. }
+            | Error::CorruptChunk { offset, .. } => *offset = at,
+            _ => {}
+        }
+        self
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Error::IO(e) => write!(f, "I/O error: {}", e),
+            Error::Utf8(e) => write!(f, "invalid UTF-8: {}", e),
+            Error::FromUtf8(e) => write!(f, "invalid UTF-8: {}", e),
+            Error::FileIO { file, error } => write!(f, "{}: {}", file.display(), error),
+            Error::UnexpectedChunk {
+                file,
+                offset,
+                chunk_type,
+            } => write!(
+                f,
+                "{}: unexpected chunk type {:#x} at offset {:#x}",
+                file.display(),
+                chunk_type,
+                offset
+            ),
+            Error::WrongFileType {
+                file,
+                offset,
+                expected,
+                found,
+            } => write!(
+                f,
+                "{}: wrong file type {:#x} at offset {:#x}, expected {:#x}",
+                file.display(),
+                found,
+                offset,
+                expected
+            ),
+            Error::Truncated { file, offset } => write!(
+                f,
+                "{}: file ends inside chunk at offset {:#x}",
+                file.display(),
+                offset
+            ),
+            Error::CorruptChunk {
+                file,
+                offset,
+                chunk_type,
+                reason,
+            } => write!(
+                f,
+                "{}: corrupt chunk type {:#x} at offset {:#x}: {}",
+                file.display(),
+                chunk_type,
+                offset,
+                reason
+            ),
+            Error::ParseText {
+                file,
+                line,
+                expected,
+                found,
+            } => write!(
+                f,
+                "{}:{}: expected {} but got {:?}",
+                file.display(),
+                line,
+                expected,
+                found
+            ),
+            Error::UnsupportedVersion {
+                file,
+                line,
+                version,
+            } => write!(
+                f,
+                "{}:{}: unsupported version {}",
+                file.display(),
+                line,
+                version
+            ),
+            Error::BadPixelmap { name, reason } => write!(f, "pixelmap {}: {}", name, reason),
+            Error::BadPalette { name, reason } => write!(f, "palette {}: {}", name, reason),
+            Error::BadFlic { name, reason } => write!(f, "animation {}: {}", name, reason),
+            Error::TooManyVertices { mesh, count } => {
+                write!(
+                    f,
+                    "mesh {}: {} vertices don't fit in 16-bit indices",
+                    mesh, count
+                )
+            }
+            Error::BadRecord { index, error } => write!(f, "{} (record {})", error, index),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::IO(e) => Some(e),
+            Error::Utf8(e) => Some(e),
+            Error::FromUtf8(e) => Some(e),
+            Error::FileIO { error, .. } => Some(error),
+            Error::BadRecord { error, .. } => Some(error.as_ref()),
+            _ => None,
+        }
+    }
 }
 
 impl From<std::io::Error> for Error {
@@ -128,22 +364,38 @@ where
     }
 }
 
-// Load a C-style 0-terminated string from the file and return it
-pub fn read_c_string<R: BufRead>(reader: &mut R) -> Result<String, Error> {
+// Load a C-style 0-terminated string from the file and return it.
+// Reads byte by byte to not consume anything past the terminator, wrap unbuffered sources
+// in a BufReader.
+pub fn read_c_string<R: Read>(reader: &mut R) -> Result<String, Error> {
     let mut buf = vec![];
-    /*let num_bytes =*/
-    reader.read_until(0, &mut buf)?;
-    buf.pop();
+    loop {
+        match reader.read_u8()? {
+            0 => break,
+            byte => buf.push(byte),
+        }
+    }
     let s = String::from_utf8(buf)?; //@todo from_utf8_lossy
     Ok(s)
 }
 
+// Write a C-style 0-terminated string to the file
+pub fn write_c_string<W: Write>(writer: &mut W, s: &str) -> Result<(), Error> {
+    writer.write_all(s.as_bytes())?;
+    writer.write_all(&[0])?;
+    Ok(())
+}
+
 /*
  * Creates a pathname to filepath with the last directory replaced to newdir
  * and optionally changing extension to newext.
  */
 pub fn path_subst(filepath: &Path, newdir: &Path, newext: Option<String>) -> PathBuf {
-    let fname = filepath.file_name().unwrap();
+    // Names come from data files, leave anything without a file name (like "..") alone,
+    // opening it will fail with a proper error.
+    let Some(fname) = filepath.file_name() else {
+        return filepath.to_path_buf();
+    };
     let mut dir = PathBuf::from(filepath);
     dir.pop(); // remove file name
     dir.pop(); // remove parent dir
@@ -152,7 +404,27 @@ pub fn path_subst(filepath: &Path, newdir: &Path, newext: Option<String>) -> Pat
     if let Some(ext) = newext {
         dir.set_extension(ext);
     }
-    return dir;
+    dir
+}
+
+/// Existing file at path, or one in the same directory with the file name in different case.
+/// Data files were made on DOS and refer to each other in any case.
+/// Exact match wins, otherwise the first match in directory order.
+pub fn find_ignoring_case(path: &Path) -> Option<PathBuf> {
+    if path.exists() {
+        return Some(path.to_path_buf());
+    }
+    let name = path.file_name()?.to_str()?;
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    fs::read_dir(dir)
+        .ok()?
+        .filter_map(Result::ok)
+        .map(|entry| entry.file_name())
+        .find(|found| found.to_str().is_some_and(|f| f.eq_ignore_ascii_case(name)))
+        .map(|found| path.with_file_name(found))
 }
 
 // Returns a vertex buffer that should be rendered as `TrianglesList`.
@@ -213,7 +485,7 @@ pub const ACTOR_NAME_CHUNK: u32 = 0x23;
 pub const ACTOR_NODE_DOWN_CHUNK: u32 = 0x25;
 pub const MESHFILE_REF_CHUNK: u32 = 0x24;
 pub const MATERIAL_REF_CHUNK: u32 = 0x26;
-pub const UNKNOWN_29_CHUNK: u32 = 0x29;
+pub const ACTOR_BOUNDS_CHUNK: u32 = 0x29;
 pub const ACTOR_NODE_UP_CHUNK: u32 = 0x2a;
 pub const ACTOR_TRANSFORM_CHUNK: u32 = 0x2b;
 pub const MAP_BOUNDINGBOX_CHUNK: u32 = 0x32;
@@ -227,6 +499,48 @@ pub const MESH_FILE_TYPE: u32 = 0xface;
 
 pub const MODEL_FILE_SUBTYPE: u16 = 0x3;
 
+// Second field of the file header chunk, same in all known game files.
+pub const FILE_HEADER_VERSION: u32 = 0x2;
+
+/// Name of the chunk type constant, for diagnostics.
+pub fn chunk_name(chunk_type: u32) -> Option<&'static str> {
+    match chunk_type {
+        NULL_CHUNK => Some("NULL_CHUNK"),
+        PIXELMAP_HEADER_CHUNK => Some("PIXELMAP_HEADER_CHUNK"),
+        MATERIAL_DESC_CHUNK => Some("MATERIAL_DESC_CHUNK"),
+        FILE_HEADER_CHUNK => Some("FILE_HEADER_CHUNK"),
+        MATERIAL_LIST_CHUNK => Some("MATERIAL_LIST_CHUNK"),
+        VERTEX_LIST_CHUNK => Some("VERTEX_LIST_CHUNK"),
+        UVMAP_LIST_CHUNK => Some("UVMAP_LIST_CHUNK"),
+        FACE_MAT_LIST_CHUNK => Some("FACE_MAT_LIST_CHUNK"),
+        PIXELMAP_REF_CHUNK => Some("PIXELMAP_REF_CHUNK"),
+        RENDERTAB_REF_CHUNK => Some("RENDERTAB_REF_CHUNK"),
+        PIXELMAP_DATA_CHUNK => Some("PIXELMAP_DATA_CHUNK"),
+        ACTOR_NAME_CHUNK => Some("ACTOR_NAME_CHUNK"),
+        ACTOR_NODE_DOWN_CHUNK => Some("ACTOR_NODE_DOWN_CHUNK"),
+        MESHFILE_REF_CHUNK => Some("MESHFILE_REF_CHUNK"),
+        MATERIAL_REF_CHUNK => Some("MATERIAL_REF_CHUNK"),
+        ACTOR_BOUNDS_CHUNK => Some("ACTOR_BOUNDS_CHUNK"),
+        ACTOR_NODE_UP_CHUNK => Some("ACTOR_NODE_UP_CHUNK"),
+        ACTOR_TRANSFORM_CHUNK => Some("ACTOR_TRANSFORM_CHUNK"),
+        MAP_BOUNDINGBOX_CHUNK => Some("MAP_BOUNDINGBOX_CHUNK"),
+        FACE_LIST_CHUNK => Some("FACE_LIST_CHUNK"),
+        FILE_NAME_CHUNK => Some("FILE_NAME_CHUNK"),
+        _ => None,
+    }
+}
+
+/// Name of the file type constant from the file header chunk, for diagnostics.
+pub fn file_type_name(file_type: u32) -> Option<&'static str> {
+    match file_type {
+        ACTOR_FILE_TYPE => Some("ACTOR_FILE_TYPE"),
+        PIXELMAP_FILE_TYPE => Some("PIXELMAP_FILE_TYPE"),
+        MATERIAL_FILE_TYPE => Some("MATERIAL_FILE_TYPE"),
+        MESH_FILE_TYPE => Some("MESH_FILE_TYPE"),
+        _ => None,
+    }
+}
+
 #[cfg(test)]
 mod tests {
 
@@ -251,19 +565,26 @@ mod tests {
         assert_eq!(b"c"[0], v);
     }
 
+    #[test]
+    fn test_write_c_string() {
+        let mut data = Vec::new();
+        write_c_string(&mut data, "hello world").unwrap();
+        assert_eq!(b"hello world\0", data.as_slice());
+    }
+
     #[test]
     fn test_path_subst() {
         assert_eq!(
             PathBuf::from("/path/file.ext2"),
             path_subst(
-                &Path::new("/old/file.ext"),
-                &Path::new("path"),
+                Path::new("/old/file.ext"),
+                Path::new("path"),
                 Some(String::from("ext2")),
             )
         );
         assert_eq!(
             PathBuf::from("/path/file.ext"),
-            path_subst(&Path::new("/old/file.ext"), &Path::new("path"), None)
+            path_subst(Path::new("/old/file.ext"), Path::new("path"), None)
         );
     }
 }
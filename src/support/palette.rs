@@ -0,0 +1,418 @@
+//
+// Part of Roadkill Project.
+//
+// Copyright 2010, 2017, Stanislav Karchebnyy <berkus@madfire.net>
+//
+// Distributed under the Boost Software License, Version 1.0.
+// (See file LICENSE_1_0.txt or a copy at http://www.boost.org/LICENSE_1_0.txt)
+//
+use {
+    crate::support::{
+        self,
+        resource::{load_records, Chunk, Strictness},
+        text::TextReader,
+        texture::{PixelFormat, PixelMap},
+        Error,
+    },
+    std::{
+        fs::{self, File},
+        io::{BufWriter, Cursor, Read, Write},
+        path::{Path, PathBuf},
+    },
+};
+
+pub const PALETTE_SIZE: usize = 256;
+
+/// File formats palettes are read from and written to.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PaletteFormat {
+    Pixelmap, // Game .PAL file, a chunked pixelmap with 256 XRGB entries
+    Jasc,     // JASC-PAL text from Paint Shop Pro
+    Gimp,     // GIMP .gpl text
+    Act,      // Adobe colour table, 768 bytes of RGB
+}
+
+impl PaletteFormat {
+    /// Guess format from file contents.
+    pub fn detect(data: &[u8]) -> PaletteFormat {
+        if data.starts_with(b"JASC-PAL") {
+            PaletteFormat::Jasc
+        } else if data.starts_with(b"GIMP Palette") {
+            PaletteFormat::Gimp
+        } else if data.len() == 768 || data.len() == 772 {
+            PaletteFormat::Act
+        } else {
+            PaletteFormat::Pixelmap
+        }
+    }
+
+    /// Guess format from file extension, .PAL means the game format.
+    pub fn from_extension(path: &Path) -> Option<PaletteFormat> {
+        let ext = path.extension()?.to_str()?.to_ascii_lowercase();
+        match ext.as_str() {
+            "pal" => Some(PaletteFormat::Pixelmap),
+            "gpl" => Some(PaletteFormat::Gimp),
+            "act" => Some(PaletteFormat::Act),
+            _ => None,
+        }
+    }
+}
+
+/**
+ * Table of 256 RGBA colours for 8-bit pixelmaps.
+ * The game stores alpha inverted in the X byte, formats without alpha import as opaque.
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Palette {
+    pub name: String,
+    colors: [[u8; 4]; PALETTE_SIZE],
+}
+
+impl Default for Palette {
+    fn default() -> Palette {
+        Palette {
+            name: String::new(),
+            colors: [[0, 0, 0, 255]; PALETTE_SIZE],
+        }
+    }
+}
+
+impl Palette {
+    fn error(&self, reason: String) -> Error {
+        Error::BadPalette {
+            name: self.name.clone(),
+            reason,
+        }
+    }
+
+    pub fn rgba(&self, index: u8) -> [u8; 4] {
+        self.colors[usize::from(index)]
+    }
+
+    pub fn set_rgba(&mut self, index: u8, rgba: [u8; 4]) {
+        self.colors[usize::from(index)] = rgba;
+    }
+
+    pub fn colors(&self) -> &[[u8; 4]; PALETTE_SIZE] {
+        &self.colors
+    }
+
+    /// Index of the colour closest to rgb, skipping index 0 which the game keeps for transparency.
+    /// Components may be out of 0..255 range while dithering carries error over.
+    pub fn nearest(&self, rgb: [i32; 3]) -> u8 {
+        (1..PALETTE_SIZE)
+            .min_by_key(|&index| {
+                let color = self.colors[index];
+                (0..3)
+                    .map(|c| (rgb[c] - i32::from(color[c])).pow(2))
+                    .sum::<i32>()
+            })
+            .unwrap() as u8
+    }
+
+    /// All colours as RGBA bytes, to upload as a 256 x 1 texture.
+    pub fn to_rgba(&self) -> Vec<u8> {
+        self.colors.concat()
+    }
+
+    pub fn from_pixelmap(pm: &PixelMap) -> Result<Palette, Error> {
+        let mut palette = Palette {
+            name: pm.name.clone(),
+            ..Default::default()
+        };
+        let (w, h) = (usize::from(pm.w), usize::from(pm.h));
+        if pm.format != PixelFormat::Rgbx888 || w * h != PALETTE_SIZE {
+            return Err(palette.error(format!(
+                "expected {} {} entries, got {}x{} {}",
+                PALETTE_SIZE,
+                PixelFormat::Rgbx888,
+                w,
+                h,
+                pm.format
+            )));
+        }
+        let stride = match pm.row_bytes {
+            0 => w * 4,
+            row_bytes => usize::from(row_bytes),
+        };
+        if stride < w * 4 || pm.data.len() < stride * h {
+            return Err(palette.error(format!(
+                "{} bytes of data for {} entries with {} bytes per row",
+                pm.data.len(),
+                PALETTE_SIZE,
+                stride
+            )));
+        }
+        let entries = pm
+            .data
+            .chunks(stride)
+            .take(h)
+            .flat_map(|row| row[..w * 4].chunks(4));
+        for (color, x) in palette.colors.iter_mut().zip(entries) {
+            *color = [x[1], x[2], x[3], 255 - x[0]];
+        }
+        Ok(palette)
+    }
+
+    /// Pixelmap laid out like DRRENDER.PAL.
+    pub fn to_pixelmap(&self) -> PixelMap {
+        PixelMap {
+            name: self.name.clone(),
+            w: 1,
+            h: PALETTE_SIZE as u16,
+            origin_x: 0,
+            origin_y: PALETTE_SIZE as u16 / 2,
+            format: PixelFormat::Rgbx888,
+            row_bytes: 4,
+            units: PALETTE_SIZE as u32,
+            unit_bytes: 4,
+            data: self
+                .colors
+                .iter()
+                .flat_map(|&[r, g, b, a]| [255 - a, r, g, b])
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    /// Load the first palette of a game .PAL file.
+    pub fn load_from(fname: String) -> Result<Palette, Error> {
+        let path = PathBuf::from(&fname);
+        let pixelmaps = PixelMap::load_from(fname)?;
+        let pm = pixelmaps.first().ok_or_else(|| Error::BadPalette {
+            name: path.display().to_string(),
+            reason: String::from("no palette in file"),
+        })?;
+        Palette::from_pixelmap(pm)
+    }
+
+    pub fn save_to(&self, fname: String) -> Result<(), Error> {
+        PixelMap::save_to(fname, &[self.to_pixelmap()])
+    }
+
+    pub fn read_from<R: Read>(rdr: &mut R, format: PaletteFormat) -> Result<Palette, Error> {
+        let mut data = Vec::new();
+        rdr.read_to_end(&mut data)?;
+        match format {
+            PaletteFormat::Pixelmap => {
+                let pixelmaps =
+                    load_records(Cursor::new(data), support::PIXELMAP_FILE_TYPE, |chunks| {
+                        PixelMap::from_chunks(chunks, Strictness::default())
+                    })?;
+                let pm = pixelmaps.first().ok_or_else(|| Error::BadPalette {
+                    name: String::new(),
+                    reason: String::from("no palette in file"),
+                })?;
+                Palette::from_pixelmap(pm)
+            }
+            PaletteFormat::Jasc => Palette::read_jasc(&String::from_utf8_lossy(&data)),
+            PaletteFormat::Gimp => Palette::read_gimp(&String::from_utf8_lossy(&data)),
+            PaletteFormat::Act => Palette::read_act(&data),
+        }
+    }
+
+    pub fn write_to<W: Write>(&self, wr: &mut W, format: PaletteFormat) -> Result<(), Error> {
+        match format {
+            PaletteFormat::Pixelmap => {
+                Chunk::FileHeader {
+                    file_type: support::PIXELMAP_FILE_TYPE,
+                    version: support::FILE_HEADER_VERSION,
+                }
+                .write(wr)?;
+                self.to_pixelmap().save(wr)
+            }
+            PaletteFormat::Jasc => {
+                writeln!(wr, "JASC-PAL\r\n0100\r\n{}\r", PALETTE_SIZE)?;
+                for [r, g, b, _] in self.colors {
+                    writeln!(wr, "{} {} {}\r", r, g, b)?;
+                }
+                Ok(())
+            }
+            PaletteFormat::Gimp => {
+                let name = self.name.split('.').next().unwrap_or_default();
+                writeln!(wr, "GIMP Palette\nName: {}\nColumns: 16\n#", name)?;
+                for (index, [r, g, b, _]) in self.colors.iter().enumerate() {
+                    writeln!(wr, "{:3} {:3} {:3}\tIndex {}", r, g, b, index)?;
+                }
+                Ok(())
+            }
+            PaletteFormat::Act => {
+                for [r, g, b, _] in self.colors {
+                    wr.write_all(&[r, g, b])?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Load palette in any format, guessing it from contents.
+    /// Palettes without a name are named after the file, like game palettes.
+    pub fn import_from(fname: String) -> Result<Palette, Error> {
+        let path = PathBuf::from(fname);
+        let data = fs::read(&path).map_err(|e| Error::from(e).in_file(&path))?;
+        let format = PaletteFormat::detect(&data);
+        let mut palette =
+            Palette::read_from(&mut data.as_slice(), format).map_err(|e| e.in_file(&path))?;
+        if palette.name.is_empty() {
+            if let Some(stem) = path.file_stem() {
+                palette.name = format!("{}.PAL", stem.to_string_lossy().to_uppercase());
+            }
+        }
+        Ok(palette)
+    }
+
+    pub fn export_to(&self, fname: String, format: PaletteFormat) -> Result<(), Error> {
+        let path = PathBuf::from(fname);
+        let file = File::create(&path).map_err(|e| Error::from(e).in_file(&path))?;
+        let mut file = BufWriter::new(file);
+        self.write_to(&mut file, format)
+            .and_then(|_| Ok(file.flush()?))
+            .map_err(|e| e.in_file(&path))
+    }
+
+    // Parse "r g b" colour line, the rest of it (GIMP colour name) is ignored.
+    fn parse_rgb(input: &TextReader, line: &str) -> Result<[u8; 4], Error> {
+        let rgb = line
+            .split_whitespace()
+            .take(3)
+            .map(|c| c.parse::<u8>())
+            .collect::<Result<Vec<_>, _>>()
+            .ok()
+            .filter(|rgb| rgb.len() == 3)
+            .ok_or_else(|| input.error("red, green and blue 0-255", line))?;
+        Ok([rgb[0], rgb[1], rgb[2], 255])
+    }
+
+    fn read_jasc(text: &str) -> Result<Palette, Error> {
+        let mut input = TextReader::new(PathBuf::new(), text);
+        input.expect_line("JASC-PAL")?;
+        input.expect_line("0100")?;
+        let count: usize = input.parse_line("colour count")?;
+        if count != PALETTE_SIZE {
+            return Err(input.error("256 colours", &count.to_string()));
+        }
+        let mut palette = Palette::default();
+        for color in palette.colors.iter_mut() {
+            let line = input.next_line()?;
+            *color = Palette::parse_rgb(&input, &line)?;
+        }
+        Ok(palette)
+    }
+
+    fn read_gimp(text: &str) -> Result<Palette, Error> {
+        let mut input = TextReader::new(PathBuf::new(), text);
+        input.expect_line("GIMP Palette")?;
+        let mut palette = Palette::default();
+        let mut count = 0;
+        while let Ok(line) = input.next_line() {
+            if line.starts_with('#') || line.starts_with("Columns:") {
+                continue;
+            }
+            if let Some(name) = line.strip_prefix("Name:") {
+                palette.name = format!("{}.PAL", name.trim().to_uppercase());
+                continue;
+            }
+            let color = Palette::parse_rgb(&input, &line)?;
+            if count == PALETTE_SIZE {
+                return Err(input.error("256 colours", "more"));
+            }
+            palette.colors[count] = color;
+            count += 1;
+        }
+        if count != PALETTE_SIZE {
+            return Err(input.error("256 colours", &count.to_string()));
+        }
+        Ok(palette)
+    }
+
+    fn read_act(data: &[u8]) -> Result<Palette, Error> {
+        let mut palette = Palette::default();
+        // Optional trailer holds colour count and transparent index.
+        let count = match data.len() {
+            768 => PALETTE_SIZE,
+            772 => usize::from(u16::from_be_bytes([data[768], data[769]])),
+            size => return Err(palette.error(format!("{} bytes, expected 768 or 772", size))),
+        };
+        if count != PALETTE_SIZE {
+            return Err(palette.error(format!("{} colours, expected {}", count, PALETTE_SIZE)));
+        }
+        for (color, rgb) in palette.colors.iter_mut().zip(data.chunks(3)) {
+            *color = [rgb[0], rgb[1], rgb[2], 255];
+        }
+        Ok(palette)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DRRENDER: &str = "DecodedData/DATA/REG/PALETTES/DRRENDER.PAL";
+
+    #[test]
+    fn test_load_game_palette() {
+        let palette = Palette::load_from(String::from(DRRENDER)).unwrap();
+        assert_eq!("DRRENDER.PAL", palette.name);
+        assert_eq!([0, 0, 0, 255], palette.rgba(0));
+        assert_eq!([0x5d, 0x03, 0x03, 255], palette.rgba(1));
+
+        // Written back it is the same file.
+        let mut data = Vec::new();
+        palette
+            .write_to(&mut data, PaletteFormat::Pixelmap)
+            .unwrap();
+        assert!(fs::read(DRRENDER).unwrap() == data);
+    }
+
+    #[test]
+    fn test_palette_formats_roundtrip() {
+        let mut palette = Palette::load_from(String::from(DRRENDER)).unwrap();
+        for format in [
+            PaletteFormat::Pixelmap,
+            PaletteFormat::Jasc,
+            PaletteFormat::Gimp,
+            PaletteFormat::Act,
+        ] {
+            let mut data = Vec::new();
+            palette.write_to(&mut data, format).unwrap();
+            assert_eq!(format, PaletteFormat::detect(&data));
+            let back = Palette::read_from(&mut data.as_slice(), format).unwrap();
+            assert_eq!(palette.colors(), back.colors(), "{:?}", format);
+        }
+
+        // Only the game format keeps alpha.
+        palette.set_rgba(5, [1, 2, 3, 4]);
+        let mut data = Vec::new();
+        palette.write_to(&mut data, PaletteFormat::Jasc).unwrap();
+        let back = Palette::read_from(&mut data.as_slice(), PaletteFormat::Jasc).unwrap();
+        assert_eq!([1, 2, 3, 255], back.rgba(5));
+    }
+
+    #[test]
+    fn test_palette_errors() {
+        let short_jasc = "JASC-PAL\n0100\n2\n0 0 0\n1 1 1\n";
+        assert!(matches!(
+            Palette::read_from(&mut short_jasc.as_bytes(), PaletteFormat::Jasc),
+            Err(Error::ParseText { line: 3, .. })
+        ));
+
+        let mut gimp = String::from("GIMP Palette\nName: Test\n#\n");
+        gimp.push_str(&"0 0 0 black\n".repeat(255));
+        gimp.push_str("0 300 0 not a colour\n");
+        assert!(matches!(
+            Palette::read_from(&mut gimp.as_bytes(), PaletteFormat::Gimp),
+            Err(Error::ParseText { line: 259, .. })
+        ));
+
+        assert!(matches!(
+            Palette::read_from(&mut [0u8; 700].as_slice(), PaletteFormat::Act),
+            Err(Error::BadPalette { .. })
+        ));
+        let mut act = vec![0u8; 768];
+        act.extend_from_slice(&[0, 16, 0xff, 0xff]);
+        assert!(matches!(
+            Palette::read_from(&mut act.as_slice(), PaletteFormat::Act),
+            Err(Error::BadPalette { .. })
+        ));
+    }
+}
@@ -7,29 +7,165 @@
 // (See file LICENSE_1_0.txt or a copy at http://www.boost.org/LICENSE_1_0.txt)
 //
 use {
-    crate::support::{actor::ActorNode, camera::CameraState, car::Car, Vertex},
-    cgmath::{prelude::*, Matrix4, Vector3},
+    crate::support::{
+        actor::ActorNode,
+        atlas::{Atlas, DEFAULT_ATLAS_SIZE, DEFAULT_PADDING},
+        camera::{box_corners, CameraState},
+        car::Car,
+        font::Font,
+        headup::{Hud, HudState},
+        material::MaterialProperties,
+        palette::Palette,
+        resolver,
+        texture::{PixelFormat, PixelMap, Texture, Transparency},
+        Error, Vertex,
+    },
+    cgmath::{prelude::*, Matrix4, Point3, Vector3},
     glium::{
-        self,
+        self, implement_vertex,
         index::*,
-        texture::{RawImage2d, SrgbTexture2d},
+        texture::{
+            ClientFormat, MipmapsOption, RawImage2d, SrgbTexture2d, Texture2d,
+            UncompressedFloatFormat,
+        },
         uniform,
         uniforms::*,
-        Display, IndexBuffer, Program, Surface, VertexBuffer,
+        Blend, Display, IndexBuffer, Program, Rect, Surface, VertexBuffer,
     },
     log::*,
-    std::{collections::HashMap, str, vec::Vec},
+    std::{borrow::Cow, collections::HashMap, str, vec::Vec},
 };
 
+/// How textures get their colours.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum RenderMode {
+    /// Textures remapped to RGBA and filtered.
+    #[default]
+    Truecolor,
+    /// 8-bit textures lit and depth cued through material shade tables,
+    /// like the original software renderer.
+    ShadeTable,
+}
+
+/// Texture a group of faces is drawn with.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum TextureSlot {
+    Blank,          // no material or pixelmap not found
+    Page(usize),    // atlas page of the car
+    Tiling(String), // repeating pixelmap kept out of the atlas
+    Colour(u8),     // palette colour of an untextured material
+    Environment,    // shared reflection of screen materials, see load_environment_map()
+}
+
+/// Faces drawn in one call, shade table only matters in ShadeTable mode.
+/// Material properties are looked up by name when drawing.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct DrawGroup {
+    texture: TextureSlot,
+    shade_table: Option<String>,
+    material: Option<String>,
+}
+
+/// Corner of a screen space quad, position in pixels from the top left.
+#[derive(Copy, Clone, Debug)]
+struct OverlayVertex {
+    position: [f32; 2],
+    tex_coords: [f32; 2],
+}
+
+implement_vertex!(OverlayVertex, position, tex_coords);
+
+struct FontTexture {
+    font: Font,
+    texture: SrgbTexture2d,
+    queued: Vec<OverlayVertex>, // glyph quads waiting for draw_queued_text()
+}
+
+/// Buffers reused for screen space quads, four vertices each.
+/// Indices of quads never change, only vertices are written every draw.
+struct QuadBuffers {
+    vertices: VertexBuffer<OverlayVertex>,
+    indices: IndexBuffer<u32>,
+}
+
+/// Head-up display as composed for the car and state.
+struct HudTexture {
+    car: String,
+    state: HudState,
+    texture: SrgbTexture2d,
+}
+
+struct ShadeTableTexture {
+    texture: Texture2d,
+    levels: i32,
+    identity_level: i32,
+}
+
 /// Provide storage for in-memory level-data - models, meshes, textures etc.
+/// Meshes are stored per car, because each car has its own atlas.
 pub struct RenderManager {
     vertices: HashMap<String, VertexBuffer<Vertex>>,
-    indices: HashMap<String, HashMap<u16, IndexBuffer<u16>>>, // MaterialId -> index buffer
-    bound_textures: HashMap<String, HashMap<u16, SrgbTexture2d>>, // MaterialId -> texture
+    mesh_bounds: HashMap<String, ([f32; 3], [f32; 3])>, // Axis-aligned, in mesh space
+    indices: HashMap<String, HashMap<DrawGroup, IndexBuffer<u16>>>,
+    bound_textures: HashMap<String, HashMap<TextureSlot, SrgbTexture2d>>, // Car name -> textures
+    index_textures: HashMap<String, HashMap<TextureSlot, Texture2d>>, // Car name -> 8-bit textures
+    palettes: HashMap<String, SrgbTexture2d>,                         // Car name -> palette
+    shade_tables: HashMap<String, ShadeTableTexture>,                 // Shared by table name
+    fonts: HashMap<String, FontTexture>,                              // Font name -> glyphs
+    hud: Option<HudTexture>,
+    quads: Option<QuadBuffers>,
+    environment: Option<SrgbTexture2d>,
+    environment_indices: Option<Texture2d>,
+    blank_texture: SrgbTexture2d,
     program: Program,
+    shaded_program: Program,
+    overlay_program: Program,
+    mode: RenderMode,
+    depth_cue: [f32; 2],
+}
+
+// Opaque black stand-in for untextured faces.
+fn black() -> Texture {
+    Texture {
+        w: 32,
+        h: 32,
+        format: PixelFormat::Rgba8888,
+        data: [0, 0, 0, 255].repeat(32 * 32),
+    }
 }
 
-fn debug_tree(name: &String, actor_name: &String, stack: &Vec<Matrix4<f32>>) {
+// Vertex shader source with functions shared by the car shaders
+// put right after its #version line.
+fn with_shared_functions(source: &str) -> String {
+    let shared = str::from_utf8(include_bytes!("../../shaders/reflection.glsl")).unwrap();
+    let (version, rest) = source.split_once('\n').unwrap();
+    format!("{}\n{}\n{}", version, shared, rest)
+}
+
+fn mesh_key(car: &Car, mesh_name: &str) -> String {
+    format!("{}/{}", car.name, mesh_name)
+}
+
+// Bounds met walking car actors, with the model transform in effect there:
+// actor bounding boxes and bounds of meshes to draw.
+struct Placed<'a> {
+    model: Matrix4<f32>,
+    min: [f32; 3],
+    max: [f32; 3],
+    mesh: Option<&'a String>,
+}
+
+fn vertex_bounds(vertices: &[Vertex]) -> Option<([f32; 3], [f32; 3])> {
+    let first = vertices.first()?.position;
+    Some(vertices.iter().fold((first, first), |(min, max), v| {
+        (
+            std::array::from_fn(|i| min[i].min(v.position[i])),
+            std::array::from_fn(|i| max[i].max(v.position[i])),
+        )
+    }))
+}
+
+fn debug_tree(name: &String, actor_name: &String, stack: &[Matrix4<f32>]) {
     debug!("{} for {}: stack depth {}", name, actor_name, stack.len());
     for x in stack.iter().rev() {
         debug!(".. {:?}", x);
@@ -41,21 +177,74 @@ fn debug_tree(name: &String, actor_name: &String, stack: &Vec<Matrix4<f32>>) {
 // Set up renderlist (VertexBuffer, IndexBuffer)
 impl RenderManager {
     pub fn new(display: &Display) -> Self {
-        let vertex_shader_src = str::from_utf8(include_bytes!("../../shaders/first.vert")).unwrap();
+        let vertex_shader_src = with_shared_functions(
+            str::from_utf8(include_bytes!("../../shaders/first.vert")).unwrap(),
+        );
         let fragment_shader_src =
             str::from_utf8(include_bytes!("../../shaders/first.frag")).unwrap();
 
+        let shaded_vertex_src = with_shared_functions(
+            str::from_utf8(include_bytes!("../../shaders/shaded.vert")).unwrap(),
+        );
+        let shaded_fragment_src =
+            str::from_utf8(include_bytes!("../../shaders/shaded.frag")).unwrap();
+
+        let overlay_vertex_src =
+            str::from_utf8(include_bytes!("../../shaders/overlay.vert")).unwrap();
+        let overlay_fragment_src =
+            str::from_utf8(include_bytes!("../../shaders/overlay.frag")).unwrap();
+
         Self {
             vertices: HashMap::new(),
+            mesh_bounds: HashMap::new(),
             indices: HashMap::new(),
             bound_textures: HashMap::new(),
-            program: Program::from_source(display, vertex_shader_src, fragment_shader_src, None)
+            index_textures: HashMap::new(),
+            palettes: HashMap::new(),
+            shade_tables: HashMap::new(),
+            fonts: HashMap::new(),
+            hud: None,
+            quads: None,
+            environment: None,
+            environment_indices: None,
+            blank_texture: RenderManager::upload_texture(&[black()], display),
+            program: Program::from_source(display, &vertex_shader_src, fragment_shader_src, None)
                 .unwrap(),
+            shaded_program: Program::from_source(
+                display,
+                &shaded_vertex_src,
+                shaded_fragment_src,
+                None,
+            )
+            .unwrap(),
+            overlay_program: Program::from_source(
+                display,
+                overlay_vertex_src,
+                overlay_fragment_src,
+                None,
+            )
+            .unwrap(),
+            mode: RenderMode::default(),
+            depth_cue: [10.0, 40.0],
         }
     }
 
+    pub fn mode(&self) -> RenderMode {
+        self.mode
+    }
+
+    pub fn set_mode(&mut self, mode: RenderMode) {
+        self.mode = mode;
+    }
+
+    /// Distances from the camera where depth cue starts darkening and where it reaches
+    /// the darkest shade table row.
+    pub fn set_depth_cue(&mut self, start: f32, end: f32) {
+        self.depth_cue = [start, end];
+    }
+
     fn debug_indices(&self) {
-        for (name, _indices) in &self.indices {
+        for name in self.indices.keys() {
             trace!("Indices for {}:", name);
             // for () in &indices {
             //     trace!("  ", )
@@ -63,132 +252,524 @@ impl RenderManager {
         }
     }
 
-    fn bind_default_texture(
-        textures: &mut HashMap<u16, SrgbTexture2d>,
-        mat: u16,
+    // Flip rows so v runs bottom-up like it did with from_raw_rgba_reversed.
+    fn flipped(level: &Texture, bpp: usize) -> Vec<u8> {
+        level
+            .data
+            .chunks(usize::from(level.w) * bpp)
+            .take(usize::from(level.h))
+            .rev()
+            .flatten()
+            .copied()
+            .collect()
+    }
+
+    // Upload RGBA texture with the given mip levels, a single level gets no mipmaps.
+    fn upload_texture(levels: &[Texture], display: &Display) -> SrgbTexture2d {
+        let (w, h) = (u32::from(levels[0].w), u32::from(levels[0].h));
+        let mipmaps = match levels.len() {
+            1 => MipmapsOption::NoMipmap,
+            _ => MipmapsOption::EmptyMipmaps,
+        };
+        let texture = SrgbTexture2d::empty_with_mipmaps(display, mipmaps, w, h).unwrap();
+        for (n, level) in levels.iter().enumerate() {
+            let image = RawImage2d::from_raw_rgba(
+                RenderManager::flipped(level, 4),
+                (u32::from(level.w), u32::from(level.h)),
+            );
+            let rect = Rect {
+                left: 0,
+                bottom: 0,
+                width: image.width,
+                height: image.height,
+            };
+            texture.mipmap(n as u32).unwrap().write(rect, image);
+        }
+        texture
+    }
+
+    // Single channel texture of 8-bit indices, read back exactly with nearest filtering.
+    fn upload_indices(levels: &[Texture], display: &Display) -> Texture2d {
+        let (w, h) = (u32::from(levels[0].w), u32::from(levels[0].h));
+        let mipmaps = match levels.len() {
+            1 => MipmapsOption::NoMipmap,
+            _ => MipmapsOption::EmptyMipmaps,
+        };
+        let texture =
+            Texture2d::empty_with_format(display, UncompressedFloatFormat::U8, mipmaps, w, h)
+                .unwrap();
+        for (n, level) in levels.iter().enumerate() {
+            let image = RawImage2d {
+                data: Cow::Owned(RenderManager::flipped(level, 1)),
+                width: u32::from(level.w),
+                height: u32::from(level.h),
+                format: ClientFormat::U8,
+            };
+            let rect = Rect {
+                left: 0,
+                bottom: 0,
+                width: image.width,
+                height: image.height,
+            };
+            texture.mipmap(n as u32).unwrap().write(rect, image);
+        }
+        texture
+    }
+
+    /// Upload glyphs of a font coloured through palette, text is then drawn with
+    /// queue_text() under the font name.
+    pub fn load_font(
+        &mut self,
+        font: Font,
+        palette: &Palette,
         display: &Display,
-    ) {
-        let black_data = [0; 32 * 32 * 4];
-        let black_image = RawImage2d::from_raw_rgba_reversed(&black_data, (32, 32));
-        let black_texture = SrgbTexture2d::new(display, black_image).unwrap();
-        textures.insert(mat, black_texture);
+    ) -> Result<(), Error> {
+        // Glyphs are drawn pixel for pixel, without mipmaps.
+        let level = font.glyphs.remap_via(palette)?.texture()?;
+        let texture = RenderManager::upload_texture(&[level], display);
+        self.fonts.insert(
+            font.name.clone(),
+            FontTexture {
+                font,
+                texture,
+                queued: Vec::new(),
+            },
+        );
+        Ok(())
     }
 
-    // @todo Prepare megatexture from all these small textures and keep a map
-    // of texture ID to the rect region, scale u,v appropriately in vertices.
-    // In theory, whole of the game could fit in 4096x4096 megatex.
-    fn bind_textures(&mut self, actor_name: &String, car: &Car, display: &Display) {
-        for (&mat, _) in &self.indices[actor_name] {
-            let textures = self
-                .bound_textures
-                .entry(actor_name.clone())
-                .or_insert(HashMap::new());
-            if mat == 0 {
-                RenderManager::bind_default_texture(textures, mat, display);
-            } else {
-                let material = &car.meshes[actor_name].material_names[(mat - 1) as usize];
-                trace!("Referred material {} index {}", material, mat);
-                if let Some(m) = car.materials.get(material) {
-                    trace!("Found material {}", m);
-                    let mut name = m.pixelmap_name.clone();
-                    if name.is_empty() {
-                        // @fixme hack
-                        name = material.replace(".MAT", ".pix").to_lowercase();
-                    }
-                    if let Some(tex) = car.textures.get(&name) {
-                        trace!("Found texture {}", tex);
-                        let image = RawImage2d::from_raw_rgba_reversed(
-                            &tex.data,
-                            (tex.w as u32, tex.h as u32),
-                        );
-                        let bound_texture = SrgbTexture2d::new(display, image).unwrap();
-                        textures.insert(mat, bound_texture);
-                    } else {
-                        RenderManager::bind_default_texture(textures, mat, display);
-                    }
+    /// Use pixelmap, coloured through palette, as the surroundings reflected by
+    /// screen materials of cars. They're drawn black until it's loaded.
+    pub fn load_environment_map(
+        &mut self,
+        pixelmap: &PixelMap,
+        palette: &Palette,
+        display: &Display,
+    ) -> Result<(), Error> {
+        let levels = pixelmap.remap_via(palette)?.mip_levels()?;
+        self.environment = Some(RenderManager::upload_texture(&levels, display));
+        self.environment_indices = match pixelmap.format {
+            PixelFormat::Index8 => Some(RenderManager::upload_indices(
+                &pixelmap.mip_levels()?,
+                display,
+            )),
+            _ => None,
+        };
+        Ok(())
+    }
+
+    /// Width in pixels of text drawn at scale 1, 0 for fonts that weren't loaded.
+    pub fn text_width(&self, font_name: &str, text: &str) -> u32 {
+        self.fonts
+            .get(font_name)
+            .map_or(0, |loaded| loaded.font.text_width(text))
+    }
+
+    /// Height in pixels of a line of text drawn at scale 1, 0 for fonts that weren't loaded.
+    pub fn line_height(&self, font_name: &str) -> u32 {
+        self.fonts
+            .get(font_name)
+            .map_or(0, |loaded| u32::from(loaded.font.height))
+    }
+
+    /// Queue a line of text to draw over the scene with its top left corner at position,
+    /// in pixels from the top left of the target. Each glyph pixel covers scale
+    /// pixels on screen. Text in fonts that weren't loaded is not drawn.
+    pub fn queue_text(&mut self, font_name: &str, text: &str, position: [f32; 2], scale: f32) {
+        let Some(FontTexture { font, queued, .. }) = self.fonts.get_mut(font_name) else {
+            trace!("No font {} to draw {:?}", font_name, text);
+            return;
+        };
+        let total = f32::from(font.glyphs.h);
+        let glyph_w = f32::from(font.glyphs.w);
+        let height = f32::from(font.height);
+
+        for glyph in font.layout(text) {
+            // Rows are flipped on upload, the first glyph is at the top of the texture.
+            let v_top = 1.0 - glyph.index as f32 * height / total;
+            let v_bottom = v_top - height / total;
+            let u_right = f32::from(glyph.w) / glyph_w;
+            let left = position[0] + glyph.x as f32 * scale;
+            let right = left + f32::from(glyph.w) * scale;
+            let top = position[1];
+            let bottom = top + height * scale;
+
+            queued.extend_from_slice(&[
+                OverlayVertex {
+                    position: [left, top],
+                    tex_coords: [0.0, v_top],
+                },
+                OverlayVertex {
+                    position: [right, top],
+                    tex_coords: [u_right, v_top],
+                },
+                OverlayVertex {
+                    position: [right, bottom],
+                    tex_coords: [u_right, v_bottom],
+                },
+                OverlayVertex {
+                    position: [left, bottom],
+                    tex_coords: [0.0, v_bottom],
+                },
+            ]);
+        }
+    }
+
+    /// Draw all queued text, with one draw call per font, and empty the queue.
+    pub fn draw_queued_text<T>(&mut self, display: &Display, target: &mut T)
+    where
+        T: Surface,
+    {
+        let most = self.fonts.values().map(|f| f.queued.len() / 4).max();
+        self.reserve_quads(display, most.unwrap_or(0));
+        for loaded in self.fonts.values().filter(|f| !f.queued.is_empty()) {
+            self.draw_quads(target, &loaded.texture, &loaded.queued);
+        }
+        for loaded in self.fonts.values_mut() {
+            loaded.queued.clear();
+        }
+    }
+
+    /// Draw head-up display of the car in state, stretched over the whole target.
+    /// It's composed and uploaded again only when the car or the state changes.
+    pub fn draw_hud<T>(
+        &mut self,
+        display: &Display,
+        target: &mut T,
+        car: &str,
+        hud: &Hud,
+        state: &HudState,
+    ) where
+        T: Surface,
+    {
+        let current = self
+            .hud
+            .as_ref()
+            .is_some_and(|cached| cached.car == car && cached.state == *state);
+        if !current {
+            self.hud = match hud.compose(state).texture() {
+                Ok(level) => Some(HudTexture {
+                    car: String::from(car),
+                    state: state.clone(),
+                    texture: RenderManager::upload_texture(&[level], display),
+                }),
+                Err(e) => {
+                    warn!("Head-up display not drawn: {}", e);
+                    None
                 }
-            }
+            };
+        }
+        self.reserve_quads(display, 1);
+        if let Some(cached) = &self.hud {
+            self.draw_overlay(target, &cached.texture);
         }
     }
 
-    pub fn prepare_car(&mut self, car: &Car, display: &Display) {
-        for actor in car.actors.traverse() {
-            match actor.data() {
-                &ActorNode::MeshfileRef(ref name) => {
-                    debug!("Actor meshfile {}", name);
-                    self.prepare_car_actor(name, car, display);
+    // Texture stretched over the whole target.
+    fn draw_overlay<T>(&self, target: &mut T, texture: &SrgbTexture2d)
+    where
+        T: Surface,
+    {
+        let (w, h) = target.get_dimensions();
+        let (w, h) = (w as f32, h as f32);
+        let vertices = [
+            OverlayVertex {
+                position: [0.0, 0.0],
+                tex_coords: [0.0, 1.0],
+            },
+            OverlayVertex {
+                position: [w, 0.0],
+                tex_coords: [1.0, 1.0],
+            },
+            OverlayVertex {
+                position: [w, h],
+                tex_coords: [1.0, 0.0],
+            },
+            OverlayVertex {
+                position: [0.0, h],
+                tex_coords: [0.0, 0.0],
+            },
+        ];
+        self.draw_quads(target, texture, &vertices);
+    }
+
+    // Make quad buffers hold at least count quads, they only ever grow.
+    fn reserve_quads(&mut self, display: &Display, count: usize) {
+        if self
+            .quads
+            .as_ref()
+            .is_some_and(|quads| quads.vertices.len() >= count * 4)
+        {
+            return;
+        }
+        let count = count.next_power_of_two().max(64);
+        let indices: Vec<u32> = (0..count as u32 * 4)
+            .step_by(4)
+            .flat_map(|base| [base, base + 1, base + 2, base, base + 2, base + 3])
+            .collect();
+        self.quads = Some(QuadBuffers {
+            vertices: VertexBuffer::empty_dynamic(display, count * 4).unwrap(),
+            indices: IndexBuffer::new(display, PrimitiveType::TrianglesList, &indices).unwrap(),
+        });
+    }
+
+    // Screen space quads with pixel art, drawn without filtering.
+    // Quad buffers must have been reserved for them.
+    fn draw_quads<T>(&self, target: &mut T, texture: &SrgbTexture2d, vertices: &[OverlayVertex])
+    where
+        T: Surface,
+    {
+        let quads = self.quads.as_ref().unwrap();
+        let vertex_slice = quads.vertices.slice(0..vertices.len()).unwrap();
+        vertex_slice.write(vertices);
+        let (w, h) = target.get_dimensions();
+        let uniforms = uniform! {
+            u_screen: [w as f32, h as f32],
+            glyphs: Sampler::new(texture)
+                .minify_filter(MinifySamplerFilter::Nearest)
+                .magnify_filter(MagnifySamplerFilter::Nearest)
+                .wrap_function(SamplerWrapFunction::Clamp),
+        };
+        // Drawn over everything, without touching depth.
+        let params = glium::DrawParameters {
+            blend: Blend::alpha_blending(),
+            ..Default::default()
+        };
+        target
+            .draw(
+                vertex_slice,
+                quads.indices.slice(0..vertices.len() / 4 * 6).unwrap(),
+                &self.overlay_program,
+                &uniforms,
+                &params,
+            )
+            .unwrap();
+    }
+
+    /// Pack car textures into an atlas and upload meshes of all its actors.
+    pub fn prepare_car(&mut self, car: &Car, display: &Display) -> Result<(), Error> {
+        let atlas = car.build_atlas(DEFAULT_ATLAS_SIZE, DEFAULT_PADDING)?;
+        let textures = self.bound_textures.entry(car.name.clone()).or_default();
+        for (id, page) in atlas.pages.iter().enumerate() {
+            debug!("Car {} atlas page {}: {}x{}", car.name, id, page.w, page.h);
+            textures.insert(
+                TextureSlot::Page(id),
+                RenderManager::upload_texture(&page.mip_levels(), display),
+            );
+        }
+
+        // Shade table mode needs 8-bit textures, cars that have others are drawn in truecolor.
+        match car.build_index_atlas(&atlas, DEFAULT_ATLAS_SIZE, DEFAULT_PADDING) {
+            Ok(indexed) => {
+                let textures = self.index_textures.entry(car.name.clone()).or_default();
+                for (id, page) in indexed.pages.iter().enumerate() {
+                    textures.insert(
+                        TextureSlot::Page(id),
+                        RenderManager::upload_indices(&page.mip_levels(), display),
+                    );
                 }
-                _ => (),
             }
+            Err(e) => warn!("Car {} has no shade table mode: {}", car.name, e),
         }
+        self.palettes.insert(
+            car.name.clone(),
+            RenderManager::upload_texture(
+                &[Texture {
+                    w: 256,
+                    h: 1,
+                    format: PixelFormat::Rgba8888,
+                    data: car.palette.to_rgba(),
+                }],
+                display,
+            ),
+        );
+        for (name, table) in &car.shade_tables {
+            self.shade_tables
+                .entry(name.clone())
+                .or_insert_with(|| ShadeTableTexture {
+                    texture: RenderManager::upload_indices(
+                        &[Texture {
+                            w: 256,
+                            h: table.levels,
+                            format: PixelFormat::Index8,
+                            data: table.data.clone(),
+                        }],
+                        display,
+                    ),
+                    levels: i32::from(table.levels),
+                    identity_level: i32::from(table.identity_level),
+                });
+        }
+
+        for actor in car.actors.traverse() {
+            if let ActorNode::MeshfileRef(name) = actor.data() {
+                debug!("Actor meshfile {}", name);
+                self.prepare_car_actor(name, car, &atlas, display)?;
+            }
+        }
+        Ok(())
     }
 
-    pub fn prepare_car_actor(&mut self, name: &String, car: &Car, display: &Display) {
+    pub fn prepare_car_actor(
+        &mut self,
+        name: &String,
+        car: &Car,
+        atlas: &Atlas,
+        display: &Display,
+    ) -> Result<(), Error> {
+        let key = mesh_key(car, name);
+        let mesh = &car.meshes[name];
+
         debug!("prepare_car_actor({}): loading vertices", name);
-        let vbo = VertexBuffer::<Vertex>::new(display, &car.meshes[name].vertices).unwrap();
-        self.vertices.insert(name.clone(), vbo);
+        let (vertices, faces) = car.render_vertices(mesh, atlas)?;
+        if let Some(bounds) = vertex_bounds(&vertices) {
+            self.mesh_bounds.insert(key.clone(), bounds);
+        }
+        let vbo = VertexBuffer::<Vertex>::new(display, &vertices).unwrap();
+        self.vertices.insert(key.clone(), vbo);
 
         debug!("prepare_car_actor({}): partitioning faces", name);
 
-        let faces = &car.meshes[name].faces;
-
-        let mut partitioned_by_material = HashMap::<u16, Vec<u16>>::new();
+        let textures = self.bound_textures.entry(car.name.clone()).or_default();
+        let index_textures = self.index_textures.entry(car.name.clone()).or_default();
+        let mut partitioned_by_texture = HashMap::<DrawGroup, Vec<u16>>::new();
 
-        for face in faces {
-            let indices = partitioned_by_material
-                .entry(face.material_id)
-                .or_insert(Vec::new());
+        for face in &faces {
+            let material = car.material(mesh, face.material_id);
+            let screen = material.is_some_and(|m| car.is_screen(m));
+            let slot = match car.material_surface(mesh, face.material_id) {
+                _ if screen => TextureSlot::Environment,
+                Some(resolver::Surface::Colour(index)) => {
+                    let slot = TextureSlot::Colour(index);
+                    if !textures.contains_key(&slot) {
+                        let [r, g, b, _] = car.palette.rgba(index);
+                        let texel = |format, data| Texture {
+                            w: 1,
+                            h: 1,
+                            format,
+                            data,
+                        };
+                        textures.insert(
+                            slot.clone(),
+                            RenderManager::upload_texture(
+                                &[texel(PixelFormat::Rgba8888, vec![r, g, b, 255])],
+                                display,
+                            ),
+                        );
+                        index_textures.insert(
+                            slot.clone(),
+                            RenderManager::upload_indices(
+                                &[texel(PixelFormat::Index8, vec![index])],
+                                display,
+                            ),
+                        );
+                    }
+                    slot
+                }
+                Some(resolver::Surface::Pixelmap(pixmap)) if pixmap.w > 0 && pixmap.h > 0 => {
+                    match atlas.get(&pixmap.name) {
+                        Some(r) => TextureSlot::Page(r.id as usize),
+                        None => {
+                            let slot = TextureSlot::Tiling(pixmap.name.clone());
+                            if !textures.contains_key(&slot) {
+                                trace!("Binding tiling texture {}", pixmap);
+                                match pixmap.mip_levels() {
+                                    Ok(levels) => {
+                                        textures.insert(
+                                            slot.clone(),
+                                            RenderManager::upload_texture(&levels, display),
+                                        );
+                                    }
+                                    Err(e) => {
+                                        warn!("Tiling texture left black: {}", e);
+                                        textures.insert(
+                                            slot.clone(),
+                                            RenderManager::upload_texture(&[black()], display),
+                                        );
+                                    }
+                                }
+                                let indexed = car.indexed_textures.get(&pixmap.name);
+                                if let Some(Ok(levels)) = indexed.map(PixelMap::mip_levels) {
+                                    index_textures.insert(
+                                        slot.clone(),
+                                        RenderManager::upload_indices(&levels, display),
+                                    );
+                                }
+                            }
+                            slot
+                        }
+                    }
+                }
+                _ => TextureSlot::Blank,
+            };
+            let group = DrawGroup {
+                texture: slot,
+                shade_table: car
+                    .material_shade_table(mesh, face.material_id)
+                    .map(|table| table.name.clone()),
+                material: material.map(|m| m.name.clone()),
+            };
+            let indices = partitioned_by_texture.entry(group).or_default();
             indices.push(face.v1);
             indices.push(face.v2);
             indices.push(face.v3);
         }
 
-        for (mat, list) in &partitioned_by_material {
+        for (group, list) in &partitioned_by_texture {
             debug!(
-                "Material {}: {} vertices, {} faces",
-                mat,
+                "{:?}: {} vertices, {} faces",
+                group,
                 list.len(),
                 list.len() as f32 / 3f32
             );
         }
 
         self.indices.insert(
-            name.clone(),
-            partitioned_by_material
+            key,
+            partitioned_by_texture
                 .iter()
-                .map(|(key, item)| {
+                .map(|(group, item)| {
                     (
-                        *key,
-                        IndexBuffer::new(display, PrimitiveType::TrianglesList, &item).unwrap(),
+                        group.clone(),
+                        IndexBuffer::new(display, PrimitiveType::TrianglesList, item).unwrap(),
                     )
                 })
                 .collect(),
         );
-
-        // each material from partitioned_by_material - load and bind it in bound_textures
-        // then remap to a set of HashMap from String material name to Vec<u16> indices
-        self.bind_textures(name, car, display);
+        Ok(())
     }
 
-    /// Draw all visible actors
-    pub fn draw_car<T>(&self, car: &Car, target: &mut T, camera: &CameraState)
-    where
-        T: Surface,
-    {
+    /// Walk car actors the way draw_car() places them. With a camera, actors whose
+    /// bounding box is out of its view are left out with their children, and so are
+    /// meshes out of view.
+    fn place_car<'a>(&self, car: &'a Car, camera: Option<&CameraState>) -> Vec<Placed<'a>> {
+        let mut placed = Vec::new();
         let mut v = false;
         let mut transform_stack = Vec::<Matrix4<f32>>::new();
         transform_stack.push(Matrix4::from_translation(car.base_translation) * Matrix4::identity());
 
         let mut actor_name = String::new();
+        // Depth of the actor out of view, until the walk leaves its children.
+        let mut culled = None;
+        let in_view = |model: &Matrix4<f32>, min, max| {
+            camera.is_none_or(|camera| camera.in_view(model, min, max))
+        };
 
         for actor in car.actors.traverse() {
             match actor.data() {
-                &ActorNode::Actor { ref name, visible } => {
+                &ActorNode::Actor {
+                    ref name,
+                    actor_type,
+                    ..
+                } => {
                     actor_name = name.clone();
-                    v = visible;
+                    v = actor_type == 1;
 
                     let depth = car.actors.get_node_depth(actor) - 1;
                     trace!("Actor {} depth {}", name, depth);
+                    if culled.is_some_and(|culled| depth <= culled) {
+                        culled = None;
+                    }
                     if depth < transform_stack.len() {
                         let pop_count = transform_stack.len() - depth;
                         trace!("Restoring transform - {} times", pop_count);
@@ -199,11 +780,33 @@ impl RenderManager {
 
                     debug_tree(&String::from("Actor"), &actor_name, &transform_stack);
                 }
-                &ActorNode::MeshfileRef(ref name) => {
+                &ActorNode::BoundingBox { min, max } if culled.is_none() => {
+                    let model = *transform_stack.last().unwrap();
+                    if !in_view(&model, min, max) {
+                        trace!("Actor {} out of view", actor_name);
+                        culled = Some(car.actors.get_node_depth(actor) - 2);
+                        continue;
+                    }
+                    placed.push(Placed {
+                        model,
+                        min,
+                        max,
+                        mesh: None,
+                    });
+                }
+                ActorNode::MeshfileRef(name) if culled.is_none() => {
                     debug_tree(&format!("Mesh {}", name), &actor_name, &transform_stack);
-                    if v {
-                        trace!("Drawing actor {}", name);
-                        self.draw_actor(name, &transform_stack.last().unwrap(), target, camera);
+                    let model = *transform_stack.last().unwrap();
+                    let bounds = self.mesh_bounds.get(&mesh_key(car, name));
+                    if let (true, Some(&(min, max))) = (v, bounds) {
+                        if in_view(&model, min, max) {
+                            placed.push(Placed {
+                                model,
+                                min,
+                                max,
+                                mesh: Some(name),
+                            });
+                        }
                     }
                 }
                 &ActorNode::Transform(t) => {
@@ -225,11 +828,41 @@ impl RenderManager {
                 _ => (),
             }
         }
+        placed
+    }
+
+    /// Axis-aligned bounds of a prepared car in world space, at its base translation.
+    pub fn car_bounds(&self, car: &Car) -> Option<([f32; 3], [f32; 3])> {
+        let corners = self
+            .place_car(car, None)
+            .into_iter()
+            .flat_map(|p| box_corners(p.min, p.max).map(|c| p.model.transform_point(c)));
+        corners.fold(None, |bounds, c: Point3<f32>| {
+            let (min, max) = bounds.unwrap_or((c.into(), c.into()));
+            Some((
+                std::array::from_fn(|i| min[i].min(c[i])),
+                std::array::from_fn(|i| max[i].max(c[i])),
+            ))
+        })
     }
 
-    /// Uses single mesh, but specific indices to draw with each material.
+    /// Draw all visible actors, leaving out those out of camera view.
+    pub fn draw_car<T>(&self, car: &Car, target: &mut T, camera: &CameraState)
+    where
+        T: Surface,
+    {
+        for placed in self.place_car(car, Some(camera)) {
+            if let Some(name) = placed.mesh {
+                trace!("Drawing actor {}", name);
+                self.draw_actor(car, name, &placed.model, target, camera);
+            }
+        }
+    }
+
+    /// Uses single mesh, but specific indices to draw with each texture.
     fn draw_actor<T>(
         &self,
+        car: &Car,
         mesh_name: &String,
         model: &Matrix4<f32>,
         target: &mut T,
@@ -243,35 +876,133 @@ impl RenderManager {
         let light = [-5.0, 5.0, 10.0f32];
         // Ambient lighting: 0.5, 0.5, 0.5, 1.0
 
-        let params = glium::DrawParameters {
-            depth: glium::Depth {
-                test: glium::draw_parameters::DepthTest::IfLess,
-                write: true,
+        let model: [[f32; 4]; 4] = (*model).into();
+
+        let key = mesh_key(car, mesh_name);
+        for (group, indices) in &self.indices[&key] {
+            let material = group
+                .material
+                .as_ref()
+                .and_then(|name| car.materials.get(name));
+            let reflects = material.is_some_and(|m| car.reflects(m));
+            // Screens show the environment map once, their own transform is for the game's sky.
+            let uv_transform = match material {
+                Some(m) if !car.is_screen(m) => m.properties.uv_transform(),
+                _ => MaterialProperties::default().uv_transform(),
+            };
+            let material = material.map(|m| m.properties).unwrap_or_default();
+            let params = glium::DrawParameters {
+                depth: glium::Depth {
+                    test: glium::draw_parameters::DepthTest::IfLess,
+                    write: !material.transparent(),
+                    ..Default::default()
+                },
+                backface_culling: if material.two_sided() {
+                    glium::draw_parameters::BackfaceCullingMode::CullingDisabled
+                } else {
+                    glium::draw_parameters::BackfaceCullingMode::CullClockwise
+                },
+                blend: if material.transparent() {
+                    Blend::alpha_blending()
+                } else {
+                    Blend::default()
+                },
                 ..Default::default()
-            },
-            backface_culling: glium::draw_parameters::BackfaceCullingMode::CullClockwise,
-            ..Default::default()
-        };
+            };
 
-        let model: [[f32; 4]; 4] = model.clone().into();
+            let slot = &group.texture;
+            let (texture, wrap) = match slot {
+                TextureSlot::Blank => (&self.blank_texture, SamplerWrapFunction::Repeat),
+                TextureSlot::Page(_) => (
+                    &self.bound_textures[&car.name][slot],
+                    SamplerWrapFunction::Clamp,
+                ),
+                TextureSlot::Tiling(_) | TextureSlot::Colour(_) => (
+                    &self.bound_textures[&car.name][slot],
+                    SamplerWrapFunction::Repeat,
+                ),
+                TextureSlot::Environment => (
+                    self.environment.as_ref().unwrap_or(&self.blank_texture),
+                    SamplerWrapFunction::Repeat,
+                ),
+            };
+            let shaded = match (self.mode, &group.shade_table) {
+                (RenderMode::ShadeTable, Some(table)) => match slot {
+                    TextureSlot::Environment => self.environment_indices.as_ref(),
+                    _ => self
+                        .index_textures
+                        .get(&car.name)
+                        .and_then(|textures| textures.get(slot)),
+                }
+                .zip(self.shade_tables.get(table))
+                .zip(self.palettes.get(&car.name)),
+                _ => None,
+            };
+            if let Some(((index_texture, table), palette)) = shaded {
+                let nearest = |texture| {
+                    Sampler::new(texture)
+                        .minify_filter(MinifySamplerFilter::Nearest)
+                        .magnify_filter(MagnifySamplerFilter::Nearest)
+                };
+                let uniforms = uniform! {
+                    model: model,
+                    view: camera.get_view(),
+                    perspective: camera.get_perspective(),
+                    u_light: light,
+                    u_ambient: 0.5f32,
+                    u_uv_transform: uv_transform,
+                    u_environment: reflects,
+                    // Indices can't be blended, but picking from smaller levels still
+                    // keeps distant cars from shimmering.
+                    index_tex: nearest(index_texture)
+                        .minify_filter(MinifySamplerFilter::NearestMipmapNearest)
+                        .wrap_function(wrap),
+                    shade_tab: nearest(&table.texture),
+                    palette: Sampler::new(palette)
+                        .minify_filter(MinifySamplerFilter::Nearest)
+                        .magnify_filter(MagnifySamplerFilter::Nearest),
+                    u_levels: table.levels,
+                    u_identity: table.identity_level,
+                    u_color_key: Transparency::default().color_key.map_or(-1, i32::from),
+                    u_cue_start: self.depth_cue[0],
+                    u_cue_end: self.depth_cue[1],
+                };
+                target
+                    .draw(
+                        &self.vertices[&key],
+                        indices,
+                        &self.shaded_program,
+                        &uniforms,
+                        &params,
+                    )
+                    .unwrap();
+                continue;
+            }
 
-        for (mat, indices) in &self.indices[mesh_name] {
             let uniforms = uniform! {
                 model: model,
                 view: camera.get_view(),
                 perspective: camera.get_perspective(),
                 u_light: light,
+                u_lit: material.lit(),
+                u_ambient: material.ambient,
+                u_diffuse: material.diffuse,
+                u_specular: material.specular,
+                u_power: material.power,
                 u_specular_color: [1.0, 1.0, 1.0f32],
-                diffuse_tex: Sampler::new(&self.bound_textures[mesh_name][mat])
-                    .minify_filter(MinifySamplerFilter::Linear)
+                u_opacity: f32::from(material.opacity) / 255.0,
+                u_uv_transform: uv_transform,
+                u_environment: reflects,
+                diffuse_tex: Sampler::new(texture)
+                    .minify_filter(MinifySamplerFilter::LinearMipmapLinear)
                     .magnify_filter(MagnifySamplerFilter::Linear)
-                    .wrap_function(SamplerWrapFunction::Repeat),
+                    .wrap_function(wrap),
                 // normal_tex: &self.bound_textures[mat],
             };
 
             target
                 .draw(
-                    &self.vertices[mesh_name],
+                    &self.vertices[&key],
                     indices,
                     &self.program,
                     &uniforms,
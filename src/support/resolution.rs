@@ -0,0 +1,223 @@
+//
+// Part of Roadkill Project.
+//
+// Copyright 2010, 2017, Stanislav Karchebnyy <berkus@madfire.net>
+//
+// Distributed under the Boost Software License, Version 1.0.
+// (See file LICENSE_1_0.txt or a copy at http://www.boost.org/LICENSE_1_0.txt)
+//
+use {
+    crate::support::find_ignoring_case,
+    std::{
+        fmt,
+        path::{Path, PathBuf},
+        str::FromStr,
+    },
+};
+
+/// Screen resolution the assets are picked for.
+/// The 320x200 assets make up the base DATA tree, higher resolutions keep
+/// their own PIXELMAP, FONTS, HEADUP.ENC and cockpit descriptions in CARS
+/// under a subdirectory of DATA and share everything else with the base tree.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub enum ResolutionProfile {
+    #[default]
+    Lowres, // 320x200
+    Hires, // 640x480, DATA/64X48X8
+}
+
+impl ResolutionProfile {
+    pub const ALL: [ResolutionProfile; 2] = [ResolutionProfile::Lowres, ResolutionProfile::Hires];
+
+    /// Subdirectory of DATA with assets overriding the base tree.
+    pub fn variant_dir(self) -> Option<&'static str> {
+        match self {
+            ResolutionProfile::Lowres => None,
+            ResolutionProfile::Hires => Some("64X48X8"),
+        }
+    }
+
+    /// Screen width and height the assets are drawn for.
+    pub fn screen_size(self) -> (u16, u16) {
+        match self {
+            ResolutionProfile::Lowres => (320, 200),
+            ResolutionProfile::Hires => (640, 480),
+        }
+    }
+
+    /// Which of the three lists of pixelmaps and of materials in car descriptions
+    /// is for this resolution, the first ones are for low memory machines.
+    pub fn car_asset_set(self) -> usize {
+        match self {
+            ResolutionProfile::Lowres => 1,
+            ResolutionProfile::Hires => 2,
+        }
+    }
+
+    /// DATA directory of a file in the base tree or in any variant of it,
+    /// e.g. DATA for both DATA/CARS/EAGLE.ENC and DATA/64X48X8/CARS/EAGLE.ENC.
+    pub fn data_root(filepath: &Path) -> PathBuf {
+        let mut root = PathBuf::from(filepath);
+        root.pop(); // remove file name
+        root.pop(); // remove its directory
+        let variant = root.file_name().and_then(|dir| dir.to_str());
+        if variant.is_some_and(|dir| {
+            ResolutionProfile::ALL
+                .iter()
+                .any(|profile| profile.variant_dir() == Some(dir))
+        }) {
+            root.pop();
+        }
+        root
+    }
+
+    /// File relative to the DATA directory root, from the variant tree when it has one,
+    /// otherwise from the base tree, even if it's not there to report it missing.
+    /// File names match ignoring case.
+    pub fn find(self, root: &Path, relative: &Path) -> PathBuf {
+        self.variant_dir()
+            .and_then(|variant| find_ignoring_case(&root.join(variant).join(relative)))
+            .or_else(|| find_ignoring_case(&root.join(relative)))
+            .unwrap_or_else(|| root.join(relative))
+    }
+
+    /// Like path_subst(), but in the DATA directory of filepath and with overrides
+    /// of this profile. Directories may be nested, like REG/PALETTES.
+    pub fn resolve(self, filepath: &Path, newdir: &Path, newext: Option<String>) -> PathBuf {
+        match relative(filepath, newdir, newext) {
+            Some(relative) => self.find(&ResolutionProfile::data_root(filepath), &relative),
+            None => filepath.to_path_buf(),
+        }
+    }
+
+    /// Like resolve(), but in the base tree only, for descriptions that mean something
+    /// else in variants, like CARS with whole cars in DATA and only cockpits in DATA/64X48X8.
+    pub fn resolve_base(filepath: &Path, newdir: &Path, newext: Option<String>) -> PathBuf {
+        ResolutionProfile::Lowres.resolve(filepath, newdir, newext)
+    }
+}
+
+// File name of filepath in newdir, None when there's no file name (like "..").
+fn relative(filepath: &Path, newdir: &Path, newext: Option<String>) -> Option<PathBuf> {
+    let mut relative = newdir.join(filepath.file_name()?);
+    if let Some(ext) = newext {
+        relative.set_extension(ext);
+    }
+    Some(relative)
+}
+
+impl fmt::Display for ResolutionProfile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ResolutionProfile::Lowres => write!(f, "320x200"),
+            ResolutionProfile::Hires => write!(f, "640x480"),
+        }
+    }
+}
+
+/// Parses resolutions like "640x480" and variant directory names like "64X48X8".
+impl FromStr for ResolutionProfile {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        ResolutionProfile::ALL
+            .into_iter()
+            .find(|profile| {
+                profile.to_string().eq_ignore_ascii_case(s)
+                    || profile
+                        .variant_dir()
+                        .is_some_and(|dir| dir.eq_ignore_ascii_case(s))
+            })
+            .ok_or_else(|| format!("unknown resolution {}, expected 320x200 or 640x480", s))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CAR: &str = "DecodedData/DATA/CARS/BLKEAGLE.ENC";
+
+    #[test]
+    fn test_data_root() {
+        let root = PathBuf::from("DecodedData/DATA");
+        assert_eq!(root, ResolutionProfile::data_root(Path::new(CAR)));
+        assert_eq!(
+            root,
+            ResolutionProfile::data_root(Path::new("DecodedData/DATA/64X48X8/CARS/BLKEAGLE.ENC"))
+        );
+        assert_eq!(
+            PathBuf::new(),
+            ResolutionProfile::data_root(Path::new("BLKEAGLE.ENC"))
+        );
+    }
+
+    #[test]
+    fn test_resolve_overrides() {
+        let car = Path::new(CAR);
+        let pixelmap = Path::new("PIXELMAP");
+        let icons = Path::new("DecodedData/DATA/CARS/CARICONS.PIX");
+        assert_eq!(
+            PathBuf::from("DecodedData/DATA/PIXELMAP/CARICONS.PIX"),
+            ResolutionProfile::Lowres.resolve(icons, pixelmap, None)
+        );
+        assert_eq!(
+            PathBuf::from("DecodedData/DATA/64X48X8/PIXELMAP/CARICONS.PIX"),
+            ResolutionProfile::Hires.resolve(icons, pixelmap, None)
+        );
+        // Car textures only come in one resolution.
+        assert_eq!(
+            PathBuf::from("DecodedData/DATA/PIXELMAP/EAGREDL.PIX"),
+            ResolutionProfile::Hires.resolve(&car.with_file_name("EAGREDL.PIX"), pixelmap, None)
+        );
+        assert_eq!(
+            PathBuf::from("DecodedData/DATA/REG/PALETTES/DRRENDER.PAL"),
+            ResolutionProfile::Hires.resolve(
+                &car.with_file_name("DRRENDER.PAL"),
+                Path::new("REG/PALETTES"),
+                None
+            )
+        );
+        // Variant cars are cockpits, the car itself stays in the base tree.
+        assert_eq!(
+            PathBuf::from(CAR),
+            ResolutionProfile::resolve_base(
+                Path::new("DecodedData/DATA/64X48X8/CARS/BLKEAGLE.TXT"),
+                Path::new("CARS"),
+                Some(String::from("ENC"))
+            )
+        );
+
+        let root = Path::new("DecodedData/DATA");
+        assert_eq!(
+            PathBuf::from("DecodedData/DATA/64X48X8/HEADUP.ENC"),
+            ResolutionProfile::Hires.find(root, Path::new("HEADUP.ENC"))
+        );
+        assert!(!ResolutionProfile::Lowres
+            .find(root, Path::new("HEADUP.ENC"))
+            .exists());
+    }
+
+    #[test]
+    fn test_find_ignoring_case() {
+        // EAGYELL.ENC asks for EAGYELl.PIX.
+        let root = Path::new("DecodedData/DATA");
+        assert_eq!(
+            PathBuf::from("DecodedData/DATA/PIXELMAP/EAGYELL.PIX"),
+            ResolutionProfile::Lowres.find(root, Path::new("PIXELMAP/EAGYELl.PIX"))
+        );
+        assert_eq!(
+            PathBuf::from("DecodedData/DATA/PIXELMAP/NOSUCH.PIX"),
+            ResolutionProfile::Hires.find(root, Path::new("PIXELMAP/NOSUCH.PIX"))
+        );
+    }
+
+    #[test]
+    fn test_parse_profile() {
+        for profile in ResolutionProfile::ALL {
+            assert_eq!(Ok(profile), profile.to_string().parse());
+        }
+        assert_eq!(Ok(ResolutionProfile::Hires), "64x48x8".parse());
+        assert!("800x600".parse::<ResolutionProfile>().is_err());
+    }
+}
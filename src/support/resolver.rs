@@ -0,0 +1,138 @@
+//
+// Part of Roadkill Project.
+//
+// Copyright 2010, 2017, Stanislav Karchebnyy <berkus@madfire.net>
+//
+// Distributed under the Boost Software License, Version 1.0.
+// (See file LICENSE_1_0.txt or a copy at http://www.boost.org/LICENSE_1_0.txt)
+//
+use {
+    crate::support::{material::Material, texture::PixelMap},
+    std::{collections::HashMap, fmt, path::PathBuf},
+};
+
+/// Reference from a car to something that isn't there, surfaces using it are drawn blank.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Unresolved {
+    /// File listed in the car description can't be loaded.
+    File { path: PathBuf, error: String },
+    /// Mesh uses a material none of the loaded material files define.
+    Material { mesh: String, material: String },
+    /// Material names a pixelmap none of the loaded pixelmap files have.
+    Pixelmap { material: String, pixelmap: String },
+    /// Material names a shade table that wasn't loaded.
+    ShadeTable { material: String, table: String },
+}
+
+impl fmt::Display for Unresolved {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Unresolved::File { path, error } => write!(f, "{}: {}", path.display(), error),
+            Unresolved::Material { mesh, material } => {
+                write!(f, "mesh {} uses unknown material {}", mesh, material)
+            }
+            Unresolved::Pixelmap { material, pixelmap } => {
+                write!(
+                    f,
+                    "material {} uses unknown pixelmap {}",
+                    material, pixelmap
+                )
+            }
+            Unresolved::ShadeTable { material, table } => {
+                write!(
+                    f,
+                    "material {} uses unknown shade table {}",
+                    material, table
+                )
+            }
+        }
+    }
+}
+
+/// Value of name, matched exactly or otherwise ignoring case, like the game
+/// running on DOS found them. Pixelmap files often name their pixelmaps in
+/// lower case while materials refer to them in upper case.
+pub fn lookup<'a, T>(map: &'a HashMap<String, T>, name: &str) -> Option<&'a T> {
+    map.get(name).or_else(|| {
+        map.iter()
+            .filter(|(key, _)| key.eq_ignore_ascii_case(name))
+            .min_by_key(|(key, _)| key.as_str())
+            .map(|(_, value)| value)
+    })
+}
+
+/// What faces with a material are drawn with.
+#[derive(Clone, Copy)]
+pub enum Surface<'a> {
+    Pixelmap(&'a PixelMap),
+    /// Materials without a pixelmap are lit along a ramp of palette colours
+    /// from index_base, this is the fully lit end of it.
+    Colour(u8),
+}
+
+/// Surface of a material, None when its pixelmap is missing.
+pub fn surface<'a>(
+    material: &Material,
+    pixelmaps: &'a HashMap<String, PixelMap>,
+) -> Option<Surface<'a>> {
+    if material.pixelmap_name.is_empty() {
+        let props = &material.properties;
+        return Some(Surface::Colour(
+            props.index_base.saturating_add(props.index_range),
+        ));
+    }
+    lookup(pixelmaps, &material.pixelmap_name).map(Surface::Pixelmap)
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, crate::support::material::MaterialProperties};
+
+    #[test]
+    fn test_lookup_ignoring_case() {
+        let map: HashMap<_, _> = [("bglside.pix", 1), ("BGLTOP.PIX", 2), ("BglTop.pix", 3)]
+            .into_iter()
+            .map(|(k, v)| (String::from(k), v))
+            .collect();
+        assert_eq!(Some(&1), lookup(&map, "BGLSIDE.PIX"));
+        assert_eq!(Some(&3), lookup(&map, "BglTop.pix"));
+        // Ambiguous matches always pick the same one.
+        assert_eq!(Some(&2), lookup(&map, "bgltop.pix"));
+        assert_eq!(None, lookup(&map, "BGLSIDE"));
+    }
+
+    #[test]
+    fn test_material_surface() {
+        let pixelmaps: HashMap<_, _> = [(
+            String::from("tyre.pix"),
+            PixelMap {
+                name: String::from("tyre.pix"),
+                ..Default::default()
+            },
+        )]
+        .into_iter()
+        .collect();
+        let mut material = Material {
+            name: String::from("TYRE.MAT"),
+            properties: MaterialProperties {
+                index_base: 240,
+                index_range: 3,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        // No pixelmap, even if there's one named like the material.
+        assert!(matches!(
+            surface(&material, &pixelmaps),
+            Some(Surface::Colour(243))
+        ));
+
+        material.pixelmap_name = String::from("TYRE.PIX");
+        assert!(matches!(
+            surface(&material, &pixelmaps),
+            Some(Surface::Pixelmap(pm)) if pm.name == "tyre.pix"
+        ));
+        material.pixelmap_name = String::from("WHEEL.PIX");
+        assert!(surface(&material, &pixelmaps).is_none());
+    }
+}
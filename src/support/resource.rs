@@ -9,37 +9,360 @@
 use {
     crate::support::{
         self,
+        material::MaterialProperties,
         mesh::{Face, UvCoord},
-        read_c_string, Error, Vertex,
+        read_c_string, write_c_string, Error, Vertex,
     },
-    byteorder::{BigEndian, ReadBytesExt},
+    byteorder::{BigEndian, ReadBytesExt, WriteBytesExt},
     log::*,
-    std::io::BufRead,
+    std::{
+        collections::HashMap,
+        io::{ErrorKind, Read, Write},
+        path::PathBuf,
+    },
 };
 
 // A binary resource file consisting of chunks with specific size.
 // Reading from such file yields chunk results, some of these chunks are service,
 // some are useful to the client.
 #[derive(Default)]
-struct ChunkHeader {
-    chunk_type: u32,
-    size: u32, // size of chunk -4
+pub struct ChunkHeader {
+    pub chunk_type: u32,
+    pub size: u32, // size of chunk -4
 }
 
 impl ChunkHeader {
     pub fn load<R: ReadBytesExt>(source: &mut R) -> Result<ChunkHeader, Error> {
-        let mut h = ChunkHeader::default();
-        h.chunk_type = source.read_u32::<BigEndian>()?;
-        h.size = source.read_u32::<BigEndian>()?;
-        debug!("Loaded chunk type {} size {}", h.chunk_type, h.size);
-        Ok(h)
+        let chunk_type = source.read_u32::<BigEndian>()?;
+        let size = source.read_u32::<BigEndian>()?;
+        debug!("Loaded chunk type {} size {}", chunk_type, size);
+        Ok(ChunkHeader { chunk_type, size })
+    }
+
+    pub fn write<W: WriteBytesExt>(&self, dest: &mut W) -> Result<(), Error> {
+        dest.write_u32::<BigEndian>(self.chunk_type)?;
+        dest.write_u32::<BigEndian>(self.size)?;
+        Ok(())
+    }
+}
+
+/// What typed loaders do with chunks they do not expect in their file type,
+/// including chunk types unknown to the reader.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Strictness {
+    /// Fail loading on the first unexpected chunk.
+    Strict,
+    /// Log and drop unexpected chunks.
+    #[default]
+    Skip,
+    /// Keep unexpected chunks with the loaded object, they are written back on save.
+    Keep,
+}
+
+impl Strictness {
+    /// Returns the chunk back if loader should keep it.
+    /// Offset of the chunk is used for error reporting.
+    pub fn check(self, offset: u64, chunk: Chunk) -> Result<Option<Chunk>, Error> {
+        match self {
+            Strictness::Strict => Err(Error::UnexpectedChunk {
+                file: PathBuf::new(),
+                offset,
+                chunk_type: chunk.chunk_type(),
+            }),
+            Strictness::Skip => {
+                warn!(
+                    "Skipping unexpected chunk type {:#x} at offset {:#x}",
+                    chunk.chunk_type(),
+                    offset
+                );
+                Ok(None)
+            }
+            Strictness::Keep => Ok(Some(chunk)),
+        }
+    }
+}
+
+// Counts and sizes in chunks come straight from the file and may be garbage.
+// Never preallocate more than this many elements, vectors grow as the data actually arrives.
+const MAX_PREALLOC: usize = 0x10000;
+
+fn capacity(n: u32) -> usize {
+    (n as usize).min(MAX_PREALLOC)
+}
+
+/// Make sure `n` list elements of `element` bytes, after `fixed` bytes of list header,
+/// fit into the chunk. Only for lists of fixed size elements, game files have the size
+/// wrong for chunks with strings.
+fn check_count(header: &ChunkHeader, n: u32, fixed: u64, element: u64) -> Result<(), Error> {
+    let needed = fixed + u64::from(n) * element;
+    if needed > u64::from(header.size) {
+        return Err(Error::CorruptChunk {
+            file: PathBuf::new(),
+            offset: 0,
+            chunk_type: header.chunk_type,
+            reason: format!(
+                "{} entries need {} bytes, chunk size is {}",
+                n, needed, header.size
+            ),
+        });
+    }
+    Ok(())
+}
+
+/// Read exactly `len` bytes, allocating only as much as the source actually has.
+fn read_payload<R: Read>(source: &mut R, len: u64) -> Result<Vec<u8>, Error> {
+    let mut payload = Vec::<u8>::with_capacity((len as usize).min(MAX_PREALLOC));
+    source.take(len).read_to_end(&mut payload)?;
+    if payload.len() as u64 != len {
+        return Err(Error::IO(ErrorKind::UnexpectedEof.into()));
+    }
+    Ok(payload)
+}
+
+// Counts bytes read through it, so chunk offsets are known without Seek.
+struct CountingReader<R> {
+    inner: R,
+    count: u64,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+}
+
+/// Streaming reader of chunks together with their byte offsets in the stream.
+/// Iterates over all chunks until a clean end of file between chunks, stops after the first error.
+/// Reads exactly as much as the chunks take, wrap unbuffered sources in a BufReader.
+pub struct ChunkReader<R> {
+    reader: CountingReader<R>,
+    peeked: Option<(u64, Chunk)>,
+    failed: bool,
+}
+
+impl<R: Read> ChunkReader<R> {
+    pub fn new(reader: R) -> Self {
+        ChunkReader {
+            reader: CountingReader {
+                inner: reader,
+                count: 0,
+            },
+            peeked: None,
+            failed: false,
+        }
+    }
+
+    /// Offset of the next chunk to be returned, counted from where reading started.
+    pub fn offset(&self) -> u64 {
+        match &self.peeked {
+            Some((offset, _)) => *offset,
+            None => self.reader.count,
+        }
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader.inner
+    }
+
+    fn read_chunk(&mut self) -> Result<Option<(u64, Chunk)>, Error> {
+        let offset = self.reader.count;
+        let mut header = [0u8; 8];
+        let mut len = 0;
+        while len < header.len() {
+            match self.reader.read(&mut header[len..]) {
+                Ok(0) => break,
+                Ok(n) => len += n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        if len == 0 {
+            return Ok(None); // Clean end of file
+        }
+        if len < header.len() {
+            return Err(Error::Truncated {
+                file: PathBuf::new(),
+                offset,
+            });
+        }
+        let header = ChunkHeader::load(&mut &header[..])?;
+        let chunk = Chunk::load_body(header, &mut self.reader).map_err(|e| e.at_offset(offset))?;
+        Ok(Some((offset, chunk)))
+    }
+
+    /// Look at the next chunk without consuming it, None at clean end of file.
+    pub fn peek(&mut self) -> Result<Option<&(u64, Chunk)>, Error> {
+        if self.peeked.is_none() && !self.failed {
+            match self.read_chunk() {
+                Ok(chunk) => self.peeked = chunk,
+                Err(e) => {
+                    self.failed = true;
+                    return Err(e);
+                }
+            }
+        }
+        Ok(self.peeked.as_ref())
     }
+
+    pub fn at_end(&mut self) -> Result<bool, Error> {
+        Ok(self.peek()?.is_none())
+    }
+
+    /// Consume file header chunk if the stream starts with one, checking the file type.
+    pub fn read_file_header(&mut self, file_type: u32) -> Result<(), Error> {
+        if let Some(&(
+            offset,
+            Chunk::FileHeader {
+                file_type: found, ..
+            },
+        )) = self.peek()?
+        {
+            if found != file_type {
+                return Err(Error::WrongFileType {
+                    file: PathBuf::new(),
+                    offset,
+                    expected: file_type,
+                    found,
+                });
+            }
+            self.peeked = None;
+        }
+        Ok(())
+    }
+
+    /// Iterate over chunks of a single record, consuming its Null terminator.
+    /// Stream ending before the terminator is reported as Truncated.
+    pub fn record(&mut self) -> RecordChunks<'_, R> {
+        RecordChunks {
+            chunks: self,
+            done: false,
+        }
+    }
+}
+
+impl<R: Read> Iterator for ChunkReader<R> {
+    type Item = Result<(u64, Chunk), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(chunk) = self.peeked.take() {
+            return Some(Ok(chunk));
+        }
+        if self.failed {
+            return None;
+        }
+        match self.read_chunk() {
+            Ok(chunk) => chunk.map(Ok),
+            Err(e) => {
+                self.failed = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// Chunks of a single record, see ChunkReader::record().
+pub struct RecordChunks<'a, R> {
+    chunks: &'a mut ChunkReader<R>,
+    done: bool,
 }
 
+impl<R: Read> Iterator for RecordChunks<'_, R> {
+    type Item = Result<(u64, Chunk), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        match self.chunks.next() {
+            Some(Ok((_, Chunk::Null()))) => {
+                self.done = true;
+                None
+            }
+            Some(Ok(chunk)) => Some(Ok(chunk)),
+            Some(Err(e)) => {
+                self.done = true;
+                Some(Err(e))
+            }
+            None => {
+                self.done = true;
+                Some(Err(Error::Truncated {
+                    file: PathBuf::new(),
+                    offset: self.chunks.offset(),
+                }))
+            }
+        }
+    }
+}
+
+/// Load records one after another until a clean end of file between records.
+/// File header is checked first, so a file with no records loads as empty.
+/// Failure to load any record is reported with the index of that record.
+pub fn load_records<R, T, F>(rdr: R, file_type: u32, mut load: F) -> Result<Vec<T>, Error>
+where
+    R: Read,
+    F: FnMut(&mut ChunkReader<R>) -> Result<T, Error>,
+{
+    let mut chunks = ChunkReader::new(rdr);
+    chunks.read_file_header(file_type)?;
+
+    let mut records = Vec::new();
+    loop {
+        let record = match chunks.at_end() {
+            Ok(true) => break,
+            Ok(false) => load(&mut chunks),
+            Err(e) => Err(e),
+        };
+        let record = record.map_err(|e| Error::BadRecord {
+            index: records.len(),
+            error: Box::new(e),
+        })?;
+        records.push(record);
+    }
+    Ok(records)
+}
+
+/// Header size of a chunk with strings. The game never reads it and the tools that
+/// wrote its files got it wrong in their own ways, the same way throughout a file.
+/// Kept to write chunks back as they were loaded.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum StringSize {
+    /// Off the way most game files have it, see kaitai/carma.ksy: 2 bytes short for
+    /// references and each material list name, 1 byte long for material descriptions.
+    #[default]
+    Game,
+    /// Off by this many bytes for each string.
+    Off(i8),
+}
+
+/// String sizes of chunks in a record by chunk type, to save it the way it was loaded.
+#[derive(Clone, Debug, Default)]
+pub struct StringSizes(HashMap<u32, StringSize>);
+
+impl StringSizes {
+    pub fn keep(&mut self, chunk: &Chunk) {
+        if let Some((_, size)) = chunk.string_size() {
+            self.0.insert(chunk.chunk_type(), size);
+        }
+    }
+
+    /// Chunk with the string size kept for its type, if any.
+    pub fn sized(&self, mut chunk: Chunk) -> Chunk {
+        let kept = self.0.get(&chunk.chunk_type());
+        if let (Some(kept), Some(size)) = (kept, chunk.string_size_mut()) {
+            *size = *kept;
+        }
+        chunk
+    }
+}
+
+#[derive(Clone, Debug)]
 pub enum Chunk {
     Null(),
     FileHeader {
         file_type: u32,
+        version: u32, // 2 in all game files, see support::FILE_HEADER_VERSION
     },
     FileName {
         name: String,
@@ -48,50 +371,86 @@ pub enum Chunk {
     VertexList(Vec<Vertex>),
     UvMapList(Vec<UvCoord>),
     FaceList(Vec<Face>),
-    MaterialList(Vec<String>),
+    MaterialList(Vec<String>, StringSize),
     MaterialDesc {
         name: String,
-        params: [f32; 12], // a matrix?
+        properties: MaterialProperties,
+        size: StringSize,
+    },
+    FaceMatList {
+        materials: Vec<u16>,
+        unknown: u32, // 2 in all game files
     },
-    FaceMatList(Vec<u16>),
     PixelmapHeader {
         name: String,
         w: u16,
         h: u16,
-        mipmap_w: u16,
-        mipmap_h: u16,
+        origin_x: u16, // hot spot, long misread as a mipmap size
+        origin_y: u16,
+        pixel_type: u8, // BRender pixel type, see texture::PixelFormat
+        row_bytes: u16,
     },
     PixelmapData {
         units: u32,
         unit_bytes: u32,
         data: Vec<u8>,
     },
-    PixelmapRef(String),
-    RenderTabRef(String),
-    MeshFileRef(String),
-    MaterialRef(String),
+    PixelmapRef(String, StringSize),
+    RenderTabRef(String, StringSize),
+    MeshFileRef(String, StringSize),
+    MaterialRef(String, StringSize),
     ActorName {
         name: String,
-        visible: bool,
+        actor_type: u8, // 1 for actors with a model, these are the visible ones
+        render_style: u8,
+        size: StringSize,
     },
     ActorTransform([f32; 12]),
-    MapBoundingBox(),
+    MapBoundingBox {
+        min: [f32; 3],
+        max: [f32; 3],
+    },
     ActorNodeDown(),
-    Unknown29(),
+    ActorBounds(), // attaches preceding bounding box to the last loaded actor
     ActorNodeUp(),
+    // Chunk type not known to the reader, skipped using size from the header.
+    Unknown {
+        chunk_type: u32,
+        payload: Vec<u8>,
+    },
 }
 
 impl Chunk {
-    pub fn load<R: ReadBytesExt + BufRead>(source: &mut R) -> Result<Chunk, Error> {
+    pub fn load<R: Read>(source: &mut R) -> Result<Chunk, Error> {
         let header = ChunkHeader::load(source)?;
+        Chunk::load_body(header, source)
+    }
+
+    /// Load chunk contents following an already read header.
+    /// Known chunks are read field by field, the size from the header is only used
+    /// to skip unknown chunks and kept for chunks with strings.
+    pub fn load_body<R: Read>(header: ChunkHeader, source: &mut R) -> Result<Chunk, Error> {
+        let mut chunk = Chunk::load_fields(&header, source)?;
+        chunk.keep_string_size(header.size)?;
+        Ok(chunk)
+    }
+
+    fn load_fields<R: Read>(header: &ChunkHeader, source: &mut R) -> Result<Chunk, Error> {
         match header.chunk_type {
             support::NULL_CHUNK => Ok(Chunk::Null()),
             support::FILE_HEADER_CHUNK => {
                 trace!("Reading file header...");
-                assert_eq!(header.size, 8);
+                if header.size != 8 {
+                    return Err(Error::CorruptChunk {
+                        file: PathBuf::new(),
+                        offset: 0,
+                        chunk_type: header.chunk_type,
+                        reason: format!("file header size {} instead of 8", header.size),
+                    });
+                }
                 let file_type = source.read_u32::<BigEndian>()?;
-                source.read_u32::<BigEndian>()?; // dummy?
-                Ok(Chunk::FileHeader { file_type })
+                let version = source.read_u32::<BigEndian>()?;
+                Ok(Chunk::FileHeader { file_type, version })
             }
             support::FILE_NAME_CHUNK => {
                 let subtype = source.read_u16::<BigEndian>()?;
@@ -103,7 +462,8 @@ impl Chunk {
             support::VERTEX_LIST_CHUNK => {
                 trace!("Reading vertex list...");
                 let n = source.read_u32::<BigEndian>()?;
-                let mut r = Vec::<Vertex>::with_capacity(n as usize);
+                check_count(header, n, 4, 12)?;
+                let mut r = Vec::<Vertex>::with_capacity(capacity(n));
                 for _ in 0..n {
                     let v = Vertex::load(source)?;
                     r.push(v);
@@ -113,7 +473,8 @@ impl Chunk {
             support::UVMAP_LIST_CHUNK => {
                 trace!("Reading uvmap list...");
                 let n = source.read_u32::<BigEndian>()?;
-                let mut r = Vec::<UvCoord>::with_capacity(n as usize);
+                check_count(header, n, 4, 8)?;
+                let mut r = Vec::<UvCoord>::with_capacity(capacity(n));
                 for _ in 0..n {
                     let v = UvCoord::load(source)?;
                     r.push(v);
@@ -123,7 +484,8 @@ impl Chunk {
             support::FACE_LIST_CHUNK => {
                 trace!("Reading face list...");
                 let n = source.read_u32::<BigEndian>()?;
-                let mut r = Vec::<Face>::with_capacity(n as usize);
+                check_count(header, n, 4, 9)?;
+                let mut r = Vec::<Face>::with_capacity(capacity(n));
                 for _ in 0..n {
                     let v = Face::load(source)?;
                     r.push(v);
@@ -133,63 +495,70 @@ impl Chunk {
             support::MATERIAL_LIST_CHUNK => {
                 trace!("Reading material list...");
                 let n = source.read_u32::<BigEndian>()?;
-                let mut r = Vec::<String>::with_capacity(n as usize);
+                // Size can't be checked here, each name takes at least a byte of input though.
+                let mut r = Vec::<String>::with_capacity(capacity(n));
                 for _ in 0..n {
                     let v = read_c_string(source)?;
                     trace!("... {}", v);
                     r.push(v);
                 }
-                Ok(Chunk::MaterialList(r))
+                Ok(Chunk::MaterialList(r, StringSize::Game))
             }
             support::MATERIAL_DESC_CHUNK => {
                 trace!("Reading material descriptor...");
-                let mut params = [0f32; 12];
-                for i in 0..12 {
-                    params[i] = source.read_f32::<BigEndian>()?;
-                }
+                let properties = MaterialProperties::load(source)?;
                 let name = read_c_string(source)?;
-                trace!("... {}", name);
-                Ok(Chunk::MaterialDesc { params, name })
+                trace!("... {} {:?}", name, properties);
+                Ok(Chunk::MaterialDesc {
+                    properties,
+                    name,
+                    size: StringSize::Game,
+                })
             }
             support::FACE_MAT_LIST_CHUNK => {
                 trace!("Reading face material list...");
                 let n = source.read_u32::<BigEndian>()?;
+                check_count(header, n, 8, 2)?;
 
-                /*let dummy =*/
-                source.read_u32::<BigEndian>()?;
+                let unknown = source.read_u32::<BigEndian>()?;
 
-                let mut r = Vec::<u16>::with_capacity(n as usize);
+                let mut r = Vec::<u16>::with_capacity(capacity(n));
                 for _ in 0..n {
                     let v = source.read_u16::<BigEndian>()?;
                     r.push(v);
                 }
-                Ok(Chunk::FaceMatList(r))
+                Ok(Chunk::FaceMatList {
+                    materials: r,
+                    unknown,
+                })
             }
             support::PIXELMAP_HEADER_CHUNK => {
                 trace!("Reading pixelmap header...");
-                let what1 = source.read_u8()?; // what1 -- somethiing
-                let what2 = source.read_u16::<BigEndian>()?; // what2 -- somethiing
+                let pixel_type = source.read_u8()?;
+                let row_bytes = source.read_u16::<BigEndian>()?;
                 let w = source.read_u16::<BigEndian>()?;
                 let h = source.read_u16::<BigEndian>()?;
-                let mipmap_w = source.read_u16::<BigEndian>()?;
-                let mipmap_h = source.read_u16::<BigEndian>()?;
+                let origin_x = source.read_u16::<BigEndian>()?;
+                let origin_y = source.read_u16::<BigEndian>()?;
                 let name = read_c_string(source)?;
                 trace!(
-                    "... {}, {}x{}, {}x{}, what1 {}, what2 {}",
+                    "... {}, {}x{}, origin {},{}, pixel type {}, row bytes {}",
                     name,
                     w,
                     h,
-                    mipmap_w,
-                    mipmap_h,
-                    what1,
-                    what2
+                    origin_x,
+                    origin_y,
+                    pixel_type,
+                    row_bytes
                 );
                 Ok(Chunk::PixelmapHeader {
                     name,
                     w,
                     h,
-                    mipmap_w,
-                    mipmap_h,
+                    origin_x,
+                    origin_y,
+                    pixel_type,
+                    row_bytes,
                 })
             }
             support::PIXELMAP_DATA_CHUNK => {
@@ -198,11 +567,20 @@ impl Chunk {
                 let units = source.read_u32::<BigEndian>()?;
                 let unit_bytes = source.read_u32::<BigEndian>()?;
 
-                let payload_size = (units * unit_bytes) as usize;
-
-                let mut data = vec![0u8; payload_size];
+                let payload_size = u64::from(units) * u64::from(unit_bytes);
+                if payload_size + 8 > u64::from(header.size) {
+                    return Err(Error::CorruptChunk {
+                        file: PathBuf::new(),
+                        offset: 0,
+                        chunk_type: header.chunk_type,
+                        reason: format!(
+                            "{} units of {} bytes don't fit in chunk size {}",
+                            units, unit_bytes, header.size
+                        ),
+                    });
+                }
 
-                source.read_exact(&mut data)?;
+                let data = read_payload(source, payload_size)?;
 
                 Ok(Chunk::PixelmapData {
                     units,
@@ -214,29 +592,34 @@ impl Chunk {
                 trace!("Reading pixelmap ref...");
                 let pixelmap_name = read_c_string(source)?;
                 trace!("... {}", pixelmap_name);
-                Ok(Chunk::PixelmapRef(pixelmap_name))
+                Ok(Chunk::PixelmapRef(pixelmap_name, StringSize::Game))
             }
             support::RENDERTAB_REF_CHUNK => {
                 trace!("Reading rendertab ref...");
                 let rendertab_name = read_c_string(source)?;
                 trace!("... {}", rendertab_name);
-                Ok(Chunk::RenderTabRef(rendertab_name))
+                Ok(Chunk::RenderTabRef(rendertab_name, StringSize::Game))
             }
             support::ACTOR_NAME_CHUNK => {
                 trace!("Reading actor name...");
-                let visible = source.read_u8()? == 0x1;
-                source.read_u8()?; // what2
+                let actor_type = source.read_u8()?;
+                let render_style = source.read_u8()?;
                 let name = read_c_string(source)?;
                 trace!("... {}", name);
-                Ok(Chunk::ActorName { name, visible })
+                Ok(Chunk::ActorName {
+                    name,
+                    actor_type,
+                    render_style,
+                    size: StringSize::Game,
+                })
             }
             support::ACTOR_NODE_DOWN_CHUNK => {
                 trace!("Reading actor node down...");
                 Ok(Chunk::ActorNodeDown())
             }
-            support::UNKNOWN_29_CHUNK => {
-                trace!("Reading unknown 29...");
-                Ok(Chunk::Unknown29())
+            support::ACTOR_BOUNDS_CHUNK => {
+                trace!("Reading actor bounds...");
+                Ok(Chunk::ActorBounds())
             }
             support::ACTOR_NODE_UP_CHUNK => {
                 trace!("Reading actor node up...");
@@ -246,24 +629,24 @@ impl Chunk {
                 trace!("Reading meshfile ref...");
                 let mesh_name = read_c_string(source)?;
                 trace!("... {}", mesh_name);
-                Ok(Chunk::MeshFileRef(mesh_name))
+                Ok(Chunk::MeshFileRef(mesh_name, StringSize::Game))
             }
             support::MATERIAL_REF_CHUNK => {
                 trace!("Reading material ref...");
                 let material_name = read_c_string(source)?;
                 trace!("... {}", material_name);
-                Ok(Chunk::MaterialRef(material_name))
+                Ok(Chunk::MaterialRef(material_name, StringSize::Game))
             }
             support::ACTOR_TRANSFORM_CHUNK => {
                 trace!("Reading actor transform...");
                 let mut params = [0f32; 12];
-                for i in 0..12 {
-                    params[i] = source.read_f32::<BigEndian>()?;
+                for param in params.iter_mut() {
+                    *param = source.read_f32::<BigEndian>()?;
                 }
                 for row in 0..4 {
                     trace!(
                         "[{} {} {}]",
-                        params[row * 3 + 0],
+                        params[row * 3],
                         params[row * 3 + 1],
                         params[row * 3 + 2]
                     );
@@ -284,10 +667,477 @@ impl Chunk {
                 Ok(Chunk::ActorTransform(params))
             }
             support::MAP_BOUNDINGBOX_CHUNK => {
-                trace!("Reading map bounding box (or?)...");
-                Ok(Chunk::MapBoundingBox())
+                trace!("Reading bounding box...");
+                if header.size != 24 {
+                    return Err(Error::CorruptChunk {
+                        file: PathBuf::new(),
+                        offset: 0,
+                        chunk_type: header.chunk_type,
+                        reason: format!("bounding box size {} instead of 24", header.size),
+                    });
+                }
+                let mut min = [0f32; 3];
+                for coord in min.iter_mut() {
+                    *coord = source.read_f32::<BigEndian>()?;
+                }
+                let mut max = [0f32; 3];
+                for coord in max.iter_mut() {
+                    *coord = source.read_f32::<BigEndian>()?;
+                }
+                trace!("... {:?} - {:?}", min, max);
+                Ok(Chunk::MapBoundingBox { min, max })
+            }
+            chunk_type => {
+                trace!("Reading unknown chunk {:#x}...", chunk_type);
+                let payload = read_payload(source, u64::from(header.size))?;
+                Ok(Chunk::Unknown {
+                    chunk_type,
+                    payload,
+                })
+            }
+        }
+    }
+
+    pub fn chunk_type(&self) -> u32 {
+        match self {
+            Chunk::Null() => support::NULL_CHUNK,
+            Chunk::FileHeader { .. } => support::FILE_HEADER_CHUNK,
+            Chunk::FileName { .. } => support::FILE_NAME_CHUNK,
+            Chunk::VertexList(_) => support::VERTEX_LIST_CHUNK,
+            Chunk::UvMapList(_) => support::UVMAP_LIST_CHUNK,
+            Chunk::FaceList(_) => support::FACE_LIST_CHUNK,
+            Chunk::MaterialList(..) => support::MATERIAL_LIST_CHUNK,
+            Chunk::MaterialDesc { .. } => support::MATERIAL_DESC_CHUNK,
+            Chunk::FaceMatList { .. } => support::FACE_MAT_LIST_CHUNK,
+            Chunk::PixelmapHeader { .. } => support::PIXELMAP_HEADER_CHUNK,
+            Chunk::PixelmapData { .. } => support::PIXELMAP_DATA_CHUNK,
+            Chunk::PixelmapRef(..) => support::PIXELMAP_REF_CHUNK,
+            Chunk::RenderTabRef(..) => support::RENDERTAB_REF_CHUNK,
+            Chunk::MeshFileRef(..) => support::MESHFILE_REF_CHUNK,
+            Chunk::MaterialRef(..) => support::MATERIAL_REF_CHUNK,
+            Chunk::ActorName { .. } => support::ACTOR_NAME_CHUNK,
+            Chunk::ActorTransform(_) => support::ACTOR_TRANSFORM_CHUNK,
+            Chunk::MapBoundingBox { .. } => support::MAP_BOUNDINGBOX_CHUNK,
+            Chunk::ActorNodeDown() => support::ACTOR_NODE_DOWN_CHUNK,
+            Chunk::ActorBounds() => support::ACTOR_BOUNDS_CHUNK,
+            Chunk::ActorNodeUp() => support::ACTOR_NODE_UP_CHUNK,
+            Chunk::Unknown { chunk_type, .. } => *chunk_type,
+        }
+    }
+
+    /// Bytes each string of the chunk type is off in header sizes of most game files.
+    fn game_string_size(chunk_type: u32) -> i8 {
+        match chunk_type {
+            support::PIXELMAP_REF_CHUNK
+            | support::RENDERTAB_REF_CHUNK
+            | support::MESHFILE_REF_CHUNK
+            | support::MATERIAL_REF_CHUNK
+            | support::MATERIAL_LIST_CHUNK => -2,
+            support::MATERIAL_DESC_CHUNK => 1,
+            _ => 0,
+        }
+    }
+
+    /// Number of strings and their size, for chunks with strings.
+    fn string_size(&self) -> Option<(usize, StringSize)> {
+        match self {
+            Chunk::PixelmapRef(_, size)
+            | Chunk::RenderTabRef(_, size)
+            | Chunk::MeshFileRef(_, size)
+            | Chunk::MaterialRef(_, size)
+            | Chunk::MaterialDesc { size, .. }
+            | Chunk::ActorName { size, .. } => Some((1, *size)),
+            Chunk::MaterialList(names, size) => Some((names.len(), *size)),
+            _ => None,
+        }
+    }
+
+    fn string_size_mut(&mut self) -> Option<&mut StringSize> {
+        match self {
+            Chunk::PixelmapRef(_, size)
+            | Chunk::RenderTabRef(_, size)
+            | Chunk::MeshFileRef(_, size)
+            | Chunk::MaterialRef(_, size)
+            | Chunk::MaterialList(_, size)
+            | Chunk::MaterialDesc { size, .. }
+            | Chunk::ActorName { size, .. } => Some(size),
+            _ => None,
+        }
+    }
+
+    // Remember how far the header size of a loaded chunk with strings was off.
+    fn keep_string_size(&mut self, header_size: u32) -> Result<(), Error> {
+        let Some((strings @ 1.., _)) = self.string_size() else {
+            return Ok(());
+        };
+        let mut payload = Vec::<u8>::new();
+        self.write_payload(&mut payload)?;
+        let off = (i64::from(header_size) - payload.len() as i64) / strings as i64;
+        let game = Chunk::game_string_size(self.chunk_type());
+        if let Some(size) = self.string_size_mut() {
+            *size = match i8::try_from(off) {
+                Ok(off) if off != game => StringSize::Off(off),
+                _ => StringSize::Game,
+            };
+        }
+        Ok(())
+    }
+
+    /// Write chunk in the same binary format `load()` reads it.
+    /// Header size of chunks with strings is off the way it was in the loaded file,
+    /// or the way the game has it for new chunks, see StringSize.
+    pub fn write<W: WriteBytesExt>(&self, dest: &mut W) -> Result<(), Error> {
+        let mut payload = Vec::<u8>::new();
+        self.write_payload(&mut payload)?;
+        let mut size = payload.len() as i64;
+        if let Some((strings, string_size)) = self.string_size() {
+            let off = match string_size {
+                StringSize::Game => Chunk::game_string_size(self.chunk_type()),
+                StringSize::Off(off) => off,
+            };
+            size += strings as i64 * i64::from(off);
+        }
+        let header = ChunkHeader {
+            chunk_type: self.chunk_type(),
+            size: size.max(0) as u32,
+        };
+        header.write(dest)?;
+        dest.write_all(&payload)?;
+        Ok(())
+    }
+
+    fn write_payload(&self, dest: &mut Vec<u8>) -> Result<(), Error> {
+        match self {
+            Chunk::Null()
+            | Chunk::ActorNodeDown()
+            | Chunk::ActorBounds()
+            | Chunk::ActorNodeUp() => {}
+            Chunk::MapBoundingBox { min, max } => {
+                for coord in min.iter().chain(max) {
+                    dest.write_f32::<BigEndian>(*coord)?;
+                }
+            }
+            Chunk::FileHeader { file_type, version } => {
+                dest.write_u32::<BigEndian>(*file_type)?;
+                dest.write_u32::<BigEndian>(*version)?;
+            }
+            Chunk::FileName { name, subtype } => {
+                dest.write_u16::<BigEndian>(*subtype)?;
+                write_c_string(dest, name)?;
+            }
+            Chunk::VertexList(r) => {
+                dest.write_u32::<BigEndian>(r.len() as u32)?;
+                for v in r {
+                    v.write(dest)?;
+                }
+            }
+            Chunk::UvMapList(r) => {
+                dest.write_u32::<BigEndian>(r.len() as u32)?;
+                for v in r {
+                    v.write(dest)?;
+                }
+            }
+            Chunk::FaceList(r) => {
+                dest.write_u32::<BigEndian>(r.len() as u32)?;
+                for v in r {
+                    v.write(dest)?;
+                }
+            }
+            Chunk::MaterialList(r, _) => {
+                dest.write_u32::<BigEndian>(r.len() as u32)?;
+                for v in r {
+                    write_c_string(dest, v)?;
+                }
+            }
+            Chunk::MaterialDesc {
+                name, properties, ..
+            } => {
+                properties.write(dest)?;
+                write_c_string(dest, name)?;
+            }
+            Chunk::FaceMatList { materials, unknown } => {
+                dest.write_u32::<BigEndian>(materials.len() as u32)?;
+                dest.write_u32::<BigEndian>(*unknown)?;
+                for v in materials {
+                    dest.write_u16::<BigEndian>(*v)?;
+                }
+            }
+            Chunk::PixelmapHeader {
+                name,
+                w,
+                h,
+                origin_x,
+                origin_y,
+                pixel_type,
+                row_bytes,
+            } => {
+                dest.write_u8(*pixel_type)?;
+                dest.write_u16::<BigEndian>(*row_bytes)?;
+                dest.write_u16::<BigEndian>(*w)?;
+                dest.write_u16::<BigEndian>(*h)?;
+                dest.write_u16::<BigEndian>(*origin_x)?;
+                dest.write_u16::<BigEndian>(*origin_y)?;
+                write_c_string(dest, name)?;
+            }
+            Chunk::PixelmapData {
+                units,
+                unit_bytes,
+                data,
+            } => {
+                dest.write_u32::<BigEndian>(*units)?;
+                dest.write_u32::<BigEndian>(*unit_bytes)?;
+                dest.write_all(data)?;
+            }
+            Chunk::PixelmapRef(name, _)
+            | Chunk::RenderTabRef(name, _)
+            | Chunk::MeshFileRef(name, _)
+            | Chunk::MaterialRef(name, _) => {
+                write_c_string(dest, name)?;
+            }
+            Chunk::ActorName {
+                name,
+                actor_type,
+                render_style,
+                ..
+            } => {
+                dest.write_u8(*actor_type)?;
+                dest.write_u8(*render_style)?;
+                write_c_string(dest, name)?;
+            }
+            Chunk::ActorTransform(params) => {
+                for param in params {
+                    dest.write_f32::<BigEndian>(*param)?;
+                }
+            }
+            Chunk::Unknown { payload, .. } => {
+                dest.write_all(payload)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use {
+        super::*,
+        std::{fs, path::Path},
+    };
+
+    fn load_all_chunks(data: &[u8]) -> Result<Vec<Chunk>, Error> {
+        ChunkReader::new(data)
+            .map(|chunk| chunk.map(|(_, chunk)| chunk))
+            .collect()
+    }
+
+    fn write_all_chunks(chunks: &[Chunk]) -> Vec<u8> {
+        let mut data = Vec::new();
+        for chunk in chunks {
+            chunk.write(&mut data).unwrap();
+        }
+        data
+    }
+
+    #[rustfmt::skip]
+    #[test]
+    fn test_write_chunk() {
+        let chunk = Chunk::FileName {
+            name: String::from("hello"),
+            subtype: support::MODEL_FILE_SUBTYPE,
+        };
+        let mut data = Vec::new();
+        chunk.write(&mut data).unwrap();
+        assert_eq!(vec![
+            0x0, 0x0, 0x0, 0x36, // Chunk type - FILE_NAME_CHUNK
+            0x0, 0x0, 0x0, 0x8, // Chunk size
+            0x0, 0x3, // subtype u16
+            b'h', b'e', b'l', b'l', b'o', 0, // Chunk contents
+        ], data);
+    }
+
+    #[rustfmt::skip]
+    #[test]
+    fn test_write_string_sizes() {
+        let mut data = Vec::new();
+        Chunk::MaterialList(vec![String::from("A"), String::from("B")], StringSize::Game)
+            .write(&mut data)
+            .unwrap();
+        assert_eq!(vec![
+            0x0, 0x0, 0x0, 0x16, // Chunk type - MATERIAL_LIST_CHUNK
+            0x0, 0x0, 0x0, 0x4, // Chunk size, 2 short per name like in game files
+            0x0, 0x0, 0x0, 0x2, // count
+            b'A', 0, b'B', 0, // Chunk contents
+        ], data);
+
+        let chunks = load_all_chunks(&[
+            0x0, 0x0, 0x0, 0x1c, // Chunk type - PIXELMAP_REF_CHUNK
+            0x0, 0x0, 0x0, 0x2, // Chunk size, exact
+            b'A', 0, // Chunk contents
+        ]).unwrap();
+        assert!(matches!(chunks[0], Chunk::PixelmapRef(_, StringSize::Off(0))));
+        let mut sizes = StringSizes::default();
+        sizes.keep(&chunks[0]);
+        assert!(matches!(
+            sizes.sized(Chunk::PixelmapRef(String::from("B"), StringSize::Game)),
+            Chunk::PixelmapRef(_, StringSize::Off(0))
+        ));
+    }
+
+    #[rustfmt::skip]
+    #[test]
+    fn test_load_unknown_chunk() {
+        let data = vec![
+            0x0, 0x0, 0xbe, 0xef, // Chunk type - unknown
+            0x0, 0x0, 0x0, 0x3, // Chunk size
+            0xa, 0xb, 0xc, // Chunk contents
+            0x0, 0x0, 0x0, 0x0, // Chunk type - NULL_CHUNK
+            0x0, 0x0, 0x0, 0x0, // Chunk size
+        ];
+        let chunks = load_all_chunks(&data).unwrap();
+        match &chunks[0] {
+            Chunk::Unknown { chunk_type, payload } => {
+                assert_eq!(0xbeef, *chunk_type);
+                assert_eq!(vec![0xa, 0xb, 0xc], *payload);
+            }
+            _ => panic!("Expected unknown chunk"),
+        }
+        assert_eq!(support::NULL_CHUNK, chunks[1].chunk_type());
+        assert_eq!(data, write_all_chunks(&chunks));
+    }
+
+    #[rustfmt::skip]
+    #[test]
+    fn test_chunk_reader_records() {
+        let data = vec![
+            0x0, 0x0, 0x0, 0x12, // Chunk type - FILE_HEADER_CHUNK
+            0x0, 0x0, 0x0, 0x8, // Chunk size
+            0x0, 0x0, 0x0, 0x5, // File type - material
+            0x0, 0x0, 0x0, 0x2, // Version
+            0x0, 0x0, 0x0, 0x1c, // Chunk type - PIXELMAP_REF_CHUNK, at 0x10
+            0x0, 0x0, 0x0, 0x2, // Chunk size
+            b'A', 0, // Chunk contents
+            0x0, 0x0, 0x0, 0x0, // Chunk type - NULL_CHUNK, at 0x1a
+            0x0, 0x0, 0x0, 0x0, // Chunk size
+            0x0, 0x0, 0x0, 0x1f, // Chunk type - RENDERTAB_REF_CHUNK, at 0x22
+            0x0, 0x0, 0x0, 0x2, // Chunk size
+            b'B', 0, // Chunk contents
+            0x0, 0x0, 0x0, // Cut short chunk header, at 0x2c
+        ];
+
+        let offsets: Vec<_> = ChunkReader::new(&data[..])
+            .map(|chunk| chunk.map(|(offset, _)| offset))
+            .collect();
+        assert_eq!(5, offsets.len());
+        assert_eq!(vec![0x0, 0x10, 0x1a, 0x22], offsets[..4].iter().map(|o| *o.as_ref().unwrap()).collect::<Vec<_>>());
+        assert!(matches!(offsets[4], Err(Error::Truncated { offset: 0x2c, .. })));
+
+        let mut chunks = ChunkReader::new(&data[..]);
+        assert!(matches!(
+            chunks.read_file_header(support::PIXELMAP_FILE_TYPE),
+            Err(Error::WrongFileType { offset: 0, found: 0x5, .. })
+        ));
+
+        let mut chunks = ChunkReader::new(&data[..]);
+        chunks.read_file_header(support::MATERIAL_FILE_TYPE).unwrap();
+        assert_eq!(0x10, chunks.offset());
+        let record: Vec<_> = chunks.record().map(|chunk| chunk.unwrap()).collect();
+        assert_eq!(1, record.len());
+        assert_eq!(support::PIXELMAP_REF_CHUNK, record[0].1.chunk_type());
+        assert!(!chunks.at_end().unwrap());
+        assert_eq!(0x22, chunks.offset());
+        let record: Vec<_> = chunks.record().collect();
+        assert_eq!(2, record.len());
+        assert!(matches!(record[1], Err(Error::Truncated { offset: 0x2c, .. })));
+        assert!(chunks.next().is_none());
+    }
+
+    #[rustfmt::skip]
+    #[test]
+    fn test_load_truncated_unknown_chunk() {
+        let data = vec![
+            0x0, 0x0, 0xbe, 0xef, // Chunk type - unknown
+            0xff, 0xff, 0xff, 0xff, // Chunk size, way past the end of data
+            0xa, 0xb, 0xc,
+        ];
+        assert!(matches!(
+            load_all_chunks(&data),
+            Err(Error::Truncated { offset: 0, .. })
+        ));
+    }
+
+    #[rustfmt::skip]
+    #[test]
+    fn test_load_bogus_counts() {
+        let data = vec![
+            0x0, 0x0, 0x0, 0x17, // Chunk type - VERTEX_LIST_CHUNK
+            0x0, 0x0, 0x0, 0x10, // Chunk size, room for one vertex
+            0xff, 0xff, 0xff, 0xff, // Vertex count
+            0x3f, 0x80, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0,
+        ];
+        assert!(matches!(
+            load_all_chunks(&data),
+            Err(Error::CorruptChunk { offset: 0, chunk_type: support::VERTEX_LIST_CHUNK, .. })
+        ));
+
+        let data = vec![
+            0x0, 0x0, 0x0, 0x21, // Chunk type - PIXELMAP_DATA_CHUNK
+            0x0, 0x0, 0x0, 0xc, // Chunk size
+            0x0, 0x1, 0x0, 0x0, // Units
+            0x0, 0x1, 0x0, 0x0, // Unit bytes, units * unit_bytes overflows u32
+            0xa, 0xb, 0xc, 0xd,
+        ];
+        assert!(matches!(
+            load_all_chunks(&data),
+            Err(Error::CorruptChunk { offset: 0, chunk_type: support::PIXELMAP_DATA_CHUNK, .. })
+        ));
+
+        let data = vec![
+            0x0, 0x0, 0x0, 0x21, // Chunk type - PIXELMAP_DATA_CHUNK
+            0xff, 0xff, 0xff, 0xff, // Chunk size, way past the end of data
+            0x1f, 0xff, 0xff, 0xff, // Units, 2GiB of data
+            0x0, 0x0, 0x0, 0x4, // Unit bytes
+            0xa, 0xb, 0xc, 0xd,
+        ];
+        assert!(matches!(
+            load_all_chunks(&data),
+            Err(Error::Truncated { offset: 0, .. })
+        ));
+    }
+
+    // Load every chunk from game files, write them back and check the result.
+    // Pixelmap files have precise chunk sizes so they must come back byte-exact.
+    // Some files in the game data are corrupt, those just must fail to load.
+    #[test]
+    fn test_roundtrip_game_files() {
+        const CORRUPT: &[&str] = &[
+            "DecodedData/DATA/ACTORS/&CRANE.ACT", // beginning of the file is cut off
+            "DecodedData/DATA/ACTORS/PROG.ACT",   // a line of text
+            "DecodedData/DATA/MATERIAL/FONE.MAT", // beginning of the file is cut off
+        ];
+        for dir in &[
+            "DecodedData/DATA/ACTORS",
+            "DecodedData/DATA/MATERIAL",
+            "DecodedData/DATA/MODELS",
+            "DecodedData/DATA/PIXELMAP",
+            "DecodedData/DATA/64X48X8/PIXELMAP",
+            "DecodedData/DATA/REG/MATERIAL",
+            "DecodedData/DATA/REG/PALETTES",
+            "DecodedData/DATA/REG/PIXELMAP",
+            "DecodedData/DATA/REG/SHADETAB",
+        ] {
+            for entry in fs::read_dir(Path::new(dir)).unwrap() {
+                let path = entry.unwrap().path();
+                let original = fs::read(&path).unwrap();
+                if CORRUPT.iter().any(|corrupt| path == Path::new(corrupt)) {
+                    assert!(load_all_chunks(&original).is_err(), "{:?} loads", path);
+                    continue;
+                }
+                let chunks = load_all_chunks(&original)
+                    .unwrap_or_else(|e| panic!("{:?} does not load: {}", path, e));
+                let written = write_all_chunks(&chunks);
+                assert!(original == written, "{:?} is not byte-exact", path);
             }
-            _ => unimplemented!(), // should not appear here, in general chunk reader is possible
         }
     }
 }
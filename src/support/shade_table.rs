@@ -0,0 +1,151 @@
+//
+// Part of Roadkill Project.
+//
+// Copyright 2010, 2017, Stanislav Karchebnyy <berkus@madfire.net>
+//
+// Distributed under the Boost Software License, Version 1.0.
+// (See file LICENSE_1_0.txt or a copy at http://www.boost.org/LICENSE_1_0.txt)
+//
+use {
+    crate::support::{
+        texture::{PixelFormat, PixelMap},
+        Error,
+    },
+    log::*,
+};
+
+/**
+ * Shade table (.TAB) gives the palette index to draw a colour index with at some light level.
+ * It is stored as an 8-bit pixelmap with a row of 256 indices per light level, darkest first.
+ * Header origin y points at the row that keeps colours unchanged, rows below it darken
+ * and rows above it brighten.
+ */
+#[derive(Clone, Debug, Default)]
+pub struct ShadeTable {
+    pub name: String,
+    pub levels: u16,
+    pub identity_level: u16,
+    pub data: Vec<u8>, // levels rows of 256 entries
+}
+
+impl ShadeTable {
+    pub fn from_pixelmap(pm: &PixelMap) -> Result<ShadeTable, Error> {
+        let error = |reason: String| Error::BadPixelmap {
+            name: pm.name.clone(),
+            reason,
+        };
+        if pm.format != PixelFormat::Index8 || pm.w != 256 || pm.h == 0 {
+            return Err(error(format!(
+                "shade table must be 256 wide {}, got {}x{} {}",
+                PixelFormat::Index8,
+                pm.w,
+                pm.h,
+                pm.format
+            )));
+        }
+        let stride = match pm.row_bytes {
+            0 => 256,
+            row_bytes => usize::from(row_bytes),
+        };
+        if stride < 256 || pm.data.len() < stride * usize::from(pm.h) {
+            return Err(error(format!(
+                "{} bytes of data for {} levels with {} bytes per row",
+                pm.data.len(),
+                pm.h,
+                stride
+            )));
+        }
+
+        let data = pm
+            .data
+            .chunks(stride)
+            .take(usize::from(pm.h))
+            .flat_map(|row| &row[..256])
+            .copied()
+            .collect();
+        Ok(ShadeTable {
+            name: pm.name.clone(),
+            levels: pm.h,
+            identity_level: pm.origin_y.min(pm.h - 1),
+            data,
+        })
+    }
+
+    pub fn load_from(fname: String) -> Result<Vec<ShadeTable>, Error> {
+        PixelMap::load_from(fname)?
+            .iter()
+            .map(|pm| {
+                let table = ShadeTable::from_pixelmap(pm)?;
+                debug!(
+                    "Shade table {}: {} levels, identity at {}",
+                    table.name, table.levels, table.identity_level
+                );
+                Ok(table)
+            })
+            .collect()
+    }
+
+    /// Palette index to draw colour index with at light level, levels past the table are clamped.
+    pub fn shade(&self, index: u8, level: u16) -> u8 {
+        let level = level.min(self.levels.saturating_sub(1));
+        self.data[usize::from(level) * 256 + usize::from(index)]
+    }
+
+    /// Light level for lighting intensity, where 1.0 is a fully lit surface and
+    /// picks the identity row. Intensity above 1.0 reaches the brighter rows.
+    pub fn level_for(&self, intensity: f32) -> u16 {
+        let level = (intensity * f32::from(self.identity_level)).round();
+        level.clamp(0.0, f32::from(self.levels.saturating_sub(1))) as u16
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_shade_tables() {
+        let table =
+            ShadeTable::load_from(String::from("DecodedData/DATA/REG/SHADETAB/DRRENDER.TAB"))
+                .unwrap();
+        assert_eq!(1, table.len());
+        assert_eq!((1, 0), (table[0].levels, table[0].identity_level));
+        assert_eq!(0x41, table[0].shade(0x41, 0));
+        assert_eq!(0, table[0].shade(0xf0, 5));
+        assert_eq!(0, table[0].level_for(1.5));
+
+        let table =
+            ShadeTable::load_from(String::from("DecodedData/DATA/REG/SHADETAB/HOUSING.TAB"))
+                .unwrap();
+        assert_eq!((4, 2), (table[0].levels, table[0].identity_level));
+        // Darker rows shift colours down the ramp, brighter ones up.
+        assert_eq!(0x40, table[0].shade(0x42, 0));
+        assert_eq!(0x41, table[0].shade(0x42, 1));
+        assert_eq!(0x42, table[0].shade(0x42, 2));
+        assert_eq!(0x43, table[0].shade(0x42, 3));
+        assert_eq!(
+            (0, 1, 2, 3),
+            (
+                table[0].level_for(0.0),
+                table[0].level_for(0.5),
+                table[0].level_for(1.0),
+                table[0].level_for(2.0),
+            )
+        );
+    }
+
+    #[test]
+    fn test_shade_table_needs_256_indices() {
+        let pm = PixelMap {
+            name: String::from("NARROW.TAB"),
+            w: 128,
+            h: 1,
+            data: vec![0; 128],
+            ..Default::default()
+        };
+        assert!(matches!(
+            ShadeTable::from_pixelmap(&pm),
+            Err(Error::BadPixelmap { name, .. }) if name == "NARROW.TAB"
+        ));
+    }
+}
@@ -0,0 +1,150 @@
+//
+// Part of Roadkill Project.
+//
+// Copyright 2010, 2017, Stanislav Karchebnyy <berkus@madfire.net>
+//
+// Distributed under the Boost Software License, Version 1.0.
+// (See file LICENSE_1_0.txt or a copy at http://www.boost.org/LICENSE_1_0.txt)
+//
+use {
+    crate::support::Error,
+    cgmath::Vector3,
+    std::{
+        fs,
+        path::{Path, PathBuf},
+        str::FromStr,
+    },
+};
+
+// Text resource file (car descriptions and such) with comments stripped.
+// Remembers original line numbers so parse errors can point at the right line.
+pub struct TextReader {
+    file: PathBuf,
+    lines: Vec<(usize, String)>,
+    pos: usize,
+}
+
+impl TextReader {
+    pub fn new(file: PathBuf, text: &str) -> TextReader {
+        let lines = text
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.starts_with("//")) // Skip whole-line comments
+            .filter(|(_, line)| !line.is_empty()) // Skip empty lines
+            // Separate in-line comments from data
+            .map(|(n, line)| (n + 1, line.split("//").next().unwrap().trim().to_owned()))
+            .collect();
+        TextReader {
+            file,
+            lines,
+            pos: 0,
+        }
+    }
+
+    pub fn open(fname: &Path) -> Result<TextReader, Error> {
+        let data = fs::read(fname).map_err(|e| Error::from(e).in_file(fname))?;
+        Ok(TextReader::new(
+            fname.to_path_buf(),
+            &String::from_utf8_lossy(&data),
+        ))
+    }
+
+    pub fn file(&self) -> &Path {
+        &self.file
+    }
+
+    /// Line number of the last line returned, 0 before the first one.
+    pub fn line_number(&self) -> usize {
+        if self.pos == 0 {
+            0
+        } else {
+            self.lines[self.pos - 1].0
+        }
+    }
+
+    /// Make a ParseText error pointing at the last line returned.
+    pub fn error(&self, expected: &str, found: &str) -> Error {
+        Error::ParseText {
+            file: self.file.clone(),
+            line: self.line_number(),
+            expected: String::from(expected),
+            found: String::from(found),
+        }
+    }
+
+    pub fn next_line(&mut self) -> Result<String, Error> {
+        match self.lines.get(self.pos) {
+            Some((_, line)) => {
+                self.pos += 1;
+                Ok(line.clone())
+            }
+            None => Err(self.error("a line", "end of file")),
+        }
+    }
+
+    /// Expect next line to match provided text exactly.
+    pub fn expect_line(&mut self, text: &str) -> Result<(), Error> {
+        let line = self.next_line()?;
+        if line == text {
+            return Ok(());
+        }
+        Err(self.error(&format!("{:?}", text), &line))
+    }
+
+    /// Parse next line as a single value.
+    pub fn parse_line<T: FromStr>(&mut self, expected: &str) -> Result<T, Error> {
+        let line = self.next_line()?;
+        line.parse().map_err(|_| self.error(expected, &line))
+    }
+
+    /// Parse next line as a three-component comma-separated vector.
+    pub fn parse_vector(&mut self) -> Result<Vector3<f32>, Error> {
+        let line = self.next_line()?;
+        let v = line
+            .split(',')
+            .map(|i| i.trim().parse())
+            .collect::<Result<Vec<f32>, _>>()
+            .ok()
+            .filter(|v| v.len() >= 3)
+            .ok_or_else(|| self.error("a vector", &line))?;
+        Ok(Vector3::from((v[0], v[1], v[2])))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_lines() {
+        let text = "// comment\nFIRST\n\n  second // inline comment\n1.0, 2.0,3\n";
+        let mut input = TextReader::new(PathBuf::from("TEST.TXT"), text);
+        input.expect_line("FIRST").unwrap();
+        assert_eq!(2, input.line_number());
+        assert_eq!("second", input.next_line().unwrap());
+        assert_eq!(4, input.line_number());
+        assert_eq!(Vector3::new(1.0, 2.0, 3.0), input.parse_vector().unwrap());
+        assert!(input.next_line().is_err());
+    }
+
+    #[test]
+    fn test_parse_error_location() {
+        let text = "// comment\n\nSTART\nnot a number\n";
+        let mut input = TextReader::new(PathBuf::from("TEST.TXT"), text);
+        input.expect_line("START").unwrap();
+        match input.parse_line::<u32>("a number") {
+            Err(Error::ParseText {
+                file,
+                line,
+                expected,
+                found,
+            }) => {
+                assert_eq!(PathBuf::from("TEST.TXT"), file);
+                assert_eq!(4, line);
+                assert_eq!("a number", expected);
+                assert_eq!("not a number", found);
+            }
+            _ => panic!("Expected a parse error"),
+        }
+    }
+}
@@ -7,36 +7,260 @@
 // (See file LICENSE_1_0.txt or a copy at http://www.boost.org/LICENSE_1_0.txt)
 //
 use {
-    crate::support::{self, resource::Chunk, Error},
-    byteorder::ReadBytesExt,
+    crate::support::{
+        self,
+        palette::Palette,
+        resource::{load_records, Chunk, ChunkReader, Strictness},
+        Error,
+    },
+    byteorder::WriteBytesExt,
     log::*,
     png,
     std::{
         fs::File,
-        io::{BufRead, BufReader, Write},
+        io::{BufReader, BufWriter, Read, Write},
+        path::PathBuf,
     },
 };
 
+/// Pixel type from the pixelmap header, numbered as in BRender.
+/// Pixels wider than a byte are stored big-endian like everything else in the files.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum PixelFormat {
+    Index1,
+    Index2,
+    Index4,
+    #[default]
+    Index8,
+    Rgb555,
+    Rgb565,
+    Rgb888,   // R, G, B bytes
+    Rgbx888,  // X, R, G, B bytes, palettes keep inverted alpha in X
+    Rgba8888, // R, G, B, A bytes, what remap_via() produces
+    Bgr555,
+    Rgba4444,
+    Other(u8),
+}
+
+// Scale n-bit color component up to 8 bits, filling low bits from the high ones.
+fn expand_bits(value: u16, bits: u32) -> u8 {
+    let value = value & ((1 << bits) - 1);
+    ((value << (8 - bits)) | (value >> (2 * bits - 8))) as u8
+}
+
+impl PixelFormat {
+    pub fn from_pixel_type(pixel_type: u8) -> PixelFormat {
+        match pixel_type {
+            0 => PixelFormat::Index1,
+            1 => PixelFormat::Index2,
+            2 => PixelFormat::Index4,
+            3 => PixelFormat::Index8,
+            4 => PixelFormat::Rgb555,
+            5 => PixelFormat::Rgb565,
+            6 => PixelFormat::Rgb888,
+            7 => PixelFormat::Rgbx888,
+            8 => PixelFormat::Rgba8888,
+            17 => PixelFormat::Bgr555,
+            18 => PixelFormat::Rgba4444,
+            other => PixelFormat::Other(other),
+        }
+    }
+
+    pub fn pixel_type(self) -> u8 {
+        match self {
+            PixelFormat::Index1 => 0,
+            PixelFormat::Index2 => 1,
+            PixelFormat::Index4 => 2,
+            PixelFormat::Index8 => 3,
+            PixelFormat::Rgb555 => 4,
+            PixelFormat::Rgb565 => 5,
+            PixelFormat::Rgb888 => 6,
+            PixelFormat::Rgbx888 => 7,
+            PixelFormat::Rgba8888 => 8,
+            PixelFormat::Bgr555 => 17,
+            PixelFormat::Rgba4444 => 18,
+            PixelFormat::Other(other) => other,
+        }
+    }
+
+    /// Size of one pixel in bytes. None for formats packing several pixels in a byte
+    /// and for formats we don't know.
+    pub fn bytes_per_pixel(self) -> Option<usize> {
+        match self {
+            PixelFormat::Index8 => Some(1),
+            PixelFormat::Rgb555
+            | PixelFormat::Rgb565
+            | PixelFormat::Bgr555
+            | PixelFormat::Rgba4444 => Some(2),
+            PixelFormat::Rgb888 => Some(3),
+            PixelFormat::Rgbx888 | PixelFormat::Rgba8888 => Some(4),
+            PixelFormat::Index1
+            | PixelFormat::Index2
+            | PixelFormat::Index4
+            | PixelFormat::Other(_) => None,
+        }
+    }
+
+    /// Decode one pixel of a direct color format to RGBA.
+    /// Indexed and unknown formats have no color of their own and decode to opaque black.
+    pub fn decode(self, pixel: &[u8]) -> [u8; 4] {
+        let word = || u16::from_be_bytes([pixel[0], pixel[1]]);
+        match self {
+            PixelFormat::Rgb555 => {
+                let p = word();
+                [
+                    expand_bits(p >> 10, 5),
+                    expand_bits(p >> 5, 5),
+                    expand_bits(p, 5),
+                    255,
+                ]
+            }
+            PixelFormat::Bgr555 => {
+                let p = word();
+                [
+                    expand_bits(p, 5),
+                    expand_bits(p >> 5, 5),
+                    expand_bits(p >> 10, 5),
+                    255,
+                ]
+            }
+            PixelFormat::Rgb565 => {
+                let p = word();
+                [
+                    expand_bits(p >> 11, 5),
+                    expand_bits(p >> 5, 6),
+                    expand_bits(p, 5),
+                    255,
+                ]
+            }
+            PixelFormat::Rgba4444 => {
+                let p = word();
+                [
+                    expand_bits(p >> 12, 4),
+                    expand_bits(p >> 8, 4),
+                    expand_bits(p >> 4, 4),
+                    expand_bits(p, 4),
+                ]
+            }
+            PixelFormat::Rgb888 => [pixel[0], pixel[1], pixel[2], 255],
+            PixelFormat::Rgbx888 => [pixel[1], pixel[2], pixel[3], 255],
+            PixelFormat::Rgba8888 => [pixel[0], pixel[1], pixel[2], pixel[3]],
+            _ => [0, 0, 0, 255],
+        }
+    }
+
+    /// Encode RGBA into one pixel of a direct color format, the reverse of decode().
+    /// Components are rounded to the nearest value the format can hold.
+    /// Indexed and unknown formats have no color of their own and leave the pixel untouched.
+    pub fn encode(self, rgba: [u8; 4], pixel: &mut [u8]) {
+        let [r, g, b, a] = rgba.map(u16::from);
+        let bits = |c: u16, bits: u32| (c * ((1 << bits) - 1) + 127) / 255;
+        let word = match self {
+            PixelFormat::Rgb555 => bits(r, 5) << 10 | bits(g, 5) << 5 | bits(b, 5),
+            PixelFormat::Bgr555 => bits(b, 5) << 10 | bits(g, 5) << 5 | bits(r, 5),
+            PixelFormat::Rgb565 => bits(r, 5) << 11 | bits(g, 6) << 5 | bits(b, 5),
+            PixelFormat::Rgba4444 => {
+                bits(r, 4) << 12 | bits(g, 4) << 8 | bits(b, 4) << 4 | bits(a, 4)
+            }
+            PixelFormat::Rgb888 => return pixel[..3].copy_from_slice(&rgba[..3]),
+            PixelFormat::Rgbx888 => {
+                pixel[0] = 255 - rgba[3];
+                return pixel[1..4].copy_from_slice(&rgba[..3]);
+            }
+            PixelFormat::Rgba8888 => return pixel[..4].copy_from_slice(&rgba),
+            _ => return,
+        };
+        pixel[..2].copy_from_slice(&word.to_be_bytes());
+    }
+}
+
+/// How indexed colors get their alpha when remapped to RGBA.
+/// The game draws the color key index as a hole and everything else opaque,
+/// palettes may carry alpha of their own too.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Transparency {
+    pub color_key: Option<u8>, // index that is fully transparent, 0 in the game
+    pub palette_alpha: bool,   // take alpha of the other indices from the palette
+}
+
+impl Default for Transparency {
+    fn default() -> Self {
+        Transparency {
+            color_key: Some(0),
+            palette_alpha: true,
+        }
+    }
+}
+
+impl Transparency {
+    /// Every index opaque, e.g. for images shown without a background.
+    pub const OPAQUE: Transparency = Transparency {
+        color_key: None,
+        palette_alpha: false,
+    };
+
+    pub fn lookup(&self, palette: &Palette, index: u8) -> [u8; 4] {
+        let [r, g, b, a] = palette.rgba(index);
+        if self.color_key == Some(index) {
+            [r, g, b, 0]
+        } else if self.palette_alpha {
+            [r, g, b, a]
+        } else {
+            [r, g, b, 255]
+        }
+    }
+}
+
+/// Error diffusion used when quantizing to a palette.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Dither {
+    #[default]
+    None,
+    FloydSteinberg,
+}
+
+impl std::fmt::Display for PixelFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            PixelFormat::Other(pixel_type) => write!(f, "pixel type {}", pixel_type),
+            format => write!(f, "{:?}", format),
+        }
+    }
+}
+
 // Pixmap consists of two chunks: name and data
 // TODO: use shared_data_t for pixmap contents to avoid copying.
 #[derive(Default, Clone)]
 pub struct PixelMap {
     pub name: String,
-    pub w: u16, // Actual texture w & h
+    pub w: u16,
     pub h: u16,
-    use_w: u16, // and how much of that is used for useful data
-    use_h: u16,
+    // BRender origin, the point pixelmap is positioned by. The middle for textures,
+    // the hot spot for cursors, the fully lit row for shade tables. Files hold a single
+    // level of w x h pixels, there are no stored mipmaps or used sub-rectangle.
+    pub origin_x: u16,
+    pub origin_y: u16,
+    pub format: PixelFormat,
+    pub row_bytes: u16, // rows may be padded, 0 means rows are tightly packed
     pub units: u32,
     pub unit_bytes: u32,
-    pub data: Vec<u8>, // temp pub
+    pub data: Vec<u8>,            // temp pub
+    pub extra_chunks: Vec<Chunk>, // unexpected chunks kept with Strictness::Keep
 }
 
 impl std::fmt::Display for PixelMap {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(
             f,
-            "{} ({}x{}, use {}x{}) {} units {} bytes each",
-            self.name, self.w, self.h, self.use_w, self.use_h, self.units, self.unit_bytes
+            "{} ({}x{}, origin {},{}) {} {} units {} bytes each",
+            self.name,
+            self.w,
+            self.h,
+            self.origin_x,
+            self.origin_y,
+            self.format,
+            self.units,
+            self.unit_bytes
         )
     }
 }
@@ -45,16 +269,19 @@ impl std::fmt::Display for PixelMap {
  * Megatexture for storing all loaded textures.
  * Usually 1024x1024 or 4096x4096 texture with multiple smaller textures inside.
  */
-#[derive(Default)]
+#[derive(Default, Clone)]
 pub struct Texture {
     pub w: u16,
     pub h: u16,
+    pub format: PixelFormat, // Rgba8888 or Index8 for palette-indexed rendering
     pub data: Vec<u8>,
 }
 
 /**
  * Named reference into the megatexture.
+ * Id is the atlas page, corners are fractions of page size with y0 at the top row.
  */
+#[derive(Clone, Debug, PartialEq)]
 pub struct TextureReference {
     pub id: i32,
     pub x0: f32,
@@ -64,122 +291,338 @@ pub struct TextureReference {
     pub name: String,
 }
 
+impl TextureReference {
+    /// Map vertex tex_coords of the source pixelmap into the page.
+    /// Vertex v runs bottom-up like pages uploaded by from_raw_rgba_reversed.
+    pub fn map(&self, tex_coords: [f32; 2]) -> [f32; 2] {
+        let [u, v] = tex_coords;
+        [
+            self.x0 + u * (self.x1 - self.x0),
+            1.0 - (self.y0 + (1.0 - v) * (self.y1 - self.y0)),
+        ]
+    }
+}
+
 impl PixelMap {
-    /// Convert indexed-color image to RGBA using provided palette.
-    pub fn remap_via(&self, palette: &PixelMap) -> Result<PixelMap, Error> {
+    fn error(&self, reason: String) -> Error {
+        Error::BadPixelmap {
+            name: self.name.clone(),
+            reason,
+        }
+    }
+
+    // Blank pixelmap laid out like the game files: rows padded to 4 bytes
+    // and origin in the middle.
+    fn blank(name: String, w: u16, h: u16, format: PixelFormat) -> PixelMap {
+        let bpp = format.bytes_per_pixel().unwrap_or(1);
+        let row_bytes = (usize::from(w) * bpp + 3) & !3;
+        let units = (row_bytes * usize::from(h)).div_ceil(bpp);
+        PixelMap {
+            name,
+            w,
+            h,
+            origin_x: w / 2,
+            origin_y: h / 2,
+            format,
+            row_bytes: row_bytes as u16,
+            units: units as u32,
+            unit_bytes: bpp as u32,
+            data: vec![0; units * bpp],
+            extra_chunks: Vec::new(),
+        }
+    }
+
+    // Convert pixels to tightly packed RGBA rows, dropping row padding.
+    // Indexed colors are converted by the lookup function.
+    fn convert<F: Fn(u8) -> [u8; 4]>(&self, lookup: F) -> Result<Vec<u8>, Error> {
+        let bpp = self
+            .format
+            .bytes_per_pixel()
+            .ok_or_else(|| self.error(format!("unsupported {}", self.format)))?;
+        let (w, h) = (self.w as usize, self.h as usize);
+        if w == 0 || h == 0 {
+            return Ok(vec![]);
+        }
+        let stride = match self.row_bytes {
+            0 => w * bpp,
+            row_bytes => row_bytes as usize,
+        };
+        if stride < w * bpp || self.data.len() < stride * h {
+            return Err(self.error(format!(
+                "{} bytes of data for {}x{} {} with {} bytes per row",
+                self.data.len(),
+                w,
+                h,
+                self.format,
+                stride
+            )));
+        }
+
+        let mut out = Vec::<u8>::with_capacity(w * h * 4);
+        for row in self.data.chunks(stride).take(h) {
+            for pixel in row[..w * bpp].chunks(bpp) {
+                let rgba = match self.format {
+                    PixelFormat::Index8 => lookup(pixel[0]),
+                    format => format.decode(pixel),
+                };
+                out.extend_from_slice(&rgba);
+            }
+        }
+        Ok(out)
+    }
+
+    /// Convert image to RGBA using provided palette for indexed colors,
+    /// with index 0 transparent like the game draws it.
+    pub fn remap_via(&self, palette: &Palette) -> Result<PixelMap, Error> {
+        self.remap_with(palette, Transparency::default())
+    }
+
+    pub fn remap_with(
+        &self,
+        palette: &Palette,
+        transparency: Transparency,
+    ) -> Result<PixelMap, Error> {
         let mut pm = self.clone();
-        pm.data = Vec::<u8>::with_capacity(self.data.len() * 4);
+        pm.data = self.convert(|index| transparency.lookup(palette, index))?;
+        pm.format = PixelFormat::Rgba8888;
         pm.unit_bytes = 4;
+        pm.units = u32::from(pm.w) * u32::from(pm.h);
+        pm.row_bytes = 0;
+        Ok(pm)
+    }
 
-        for i in 0..pm.units {
-            // temp use color index 0 as transparency
-            if self.data[i as usize] == 0 {
-                pm.data.push(0); // R
-                pm.data.push(0); // G
-                pm.data.push(0); // B
-                pm.data.push(255); // A = transparent
-            } else {
-                pm.data.push(
-                    palette.data[(self.data[i as usize] as u32 * palette.unit_bytes + 1) as usize],
-                ); // R
-                pm.data.push(
-                    palette.data[(self.data[i as usize] as u32 * palette.unit_bytes + 2) as usize],
-                ); // G
-                pm.data.push(
-                    palette.data[(self.data[i as usize] as u32 * palette.unit_bytes + 3) as usize],
-                ); // B
-                pm.data.push(
-                    255 - palette.data
-                        [(self.data[i as usize] as u32 * palette.unit_bytes + 0) as usize],
-                ); // A
-                if self.name == "BGLSPIKE.PIX" {
-                    trace!("spike alpha {}", pm.data.last().unwrap());
-                }
+    /// Convert a direct color pixelmap to another direct color format, e.g. one of the 16-bit ones.
+    /// Indexed pixelmaps need remap_via() first, use quantize_via() to go the other way.
+    pub fn convert_to(&self, format: PixelFormat) -> Result<PixelMap, Error> {
+        if self.format == PixelFormat::Index8 || format == PixelFormat::Index8 {
+            return Err(self.error(format!("can't convert {} to {}", self.format, format)));
+        }
+        if format.bytes_per_pixel().is_none() {
+            return Err(self.error(format!("unsupported {}", format)));
+        }
+        let rgba = self.convert(|_| [0, 0, 0, 255])?;
+        let mut pm = PixelMap::blank(self.name.clone(), self.w, self.h, format);
+        let (w, bpp) = (usize::from(self.w), pm.unit_bytes as usize);
+        for (row, pixels) in pm
+            .data
+            .chunks_mut(usize::from(pm.row_bytes))
+            .zip(rgba.chunks(w * 4))
+        {
+            for (pixel, color) in row.chunks_mut(bpp).zip(pixels.chunks(4)) {
+                format.encode(color.try_into().unwrap(), pixel);
             }
         }
+        Ok(pm)
+    }
+
+    /// Quantize a direct color pixelmap to 8-bit indices into palette.
+    /// Pixels less than half opaque get index 0, which the game keeps for transparency,
+    /// the rest get the nearest of the other colours. Floyd-Steinberg dithering spreads
+    /// the difference over the following pixels, transparent ones ignore it.
+    pub fn quantize_via(&self, palette: &Palette, dither: Dither) -> Result<PixelMap, Error> {
+        if self.format == PixelFormat::Index8 {
+            return Err(self.error(format!("already {}", self.format)));
+        }
+        let rgba = self.convert(|_| [0, 0, 0, 255])?;
+        let mut pm = PixelMap::blank(self.name.clone(), self.w, self.h, PixelFormat::Index8);
+        let (w, stride) = (usize::from(self.w), usize::from(pm.row_bytes));
 
+        // Error carried to this and the next row, in 16ths, with a spare pixel on both sides.
+        let mut errors = vec![[0i32; 3]; 2 * (w + 2)];
+        for (y, pixels) in rgba.chunks(w * 4).enumerate() {
+            let (this, next) = errors.split_at_mut(w + 2);
+            for (x, color) in pixels.chunks(4).enumerate() {
+                if color[3] < 128 {
+                    continue; // data is zeroed already
+                }
+                let wanted: [i32; 3] = std::array::from_fn(|c| {
+                    (i32::from(color[c]) + this[x + 1][c] / 16).clamp(0, 255)
+                });
+                let index = palette.nearest(wanted);
+                pm.data[y * stride + x] = index;
+                if dither == Dither::FloydSteinberg {
+                    let got = palette.rgba(index);
+                    for c in 0..3 {
+                        let error = wanted[c] - i32::from(got[c]);
+                        this[x + 2][c] += error * 7;
+                        next[x][c] += error * 3;
+                        next[x + 1][c] += error * 5;
+                        next[x + 2][c] += error;
+                    }
+                }
+            }
+            errors.copy_within(w + 2.., 0);
+            errors[w + 2..].fill([0; 3]);
+        }
         Ok(pm)
     }
 
+    /// Mip chain of an Rgba8888 pixelmap, like remap_via() produces, or an Index8 one.
+    /// Game files never store mipmaps, they have to be generated.
+    pub fn mip_levels(&self) -> Result<Vec<Texture>, Error> {
+        Ok(self.texture()?.mip_levels())
+    }
+
+    /// Pixels of an Rgba8888 or Index8 pixelmap as a single level with packed rows.
+    pub fn texture(&self) -> Result<Texture, Error> {
+        let bpp = match self.format {
+            PixelFormat::Rgba8888 => 4,
+            PixelFormat::Index8 => 1,
+            format => return Err(self.error(format!("no texture for {}", format))),
+        };
+        let (w, h) = (usize::from(self.w), usize::from(self.h));
+        let stride = match self.row_bytes {
+            0 => w * bpp,
+            row_bytes => usize::from(row_bytes),
+        };
+        if w == 0 || h == 0 || stride < w * bpp || self.data.len() < stride * h {
+            return Err(self.error(format!(
+                "{} bytes of data for {}x{} {} with {} bytes per row",
+                self.data.len(),
+                w,
+                h,
+                self.format,
+                stride
+            )));
+        }
+        Ok(Texture {
+            w: self.w,
+            h: self.h,
+            format: self.format,
+            data: self
+                .data
+                .chunks(stride)
+                .take(h)
+                .flat_map(|row| &row[..w * bpp])
+                .copied()
+                .collect(),
+        })
+    }
+
+    /// Read a PNG image into a tightly packed Rgba8888 pixelmap,
+    /// to be quantized or converted to a format the game uses.
+    pub fn read_png<R: Read>(name: String, rdr: R) -> Result<PixelMap, Error> {
+        let error = |reason: String| Error::BadPixelmap {
+            name: name.clone(),
+            reason,
+        };
+        let mut decoder = png::Decoder::new(rdr);
+        // Palette and low bit depth images come out as 8-bit gray or RGB, tRNS as alpha.
+        decoder.set_transformations(png::Transformations::normalize_to_color8());
+        let mut reader = decoder.read_info().map_err(|e| error(e.to_string()))?;
+        let mut buf = vec![0; reader.output_buffer_size()];
+        let info = reader
+            .next_frame(&mut buf)
+            .map_err(|e| error(e.to_string()))?;
+        let (Ok(w), Ok(h)) = (u16::try_from(info.width), u16::try_from(info.height)) else {
+            return Err(error(format!("{}x{} is too big", info.width, info.height)));
+        };
+
+        let pixels = &buf[..info.buffer_size()];
+        let data: Vec<u8> = match info.color_type {
+            png::ColorType::Grayscale => pixels.iter().flat_map(|&l| [l, l, l, 255]).collect(),
+            png::ColorType::GrayscaleAlpha => pixels
+                .chunks(2)
+                .flat_map(|p| [p[0], p[0], p[0], p[1]])
+                .collect(),
+            png::ColorType::Rgb => pixels
+                .chunks(3)
+                .flat_map(|p| [p[0], p[1], p[2], 255])
+                .collect(),
+            png::ColorType::Rgba => pixels.to_vec(),
+            other => return Err(error(format!("unexpected {:?} output", other))),
+        };
+        Ok(PixelMap {
+            name,
+            w,
+            h,
+            format: PixelFormat::Rgba8888,
+            units: u32::from(w) * u32::from(h),
+            unit_bytes: 4,
+            data,
+            ..Default::default()
+        })
+    }
+
+    /// Load a PNG file named like the game would name the pixelmap, e.g. SKULL.PIX for skull.png.
+    pub fn import_png(fname: String) -> Result<PixelMap, Error> {
+        let path = PathBuf::from(&fname);
+        let name = path
+            .file_stem()
+            .map(|stem| format!("{}.PIX", stem.to_string_lossy().to_uppercase()))
+            .unwrap_or_default();
+        let file = File::open(&path).map_err(|e| Error::from(e).in_file(&path))?;
+        PixelMap::read_png(name, BufReader::new(file)).map_err(|e| e.in_file(&path))
+    }
+
     pub fn write_png_remapped_via<W: Write>(
         &self,
-        palette: &PixelMap,
+        palette: &Palette,
+        w: &mut W,
+    ) -> Result<(), Error> {
+        self.write_png_remapped_with(palette, Transparency::default(), w)
+    }
+
+    /// Write RGBA PNG, indexed colors get alpha as set by transparency.
+    pub fn write_png_remapped_with<W: Write>(
+        &self,
+        palette: &Palette,
+        transparency: Transparency,
         w: &mut W,
     ) -> Result<(), Error> {
         self.dump();
 
+        let data = self.convert(|index| transparency.lookup(palette, index))?;
+
         let mut encoder = png::Encoder::new(w, self.w as u32, self.h as u32);
-        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_color(png::ColorType::Rgba);
         encoder.set_depth(png::BitDepth::Eight);
-        let mut writer = encoder.write_header().unwrap();
-
-        let mut data = Vec::<u8>::with_capacity(self.data.len() * 4);
-
-        match self.unit_bytes {
-            1 => {
-                for i in 0..self.units {
-                    data.push(
-                        palette.data
-                            [(self.data[i as usize] as u32 * palette.unit_bytes + 1) as usize],
-                    ); // R
-                    data.push(
-                        palette.data
-                            [(self.data[i as usize] as u32 * palette.unit_bytes + 2) as usize],
-                    ); // G
-                    data.push(
-                        palette.data
-                            [(self.data[i as usize] as u32 * palette.unit_bytes + 3) as usize],
-                    ); // B
-                       // data.push(
-                       // 255-palette.data[(self.data[i as usize] as u32 * palette.unit_bytes + 0) as
-                       // usize],
-                       // ); // A
-                }
-            }
-            3 => {
-                for i in 0..self.units {
-                    data.push(self.data[(i * self.unit_bytes + 0) as usize]); // R
-                    data.push(self.data[(i * self.unit_bytes + 1) as usize]); // G
-                    data.push(self.data[(i * self.unit_bytes + 2) as usize]); // B
-                                                                              // data.push(255); // A
-                }
-            }
-            4 => {
-                for i in 0..self.units {
-                    data.push(self.data[(i * self.unit_bytes + 0) as usize]); // R
-                    data.push(self.data[(i * self.unit_bytes + 1) as usize]); // G
-                    data.push(self.data[(i * self.unit_bytes + 2) as usize]); // B
-                                                                              // data.push(self.data[(i * self.unit_bytes + 3) as usize]); // A
-                }
-            }
-            _ => unimplemented!(),
-        }
-
-        writer.write_image_data(&data).unwrap();
+        let mut writer = encoder
+            .write_header()
+            .map_err(|e| self.error(e.to_string()))?;
+        writer
+            .write_image_data(&data)
+            .map_err(|e| self.error(e.to_string()))?;
         Ok(())
     }
 
-    pub fn load<R: ReadBytesExt + BufRead>(rdr: &mut R) -> Result<PixelMap, Error> {
+    pub fn load<R: Read>(rdr: &mut R) -> Result<PixelMap, Error> {
+        PixelMap::load_with(rdr, Strictness::default())
+    }
+
+    pub fn load_with<R: Read>(rdr: &mut R, strictness: Strictness) -> Result<PixelMap, Error> {
+        PixelMap::from_chunks(&mut ChunkReader::new(rdr), strictness)
+    }
+
+    /// Load single pixelmap from the chunk stream, up to its Null terminator.
+    pub fn from_chunks<R: Read>(
+        chunks: &mut ChunkReader<R>,
+        strictness: Strictness,
+    ) -> Result<PixelMap, Error> {
         let mut pm = PixelMap::default();
 
         // Read chunks until last chunk is encountered.
         // Certain chunks initialize certain properties.
-        loop {
-            let c = Chunk::load(rdr)?;
+        for chunk in chunks.record() {
+            let (offset, c) = chunk?;
             match c {
                 Chunk::PixelmapHeader {
                     name,
                     w,
                     h,
-                    mipmap_w,
-                    mipmap_h,
+                    origin_x,
+                    origin_y,
+                    pixel_type,
+                    row_bytes,
                 } => {
                     pm.name = name;
                     pm.w = w;
                     pm.h = h;
-                    pm.use_w = mipmap_w;
-                    pm.use_h = mipmap_h;
-                    debug!("Pixelmap {}x{} use {}x{}", w, h, mipmap_w, mipmap_h);
+                    pm.origin_x = origin_x;
+                    pm.origin_y = origin_y;
+                    pm.format = PixelFormat::from_pixel_type(pixel_type);
+                    pm.row_bytes = row_bytes;
+                    debug!("Pixelmap {}x{} origin {},{}", w, h, origin_x, origin_y);
                 }
                 Chunk::PixelmapData {
                     units,
@@ -191,13 +634,21 @@ impl PixelMap {
                     pm.data = data;
                     debug!("Pixelmap data {} units, {} bytes each", units, unit_bytes);
                 }
-                Chunk::Null() => break,
-                Chunk::FileHeader { file_type } => {
+                Chunk::FileHeader { file_type, .. } => {
                     if file_type != support::PIXELMAP_FILE_TYPE {
-                        panic!("Invalid pixelmap file type {}", file_type);
+                        return Err(Error::WrongFileType {
+                            file: PathBuf::new(),
+                            offset,
+                            expected: support::PIXELMAP_FILE_TYPE,
+                            found: file_type,
+                        });
+                    }
+                }
+                c => {
+                    if let Some(c) = strictness.check(offset, c)? {
+                        pm.extra_chunks.push(c);
                     }
                 }
-                _ => unimplemented!(), // unexpected type here
             }
         }
 
@@ -205,23 +656,69 @@ impl PixelMap {
     }
 
     pub fn load_from(fname: String) -> Result<Vec<PixelMap>, Error> {
-        let file = File::open(fname)?;
-        let mut file = BufReader::new(file);
-        let mut pmaps = Vec::<PixelMap>::new();
-        loop {
-            let pmap = PixelMap::load(&mut file);
-            match pmap {
-                Err(_) => break, // fixme: allow only Eof here
-                Ok(pmap) => pmaps.push(pmap),
-            }
+        PixelMap::load_from_with(fname, Strictness::default())
+    }
+
+    pub fn load_from_with(fname: String, strictness: Strictness) -> Result<Vec<PixelMap>, Error> {
+        let path = PathBuf::from(fname);
+        let file = File::open(&path).map_err(|e| Error::from(e).in_file(&path))?;
+        let file = BufReader::new(file);
+        load_records(file, support::PIXELMAP_FILE_TYPE, |chunks| {
+            PixelMap::from_chunks(chunks, strictness)
+        })
+        .map_err(|e| e.in_file(&path))
+    }
+
+    pub fn save<W: WriteBytesExt>(&self, wr: &mut W) -> Result<(), Error> {
+        Chunk::PixelmapHeader {
+            name: self.name.clone(),
+            w: self.w,
+            h: self.h,
+            origin_x: self.origin_x,
+            origin_y: self.origin_y,
+            pixel_type: self.format.pixel_type(),
+            row_bytes: self.row_bytes,
+        }
+        .write(wr)?;
+        Chunk::PixelmapData {
+            units: self.units,
+            unit_bytes: self.unit_bytes,
+            data: self.data.clone(),
+        }
+        .write(wr)?;
+        for c in &self.extra_chunks {
+            c.write(wr)?;
         }
-        Ok(pmaps)
+        Chunk::Null().write(wr)?;
+        Ok(())
+    }
+
+    pub fn save_to(fname: String, pmaps: &[PixelMap]) -> Result<(), Error> {
+        let file = File::create(fname)?;
+        let mut file = BufWriter::new(file);
+        Chunk::FileHeader {
+            file_type: support::PIXELMAP_FILE_TYPE,
+            version: support::FILE_HEADER_VERSION,
+        }
+        .write(&mut file)?;
+        for pmap in pmaps {
+            pmap.save(&mut file)?;
+        }
+        file.flush()?;
+        Ok(())
     }
 
     fn dump(&self) {
         info!(
-            "Pixelmap {}: {}x{}, mm {}x{}, {}x{} bytes",
-            self.name, self.w, self.h, self.use_w, self.use_h, self.units, self.unit_bytes
+            "Pixelmap {}: {}x{}, origin {},{}, {}, {}x{} bytes",
+            self.name,
+            self.w,
+            self.h,
+            self.origin_x,
+            self.origin_y,
+            self.format,
+            self.units,
+            self.unit_bytes
         );
     }
 }
@@ -231,7 +728,324 @@ impl Texture {
         Texture {
             w: 1024,
             h: 1024,
+            format: PixelFormat::Rgba8888,
             data: Vec::new(),
         }
     }
+
+    /// Mip chain from this texture down to 1x1, each level half the previous one
+    /// with odd sizes rounded down like GL does.
+    /// RGBA texels are averaged weighted by alpha, so colour key holes don't darken
+    /// the edges around them. Indices can't be averaged, each level keeps the top left
+    /// texel of every 2x2 block.
+    pub fn mip_levels(&self) -> Vec<Texture> {
+        let mut levels = vec![self.clone()];
+        while let Some(last) = levels.last().filter(|t| t.w > 1 || t.h > 1) {
+            levels.push(last.halve());
+        }
+        levels
+    }
+
+    fn halve(&self) -> Texture {
+        let (sw, sh) = (usize::from(self.w), usize::from(self.h));
+        let (w, h) = ((sw / 2).max(1), (sh / 2).max(1));
+        let mut data = Vec::with_capacity(w * h * 4);
+        for y in 0..h {
+            for x in 0..w {
+                if self.format == PixelFormat::Index8 {
+                    data.push(self.data[2 * y * sw + 2 * x]);
+                    continue;
+                }
+                let (mut sum, mut count) = ([0u32; 4], 0);
+                for sy in 2 * y..(2 * y + 2).min(sh) {
+                    for sx in 2 * x..(2 * x + 2).min(sw) {
+                        let texel = &self.data[(sy * sw + sx) * 4..][..4];
+                        let alpha = u32::from(texel[3]);
+                        for c in 0..3 {
+                            sum[c] += u32::from(texel[c]) * alpha;
+                        }
+                        sum[3] += alpha;
+                        count += 1;
+                    }
+                }
+                let alpha = sum[3];
+                for c in &mut sum[..3] {
+                    *c = (*c + alpha / 2).checked_div(alpha).unwrap_or(0);
+                }
+                sum[3] = (alpha + count / 2) / count;
+                data.extend(sum.map(|c| c as u8));
+            }
+        }
+        Texture {
+            w: w as u16,
+            h: h as u16,
+            format: self.format,
+            data,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_16bit_formats() {
+        let white = [0xff, 0xff];
+        for format in [
+            PixelFormat::Rgb555,
+            PixelFormat::Rgb565,
+            PixelFormat::Bgr555,
+            PixelFormat::Rgba4444,
+        ] {
+            assert_eq!([255, 255, 255, 255], format.decode(&white));
+        }
+        // 0b0_11111_00000_10000, red and half blue
+        assert_eq!(
+            [255, 0, 132, 255],
+            PixelFormat::Rgb555.decode(&[0x7c, 0x10])
+        );
+        assert_eq!(
+            [132, 0, 255, 255],
+            PixelFormat::Bgr555.decode(&[0x7c, 0x10])
+        );
+        // 0b00000_111111_00000, full green
+        assert_eq!([0, 255, 0, 255], PixelFormat::Rgb565.decode(&[0x07, 0xe0]));
+        assert_eq!(
+            [0x11, 0x22, 0x33, 0x44],
+            PixelFormat::Rgba4444.decode(&[0x12, 0x34])
+        );
+    }
+
+    #[test]
+    fn test_pixel_type_roundtrip() {
+        for pixel_type in 0..=255 {
+            assert_eq!(
+                pixel_type,
+                PixelFormat::from_pixel_type(pixel_type).pixel_type()
+            );
+        }
+    }
+
+    #[rustfmt::skip]
+    #[test]
+    fn test_remap_padded_rows() {
+        let pm = PixelMap {
+            name: String::from("TEST.PIX"),
+            w: 2,
+            h: 2,
+            format: PixelFormat::Rgb565,
+            row_bytes: 6,
+            units: 6,
+            unit_bytes: 2,
+            data: vec![
+                0xf8, 0x00, 0x07, 0xe0, 0xde, 0xad, // red, green, padding
+                0x00, 0x1f, 0x00, 0x00, 0xbe, 0xef, // blue, black, padding
+            ],
+            ..Default::default()
+        };
+        let rgba = pm.remap_via(&Palette::default()).unwrap();
+        assert_eq!(PixelFormat::Rgba8888, rgba.format);
+        assert_eq!(vec![
+            255, 0, 0, 255, 0, 255, 0, 255,
+            0, 0, 255, 255, 0, 0, 0, 255,
+        ], rgba.data);
+
+        let short = PixelMap {
+            data: vec![0; 8],
+            ..pm
+        };
+        assert!(matches!(
+            short.remap_via(&Palette::default()),
+            Err(Error::BadPixelmap { .. })
+        ));
+    }
+
+    #[test]
+    fn test_remap_padded_game_pixelmap() {
+        let palette =
+            Palette::load_from(String::from("DecodedData/DATA/REG/PALETTES/DRRENDER.PAL")).unwrap();
+        let pm = &PixelMap::load_from(String::from("DecodedData/DATA/PIXELMAP/LITGIBB1.PIX"))
+            .unwrap()[0];
+        assert_eq!(PixelFormat::Index8, pm.format);
+        assert_eq!(8, pm.row_bytes);
+        let rgba = pm.remap_via(&palette).unwrap();
+        assert_eq!(5 * 5 * 4, rgba.data.len());
+    }
+
+    #[test]
+    fn test_encode_16bit_formats() {
+        for format in [
+            PixelFormat::Rgb555,
+            PixelFormat::Rgb565,
+            PixelFormat::Bgr555,
+            PixelFormat::Rgba4444,
+        ] {
+            for word in 0..=u16::MAX {
+                let color = format.decode(&word.to_be_bytes());
+                let mut pixel = [0; 2];
+                format.encode(color, &mut pixel);
+                assert_eq!(color, format.decode(&pixel), "{} {:04x}", format, word);
+            }
+        }
+        let mut pixel = [0; 2];
+        PixelFormat::Rgb565.encode([250, 5, 130, 255], &mut pixel);
+        // 250 rounds down to 30 of 31, 5 to 1 of 63, 130 up to 16 of 31.
+        assert_eq!([0xf0, 0x30], pixel);
+    }
+
+    #[test]
+    fn test_quantize_game_pixelmap() {
+        let palette =
+            Palette::load_from(String::from("DecodedData/DATA/REG/PALETTES/DRRENDER.PAL")).unwrap();
+        let pm = &PixelMap::load_from(String::from("DecodedData/DATA/PIXELMAP/LITGIBB1.PIX"))
+            .unwrap()[0];
+        let mut png = Vec::new();
+        pm.write_png_remapped_via(&palette, &mut png).unwrap();
+        let rgba = PixelMap::read_png(pm.name.clone(), png.as_slice()).unwrap();
+        assert_eq!((5, 5, PixelFormat::Rgba8888), (rgba.w, rgba.h, rgba.format));
+
+        // Same layout as the game file and the same colours, transparent ones back at index 0.
+        let quantized = rgba.quantize_via(&palette, Dither::None).unwrap();
+        assert_eq!(
+            (
+                pm.w,
+                pm.h,
+                pm.origin_x,
+                pm.origin_y,
+                pm.row_bytes,
+                pm.units,
+                pm.unit_bytes
+            ),
+            (
+                quantized.w,
+                quantized.h,
+                quantized.origin_x,
+                quantized.origin_y,
+                quantized.row_bytes,
+                quantized.units,
+                quantized.unit_bytes
+            )
+        );
+        for (row, quantized_row) in pm.data.chunks(8).zip(quantized.data.chunks(8)) {
+            for (index, got) in row[..5].iter().zip(&quantized_row[..5]) {
+                if *index == 0 {
+                    assert_eq!(0, *got);
+                } else {
+                    assert_eq!(palette.rgba(*index), palette.rgba(*got));
+                }
+            }
+        }
+
+        // And it saves as a pixelmap file that loads back.
+        let mut data = Vec::new();
+        quantized.save(&mut data).unwrap();
+        let loaded = PixelMap::load(&mut data.as_slice()).unwrap();
+        assert_eq!(quantized.data, loaded.data);
+
+        let wide = rgba.convert_to(PixelFormat::Rgb565).unwrap();
+        assert_eq!((12, 2, 30), (wide.row_bytes, wide.unit_bytes, wide.units));
+        assert!(matches!(
+            pm.convert_to(PixelFormat::Rgb565),
+            Err(Error::BadPixelmap { .. })
+        ));
+    }
+
+    #[test]
+    fn test_quantize_dither_and_transparency() {
+        let mut palette = Palette::default();
+        palette.set_rgba(2, [255, 255, 255, 255]);
+        let mut grey = PixelMap {
+            name: String::from("GREY.PIX"),
+            w: 4,
+            h: 4,
+            format: PixelFormat::Rgba8888,
+            units: 16,
+            unit_bytes: 4,
+            data: [128, 128, 128, 255].repeat(16),
+            ..Default::default()
+        };
+        grey.data[3] = 0;
+
+        let flat = grey.quantize_via(&palette, Dither::None).unwrap();
+        assert_eq!(0, flat.data[0]);
+        assert!(flat.data[1..].iter().all(|&index| index == 2));
+
+        // Dithered, roughly half the opaque pixels are white.
+        let dithered = grey.quantize_via(&palette, Dither::FloydSteinberg).unwrap();
+        assert_eq!(0, dithered.data[0]);
+        let white = dithered.data.iter().filter(|&&index| index == 2).count();
+        assert!((6..=9).contains(&white), "{} white", white);
+        assert!(dithered.data[1..].iter().all(|&index| index != 0));
+    }
+
+    #[test]
+    fn test_color_key_transparency() {
+        let palette =
+            Palette::load_from(String::from("DecodedData/DATA/REG/PALETTES/DRRENDER.PAL")).unwrap();
+        let pmaps =
+            PixelMap::load_from(String::from("DecodedData/DATA/PIXELMAP/EAGYELE.PIX")).unwrap();
+        let spike = pmaps.iter().find(|pm| pm.name == "BGLSPIKE.PIX").unwrap();
+        let holes = spike.data.iter().filter(|&&index| index == 0).count();
+        assert!(holes > 0);
+
+        let rgba = spike.remap_via(&palette).unwrap();
+        let alpha: Vec<_> = rgba.data.chunks(4).map(|p| p[3]).collect();
+        assert_eq!(holes, alpha.iter().filter(|&&a| a == 0).count());
+        assert_eq!(
+            spike.data.len() - holes,
+            alpha.iter().filter(|&&a| a == 255).count()
+        );
+
+        let opaque = spike.remap_with(&palette, Transparency::OPAQUE).unwrap();
+        assert!(opaque.data.chunks(4).all(|p| p[3] == 255));
+
+        // Exported PNG keeps the holes.
+        let mut png = Vec::new();
+        spike.write_png_remapped_via(&palette, &mut png).unwrap();
+        let decoder = png::Decoder::new(png.as_slice());
+        assert_eq!(
+            png::ColorType::Rgba,
+            decoder.read_info().unwrap().info().color_type
+        );
+        let read = PixelMap::read_png(spike.name.clone(), png.as_slice()).unwrap();
+        assert_eq!(rgba.data, read.data);
+
+        // Palette alpha applies to other indices unless asked not to.
+        let mut faded = palette.clone();
+        faded.set_rgba(1, [1, 2, 3, 128]);
+        assert_eq!([1, 2, 3, 128], Transparency::default().lookup(&faded, 1));
+        assert_eq!([1, 2, 3, 255], Transparency::OPAQUE.lookup(&faded, 1));
+        assert_eq!([0, 0, 0, 0], Transparency::default().lookup(&faded, 0));
+    }
+
+    #[test]
+    fn test_mip_levels() {
+        let pm = &PixelMap::load_from(String::from("DecodedData/DATA/PIXELMAP/LITGIBB1.PIX"))
+            .unwrap()[0];
+        // Origin is the middle, files keep no levels below the full size.
+        assert_eq!((5, 5, 2, 2), (pm.w, pm.h, pm.origin_x, pm.origin_y));
+        assert_eq!(pm.data.len(), usize::from(pm.row_bytes) * usize::from(pm.h));
+
+        let levels = pm.mip_levels().unwrap();
+        let sizes: Vec<_> = levels.iter().map(|t| (t.w, t.h)).collect();
+        assert_eq!(vec![(5, 5), (2, 2), (1, 1)], sizes);
+        // Row padding is dropped, indices are picked rather than averaged.
+        assert_eq!(25, levels[0].data.len());
+        assert_eq!(pm.data[2 * 8 + 2], levels[1].data[3]);
+
+        let rgba = Texture {
+            w: 4,
+            h: 1,
+            format: PixelFormat::Rgba8888,
+            data: vec![
+                200, 100, 0, 255, 0, 0, 0, 0, // colour next to a hole
+                10, 20, 30, 255, 30, 40, 50, 255,
+            ],
+        };
+        let levels = rgba.mip_levels();
+        assert_eq!(3, levels.len());
+        assert_eq!(vec![200, 100, 0, 128, 20, 30, 40, 255], levels[1].data);
+        assert_eq!((1, 1), (levels[2].w, levels[2].h));
+    }
 }
@@ -0,0 +1,815 @@
+//
+// Part of Roadkill Project.
+//
+// Copyright 2010-2018, Berkus <berkus+github@metta.systems>
+//
+// Distributed under the Boost Software License, Version 1.0.
+// (See file LICENSE_1_0.txt or a copy at http://www.boost.org/LICENSE_1_0.txt)
+//
+//! Cross-check kaitai/carma.ksy against the hand-written chunk reader.
+//!
+//! The schema is run by a small interpreter supporting the subset of Kaitai Struct
+//! the file uses: primitive and user types, switch-on, repeat, size, if and value
+//! instances with simple arithmetic expressions. Every game file is parsed by both,
+//! chunks must start at the same offsets and fields known to the reader must match.
+use {
+    carma::support::{
+        resource::{Chunk, ChunkReader},
+        Error,
+    },
+    std::{collections::HashMap, fs, path::Path},
+    yaml_rust::{Yaml, YamlLoader},
+};
+
+#[derive(Clone, Debug)]
+enum Value<'s> {
+    Int(u64),
+    Float(f64),
+    Str(String),
+    Bytes(Vec<u8>),
+    Array(Vec<Value<'s>>),
+    Struct(Obj<'s>),
+    Nothing, // switch-on without matching case, field with false `if`
+}
+
+// Floats compare by bits, so NaNs in the data don't fail the check.
+impl PartialEq for Value<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Value::Int(a), Value::Int(b)) => a == b,
+            (Value::Float(a), Value::Float(b)) => a.to_bits() == b.to_bits(),
+            (Value::Str(a), Value::Str(b)) => a == b,
+            (Value::Bytes(a), Value::Bytes(b)) => a == b,
+            (Value::Array(a), Value::Array(b)) => a == b,
+            (Value::Struct(a), Value::Struct(b)) => a.fields == b.fields,
+            (Value::Nothing, Value::Nothing) => true,
+            _ => false,
+        }
+    }
+}
+
+/// Parsed user type instance, with the byte range it was read from.
+#[derive(Clone, Debug, Default)]
+struct Obj<'s> {
+    start: usize,
+    end: usize,
+    fields: Vec<(&'s str, Value<'s>)>,
+}
+
+impl<'s> Obj<'s> {
+    fn get(&self, name: &str) -> Option<&Value<'s>> {
+        self.fields.iter().find(|(n, _)| *n == name).map(|(_, v)| v)
+    }
+}
+
+// Schema compiled from YAML once, so parsing doesn't do hash lookups per field.
+
+#[derive(Debug)]
+enum Expr {
+    Int(u64),
+    Path(Vec<String>),
+    Binary(Box<Expr>, String, Box<Expr>),
+}
+
+#[derive(Debug)]
+enum Type {
+    Unsigned(usize, bool), // size, big endian
+    Float(usize, bool),
+    Strz,
+    User(String),
+    Switch(Expr, Vec<(Option<Expr>, Type)>), // None is the default case
+    Bytes,                                   // no type, only size
+}
+
+#[derive(Debug)]
+enum Repeat {
+    No,
+    Expr(Expr),
+    Eos,
+}
+
+#[derive(Debug)]
+struct Attr {
+    id: String,
+    typ: Type,
+    size: Option<Expr>,
+    repeat: Repeat,
+    cond: Option<Expr>,
+}
+
+#[derive(Debug, Default)]
+struct TypeSpec {
+    seq: Vec<Attr>,
+    instances: Vec<(String, Expr)>,
+}
+
+struct Schema {
+    root: TypeSpec,
+    types: HashMap<String, TypeSpec>,
+}
+
+impl Schema {
+    fn load(path: &str) -> Schema {
+        let text = fs::read_to_string(path).unwrap();
+        let root = YamlLoader::load_from_str(&text)
+            .unwrap()
+            .into_iter()
+            .next()
+            .unwrap();
+        let compiler = Compiler {
+            root: &root,
+            big_endian: root["meta"]["endian"].as_str() == Some("be"),
+        };
+        let mut types = HashMap::new();
+        for (name, spec) in root["types"].as_hash().into_iter().flatten() {
+            let name = name.as_str().unwrap().to_string();
+            let spec = compiler
+                .type_spec(spec)
+                .unwrap_or_else(|e| panic!("{}: {}", name, e));
+            types.insert(name, spec);
+        }
+        Schema {
+            root: compiler.type_spec(&root).unwrap(),
+            types,
+        }
+    }
+
+    fn parse<'s>(&'s self, data: &[u8]) -> Result<Obj<'s>, String> {
+        let mut parser = Parser {
+            schema: self,
+            data,
+            pos: 0,
+            end: data.len(),
+            scopes: vec![],
+        };
+        parser.parse_user_type(&self.root)
+    }
+}
+
+struct Compiler<'y> {
+    root: &'y Yaml,
+    big_endian: bool,
+}
+
+impl Compiler<'_> {
+    fn type_spec(&self, spec: &Yaml) -> Result<TypeSpec, String> {
+        let mut seq = vec![];
+        for attr in spec["seq"].as_vec().into_iter().flatten() {
+            seq.push(self.attr(attr)?);
+        }
+        let mut instances = vec![];
+        for (name, instance) in spec["instances"].as_hash().into_iter().flatten() {
+            let name = name.as_str().unwrap_or_default().to_string();
+            instances.push((name, self.expr(&instance["value"])?));
+        }
+        Ok(TypeSpec { seq, instances })
+    }
+
+    fn attr(&self, attr: &Yaml) -> Result<Attr, String> {
+        let optional = |yaml: &Yaml| match yaml {
+            Yaml::BadValue => Ok(None),
+            yaml => self.expr(yaml).map(Some),
+        };
+        let typ = match &attr["type"] {
+            Yaml::String(name) => self.named_type(name),
+            Yaml::BadValue => Type::Bytes,
+            spec => {
+                let mut cases = vec![];
+                for (case, name) in spec["cases"].as_hash().into_iter().flatten() {
+                    let case = match case.as_str() {
+                        Some("_") => None,
+                        _ => Some(self.expr(case)?),
+                    };
+                    cases.push((case, self.named_type(name.as_str().unwrap_or_default())));
+                }
+                Type::Switch(self.expr(&spec["switch-on"])?, cases)
+            }
+        };
+        let repeat = match attr["repeat"].as_str() {
+            None => Repeat::No,
+            Some("expr") => Repeat::Expr(self.expr(&attr["repeat-expr"])?),
+            Some("eos") => Repeat::Eos,
+            Some(repeat) => return Err(format!("unsupported repeat {}", repeat)),
+        };
+        Ok(Attr {
+            id: attr["id"].as_str().unwrap_or("_").to_string(),
+            typ,
+            size: optional(&attr["size"])?,
+            repeat,
+            cond: optional(&attr["if"])?,
+        })
+    }
+
+    fn named_type(&self, name: &str) -> Type {
+        let (base, big_endian) = match (name.strip_suffix("be"), name.strip_suffix("le")) {
+            (Some(base), _) if base.len() == 2 => (base, true),
+            (_, Some(base)) if base.len() == 2 => (base, false),
+            _ => (name, self.big_endian),
+        };
+        match base {
+            "u1" | "u2" | "u4" | "u8" => Type::Unsigned(base[1..].parse().unwrap(), big_endian),
+            "f4" | "f8" => Type::Float(base[1..].parse().unwrap(), big_endian),
+            "strz" => Type::Strz,
+            _ => Type::User(name.to_string()),
+        }
+    }
+
+    fn expr(&self, yaml: &Yaml) -> Result<Expr, String> {
+        let text = match yaml {
+            Yaml::String(s) => s.clone(),
+            Yaml::Integer(n) => n.to_string(),
+            yaml => return Err(format!("bad expression {:?}", yaml)),
+        };
+        let tokens = tokenize(&text)?;
+        let mut pos = 0;
+        let expr = self.compare(&tokens, &mut pos)?;
+        if pos != tokens.len() {
+            return Err(format!("trailing tokens in expression {:?}", text));
+        }
+        Ok(expr)
+    }
+
+    fn compare(&self, tokens: &[String], pos: &mut usize) -> Result<Expr, String> {
+        let left = self.sum(tokens, pos)?;
+        match tokens.get(*pos) {
+            Some(op) if op == "==" || op == "!=" => {
+                *pos += 1;
+                let right = self.sum(tokens, pos)?;
+                Ok(Expr::Binary(Box::new(left), op.clone(), Box::new(right)))
+            }
+            _ => Ok(left),
+        }
+    }
+
+    fn sum(&self, tokens: &[String], pos: &mut usize) -> Result<Expr, String> {
+        let mut expr = self.product(tokens, pos)?;
+        while let Some(op) = tokens.get(*pos).filter(|op| *op == "+" || *op == "-") {
+            *pos += 1;
+            let right = self.product(tokens, pos)?;
+            expr = Expr::Binary(Box::new(expr), op.clone(), Box::new(right));
+        }
+        Ok(expr)
+    }
+
+    fn product(&self, tokens: &[String], pos: &mut usize) -> Result<Expr, String> {
+        let mut expr = self.primary(tokens, pos)?;
+        while tokens.get(*pos).is_some_and(|op| op == "*") {
+            *pos += 1;
+            let right = self.primary(tokens, pos)?;
+            expr = Expr::Binary(Box::new(expr), "*".into(), Box::new(right));
+        }
+        Ok(expr)
+    }
+
+    fn primary(&self, tokens: &[String], pos: &mut usize) -> Result<Expr, String> {
+        let token = tokens.get(*pos).ok_or("unexpected end of expression")?;
+        *pos += 1;
+        if token == "(" {
+            let expr = self.compare(tokens, pos)?;
+            *pos += 1; // ")"
+            return Ok(expr);
+        }
+        if let Some(hex) = token.strip_prefix("0x") {
+            return u64::from_str_radix(hex, 16)
+                .map(Expr::Int)
+                .map_err(|e| e.to_string());
+        }
+        if token.as_bytes()[0].is_ascii_digit() {
+            return token.parse().map(Expr::Int).map_err(|e| format!("{}", e));
+        }
+        if let Some((enum_name, name)) = token.split_once("::") {
+            return self.enum_value(enum_name, name).map(Expr::Int);
+        }
+        Ok(Expr::Path(token.split('.').map(String::from).collect()))
+    }
+
+    fn enum_value(&self, enum_name: &str, name: &str) -> Result<u64, String> {
+        let values = self.root["enums"][enum_name]
+            .as_hash()
+            .ok_or_else(|| format!("unknown enum {}", enum_name))?;
+        values
+            .iter()
+            .find(|(_, v)| v.as_str() == Some(name))
+            .and_then(|(k, _)| k.as_i64())
+            .map(|k| k as u64)
+            .ok_or_else(|| format!("unknown enum value {}::{}", enum_name, name))
+    }
+}
+
+fn tokenize(expr: &str) -> Result<Vec<String>, String> {
+    let mut tokens = vec![];
+    let mut chars = expr.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c.is_ascii_alphanumeric() || c == '_' {
+            let mut token = String::new();
+            while let Some(&c) = chars.peek() {
+                if !(c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == ':') {
+                    break;
+                }
+                token.push(c);
+                chars.next();
+            }
+            tokens.push(token);
+        } else if c == '=' || c == '!' {
+            chars.next();
+            if chars.next() != Some('=') {
+                return Err(format!("bad operator in {:?}", expr));
+            }
+            tokens.push(format!("{}=", c));
+        } else if "+-*()".contains(c) {
+            tokens.push(c.to_string());
+            chars.next();
+        } else {
+            return Err(format!("unexpected {:?} in {:?}", c, expr));
+        }
+    }
+    Ok(tokens)
+}
+
+/// Type being parsed and fields read so far, for expression evaluation.
+struct Scope<'s> {
+    spec: &'s TypeSpec,
+    fields: Vec<(&'s str, Value<'s>)>,
+}
+
+struct Parser<'s, 'd> {
+    schema: &'s Schema,
+    data: &'d [u8],
+    pos: usize,
+    end: usize,
+    scopes: Vec<Scope<'s>>,
+}
+
+impl<'s> Parser<'s, '_> {
+    fn read_bytes(&mut self, n: usize) -> Result<&[u8], String> {
+        if self.end - self.pos < n {
+            return Err(format!(
+                "end of stream reading {} bytes at {:#x}",
+                n, self.pos
+            ));
+        }
+        self.pos += n;
+        Ok(&self.data[self.pos - n..self.pos])
+    }
+
+    fn read_int(&mut self, n: usize, big_endian: bool) -> Result<u64, String> {
+        let bytes = self.read_bytes(n)?;
+        let fold = |acc, b: &u8| (acc << 8) | u64::from(*b);
+        Ok(if big_endian {
+            bytes.iter().fold(0, fold)
+        } else {
+            bytes.iter().rev().fold(0, fold)
+        })
+    }
+
+    fn parse_user_type(&mut self, spec: &'s TypeSpec) -> Result<Obj<'s>, String> {
+        let start = self.pos;
+        self.scopes.push(Scope {
+            spec,
+            fields: vec![],
+        });
+        let result = self.parse_seq(spec);
+        let scope = self.scopes.pop().unwrap();
+        result.map(|_| Obj {
+            start,
+            end: self.pos,
+            fields: scope.fields,
+        })
+    }
+
+    fn parse_seq(&mut self, spec: &'s TypeSpec) -> Result<(), String> {
+        for attr in &spec.seq {
+            let value = self.parse_attr(attr)?;
+            self.scopes
+                .last_mut()
+                .unwrap()
+                .fields
+                .push((attr.id.as_str(), value));
+        }
+        Ok(())
+    }
+
+    fn parse_attr(&mut self, attr: &'s Attr) -> Result<Value<'s>, String> {
+        if let Some(cond) = &attr.cond {
+            if self.eval(cond)? == 0 {
+                return Ok(Value::Nothing);
+            }
+        }
+        match &attr.repeat {
+            Repeat::No => self.parse_item(attr),
+            Repeat::Expr(count) => {
+                let count = self.eval(count)?;
+                let mut items = vec![];
+                for _ in 0..count {
+                    items.push(self.parse_item(attr)?);
+                }
+                Ok(Value::Array(items))
+            }
+            Repeat::Eos => {
+                let mut items = vec![];
+                while self.pos < self.end {
+                    items.push(self.parse_item(attr)?);
+                }
+                Ok(Value::Array(items))
+            }
+        }
+    }
+
+    fn parse_item(&mut self, attr: &'s Attr) -> Result<Value<'s>, String> {
+        let typ = match &attr.typ {
+            Type::Switch(on, cases) => {
+                let on = self.eval(on)?;
+                let mut found = None;
+                for (case, typ) in cases {
+                    if case
+                        .as_ref()
+                        .map_or(Ok(true), |c| self.eval(c).map(|c| c == on))?
+                    {
+                        found = Some(typ);
+                        break;
+                    }
+                }
+                found
+            }
+            Type::Bytes => None,
+            typ => Some(typ),
+        };
+        let Some(size) = &attr.size else {
+            return match typ {
+                Some(typ) => self.parse_type(typ),
+                None => Ok(Value::Nothing),
+            };
+        };
+
+        // Sized item is parsed in its own substream
+        let size = self.eval(size)? as usize;
+        let start = self.pos;
+        self.read_bytes(size)?;
+        let Some(typ) = typ else {
+            return Ok(Value::Bytes(self.data[start..self.pos].to_vec()));
+        };
+        let (pos, end) = (self.pos, self.end);
+        self.pos = start;
+        self.end = pos;
+        let value = self.parse_type(typ);
+        self.pos = pos;
+        self.end = end;
+        value
+    }
+
+    fn parse_type(&mut self, typ: &'s Type) -> Result<Value<'s>, String> {
+        Ok(match typ {
+            Type::Unsigned(n, be) => Value::Int(self.read_int(*n, *be)?),
+            Type::Float(4, be) => {
+                Value::Float(f64::from(f32::from_bits(self.read_int(4, *be)? as u32)))
+            }
+            Type::Float(_, be) => Value::Float(f64::from_bits(self.read_int(8, *be)?)),
+            Type::Strz => {
+                let rest = &self.data[self.pos..self.end];
+                let len = rest
+                    .iter()
+                    .position(|&b| b == 0)
+                    .ok_or_else(|| format!("unterminated string at {:#x}", self.pos))?;
+                let s = String::from_utf8_lossy(&rest[..len]).into_owned();
+                self.pos += len + 1;
+                Value::Str(s)
+            }
+            Type::User(name) => {
+                let spec = self
+                    .schema
+                    .types
+                    .get(name)
+                    .ok_or_else(|| format!("unknown type {}", name))?;
+                Value::Struct(self.parse_user_type(spec)?)
+            }
+            Type::Switch(..) | Type::Bytes => unreachable!(),
+        })
+    }
+
+    fn eval(&self, expr: &Expr) -> Result<u64, String> {
+        Ok(match expr {
+            Expr::Int(n) => *n,
+            Expr::Path(path) => self.lookup(path)?,
+            Expr::Binary(left, op, right) => {
+                let (left, right) = (self.eval(left)?, self.eval(right)?);
+                match op.as_str() {
+                    "+" => left.wrapping_add(right),
+                    "-" => left.wrapping_sub(right),
+                    "*" => left.wrapping_mul(right),
+                    "==" => u64::from(left == right),
+                    _ => u64::from(left != right),
+                }
+            }
+        })
+    }
+
+    /// Integer field of the current type, either read already or a value instance,
+    /// possibly followed by fields of nested types.
+    fn lookup(&self, path: &[String]) -> Result<u64, String> {
+        let scope = self.scopes.last().unwrap();
+        let name = &path[0];
+        let mut value = match scope.fields.iter().find(|(n, _)| *n == name) {
+            Some((_, value)) => value,
+            None => {
+                let instance = scope.spec.instances.iter().find(|(n, _)| n == name);
+                return match instance {
+                    Some((_, expr)) if path.len() == 1 => self.eval(expr),
+                    _ => Err(format!("unknown field {}", name)),
+                };
+            }
+        };
+        for field in &path[1..] {
+            value = match value {
+                Value::Struct(obj) => obj.get(field),
+                _ => None,
+            }
+            .ok_or_else(|| format!("no field {} in {}", field, path.join(".")))?;
+        }
+        match value {
+            Value::Int(n) => Ok(*n),
+            value => Err(format!("{} is not an integer: {:?}", path.join("."), value)),
+        }
+    }
+}
+
+// Reader side, converted to the field names used in the schema.
+
+fn ints<T: Copy + Into<u64>>(values: &[T]) -> Value<'static> {
+    Value::Array(values.iter().map(|&v| Value::Int(v.into())).collect())
+}
+
+fn floats(values: &[f32]) -> Value<'static> {
+    Value::Array(values.iter().map(|&v| Value::Float(f64::from(v))).collect())
+}
+
+fn record(fields: Vec<(&'static str, Value<'static>)>) -> Value<'static> {
+    Value::Struct(Obj {
+        fields,
+        ..Default::default()
+    })
+}
+
+fn vec3f(v: &[f32]) -> Value<'static> {
+    record(vec![
+        ("x", Value::Float(f64::from(v[0]))),
+        ("y", Value::Float(f64::from(v[1]))),
+        ("z", Value::Float(f64::from(v[2]))),
+    ])
+}
+
+fn str(s: &str) -> Value<'static> {
+    Value::Str(s.to_string())
+}
+
+fn expected_fields(chunk: &Chunk) -> Vec<(&'static str, Value<'static>)> {
+    match chunk {
+        Chunk::Null()
+        | Chunk::ActorNodeDown()
+        | Chunk::ActorBounds()
+        | Chunk::ActorNodeUp()
+        | Chunk::Unknown { .. } => vec![],
+        Chunk::FileHeader { file_type, version } => vec![
+            ("file_type", Value::Int(u64::from(*file_type))),
+            ("version", Value::Int(u64::from(*version))),
+        ],
+        Chunk::FileName { name, subtype } => vec![
+            ("subtype", Value::Int(u64::from(*subtype))),
+            ("name", str(name)),
+        ],
+        Chunk::VertexList(vertices) => vec![(
+            "vertices",
+            Value::Array(vertices.iter().map(|v| vec3f(&v.position)).collect()),
+        )],
+        Chunk::UvMapList(uvs) => vec![(
+            "uvcoords",
+            Value::Array(
+                uvs.iter()
+                    .map(|uv| {
+                        record(vec![
+                            ("u", Value::Float(f64::from(uv.u))),
+                            ("v", Value::Float(f64::from(uv.v))),
+                        ])
+                    })
+                    .collect(),
+            ),
+        )],
+        Chunk::FaceList(faces) => vec![(
+            "faces",
+            Value::Array(
+                faces
+                    .iter()
+                    .map(|f| {
+                        record(vec![
+                            ("v1", Value::Int(u64::from(f.v1))),
+                            ("v2", Value::Int(u64::from(f.v2))),
+                            ("v3", Value::Int(u64::from(f.v3))),
+                            ("flags", Value::Int(u64::from(f.flags))),
+                            ("what", Value::Int(u64::from(f.what))),
+                        ])
+                    })
+                    .collect(),
+            ),
+        )],
+        Chunk::MaterialList(names, _) => vec![(
+            "materials",
+            Value::Array(names.iter().map(|n| str(n)).collect()),
+        )],
+        Chunk::MaterialDesc {
+            name, properties, ..
+        } => vec![
+            ("colour", ints(&properties.colour)),
+            ("opacity", Value::Int(u64::from(properties.opacity))),
+            ("ambient", Value::Float(f64::from(properties.ambient))),
+            ("diffuse", Value::Float(f64::from(properties.diffuse))),
+            ("specular", Value::Float(f64::from(properties.specular))),
+            ("power", Value::Float(f64::from(properties.power))),
+            ("flags", Value::Int(u64::from(properties.flags))),
+            (
+                "map_transform",
+                floats(properties.map_transform.as_flattened()),
+            ),
+            ("index_base", Value::Int(u64::from(properties.index_base))),
+            ("index_range", Value::Int(u64::from(properties.index_range))),
+            ("name", str(name)),
+        ],
+        Chunk::FaceMatList { materials, unknown } => vec![
+            ("unknown", Value::Int(u64::from(*unknown))),
+            ("face_materials", ints(materials)),
+        ],
+        Chunk::PixelmapHeader {
+            name,
+            w,
+            h,
+            origin_x,
+            origin_y,
+            pixel_type,
+            row_bytes,
+        } => vec![
+            ("pixel_type", Value::Int(u64::from(*pixel_type))),
+            ("row_bytes", Value::Int(u64::from(*row_bytes))),
+            ("w", Value::Int(u64::from(*w))),
+            ("h", Value::Int(u64::from(*h))),
+            ("origin_x", Value::Int(u64::from(*origin_x))),
+            ("origin_y", Value::Int(u64::from(*origin_y))),
+            ("name", str(name)),
+        ],
+        Chunk::PixelmapData {
+            units,
+            unit_bytes,
+            data,
+        } => vec![
+            ("units", Value::Int(u64::from(*units))),
+            ("unit_bytes", Value::Int(u64::from(*unit_bytes))),
+            ("pixel_data", Value::Bytes(data.clone())),
+        ],
+        Chunk::PixelmapRef(name, _)
+        | Chunk::RenderTabRef(name, _)
+        | Chunk::MeshFileRef(name, _)
+        | Chunk::MaterialRef(name, _) => vec![("name", str(name))],
+        Chunk::ActorName {
+            name,
+            actor_type,
+            render_style,
+            ..
+        } => vec![
+            ("actor_type", Value::Int(u64::from(*actor_type))),
+            ("render_style", Value::Int(u64::from(*render_style))),
+            ("name", str(name)),
+        ],
+        Chunk::ActorTransform(params) => vec![
+            ("scale_row1", vec3f(&params[0..3])),
+            ("scale_row2", vec3f(&params[3..6])),
+            ("scale_row3", vec3f(&params[6..9])),
+            ("translate_row4", vec3f(&params[9..12])),
+        ],
+        Chunk::MapBoundingBox { min, max } => vec![("min", vec3f(min)), ("max", vec3f(max))],
+    }
+}
+
+/// Compare both parses of one file, returning description of the first disagreement.
+fn cross_check(schema: &Schema, data: &[u8]) -> Result<(), String> {
+    let reader: Result<Vec<(u64, Chunk)>, Error> = ChunkReader::new(data).collect();
+    let parsed = schema.parse(data);
+    let (chunks, parsed) = match (reader, parsed) {
+        (Ok(chunks), Ok(parsed)) => (chunks, parsed),
+        (Err(_), Err(_)) => return Ok(()), // Both reject the file
+        (Err(e), Ok(_)) => return Err(format!("only reader fails: {}", e)),
+        (Ok(_), Err(e)) => return Err(format!("only schema fails: {}", e)),
+    };
+
+    let mut schema_chunks: Vec<_> = parsed.get("header").into_iter().collect();
+    if let Some(Value::Array(rest)) = parsed.get("chunks") {
+        schema_chunks.extend(rest);
+    }
+    if chunks.len() != schema_chunks.len() {
+        return Err(format!(
+            "reader has {} chunks, schema has {}",
+            chunks.len(),
+            schema_chunks.len()
+        ));
+    }
+
+    let no_body = Obj::default();
+    for ((offset, chunk), parsed) in chunks.iter().zip(schema_chunks) {
+        let at = format!("chunk {:#x} at {:#x}", chunk.chunk_type(), offset);
+        let Value::Struct(parsed) = parsed else {
+            return Err(format!("{}: schema chunk is {:?}", at, parsed));
+        };
+        if parsed.start as u64 != *offset {
+            return Err(format!(
+                "{}: schema chunk starts at {:#x}",
+                at, parsed.start
+            ));
+        }
+        let schema_type = match parsed.get("header") {
+            Some(Value::Struct(header)) => header.get("type"),
+            _ => None,
+        };
+        if schema_type != Some(&Value::Int(u64::from(chunk.chunk_type()))) {
+            return Err(format!("{}: schema type {:?}", at, schema_type));
+        }
+        let body = match parsed.get("body") {
+            Some(Value::Struct(body)) => body,
+            _ => &no_body,
+        };
+        for (name, value) in expected_fields(chunk) {
+            if body.get(name) != Some(&value) {
+                let mut found = format!("{:?}", body.get(name));
+                found.truncate(200);
+                return Err(format!(
+                    "{}: field {} is {} in schema, reader disagrees",
+                    at, name, found
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_schema_agrees_with_reader() {
+    let schema = Schema::load("kaitai/carma.ksy");
+    let mut checked = 0;
+    let mut failures = vec![];
+    for dir in ["MODELS", "ACTORS", "MATERIAL", "PIXELMAP"] {
+        let dir = Path::new("DecodedData/DATA").join(dir);
+        let mut paths: Vec<_> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.is_file())
+            .collect();
+        paths.sort();
+        for path in paths {
+            let data = fs::read(&path).unwrap();
+            if let Err(e) = cross_check(&schema, &data) {
+                failures.push(format!("{}: {}", path.display(), e));
+            }
+            checked += 1;
+        }
+    }
+    assert!(checked > 0);
+    assert!(
+        failures.is_empty(),
+        "{} of {} files disagree:\n{}",
+        failures.len(),
+        checked,
+        failures.join("\n")
+    );
+}
+
+#[test]
+fn test_schema_interpreter() {
+    let schema = Schema::load("kaitai/carma.ksy");
+    #[rustfmt::skip]
+    let data = [
+        0x0, 0x0, 0x0, 0x12, // Chunk type - FILE_HEADER_CHUNK
+        0x0, 0x0, 0x0, 0x8, // Chunk size
+        0x0, 0x0, 0x0, 0x5, // File type - material
+        0x0, 0x0, 0x0, 0x2, // Version
+        0x0, 0x0, 0x0, 0x21, // Chunk type - PIXELMAP_DATA_CHUNK
+        0x0, 0x0, 0x0, 0xa, // Chunk size
+        0x0, 0x0, 0x0, 0x1, // Units
+        0x0, 0x0, 0x0, 0x2, // Unit bytes
+        0xab, 0xcd, // Pixel data
+    ];
+    let parsed = schema.parse(&data).unwrap();
+    let Some(Value::Array(chunks)) = parsed.get("chunks") else {
+        panic!("no chunks parsed")
+    };
+    let Value::Struct(chunk) = &chunks[0] else {
+        panic!("chunk is not a struct")
+    };
+    assert_eq!((0x10, 0x22), (chunk.start, chunk.end));
+    let Some(Value::Struct(body)) = chunk.get("body") else {
+        panic!("chunk has no body")
+    };
+    assert_eq!(
+        Some(&Value::Bytes(vec![0xab, 0xcd])),
+        body.get("pixel_data")
+    );
+
+    assert!(schema.parse(&data[..30]).is_err());
+    assert!(cross_check(&schema, &data).is_ok());
+}